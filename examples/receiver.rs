@@ -1,4 +1,4 @@
-use aqueduct::{Receiver, Packet, Discovery, PixelFormat};
+use aqueduct::{Receiver, Packet, Discovery, convert};
 use std::time::Duration;
 use tokio::time;
 use minifb::{Window, WindowOptions, Key};
@@ -26,7 +26,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Connecting to localhost:9030...");
     let mut receiver = Receiver::connect("127.0.0.1:9030").await?;
 
-    let mut frame_count = 0;
     let mut window: Option<Window> = None;
     let mut fb: Vec<u32> = Vec::new();
     let mut win_w: u32 = 0;
@@ -40,15 +39,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         println!("Received Video: {}x{} [{:?}] @ {:?} ({} bytes)", 
                             frame.width, frame.height, frame.format, frame.timestamp, frame.data.len());
                         
-                        if frame.format != PixelFormat::BGRA {
-                            eprintln!("Unsupported pixel format for preview: {:?}", frame.format);
-                            frame_count += 1;
-                            continue;
-                        }
+                        let data = match convert::to_bgra(&frame) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                eprintln!("Cannot preview {:?} frame: {}", frame.format, e);
+                                continue;
+                            }
+                        };
 
                         let width = frame.width;
                         let height = frame.height;
-                        let data = &frame.data;
 
                         if window.is_none() || win_w != width || win_h != height {
                             win_w = width;
@@ -58,7 +58,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 Ok(w) => window = Some(w),
                                 Err(e) => {
                                     eprintln!("Failed to create window: {}", e);
-                                    frame_count += 1;
                                     continue;
                                 }
                             }
@@ -76,21 +75,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             }
 
                             // Convert BGRA -> 0x00RRGGBB for minifb
-                            // data is tightly packed BGRA
-                            let mut pi = 0usize;
-                            for chunk in data.chunks_exact(4) {
+                            for (px, chunk) in fb.iter_mut().zip(data.chunks_exact(4)) {
                                 let b = chunk[0] as u32;
                                 let g = chunk[1] as u32;
                                 let r = chunk[2] as u32;
-                                fb[pi] = (r << 16) | (g << 8) | b;
-                                pi += 1;
+                                *px = (r << 16) | (g << 8) | b;
                             }
 
                             if let Err(e) = win.update_with_buffer(&fb, width as usize, height as usize) {
                                 eprintln!("Window update error: {}", e);
                             }
                         }
-                        frame_count += 1;
                     }
                     Packet::Audio(frame) => {
                         println!("Received Audio: {}Hz {}ch @ {:?} ({} samples)", 
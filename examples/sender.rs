@@ -1,7 +1,6 @@
-use aqueduct::{Sender, VideoFrame, PixelFormat, FrameFlags, Packet, Discovery, AudioFrame, SineWaveGenerator, MetadataFrame};
-use bytes::Bytes;
-use std::time::{Duration, Instant};
-use tokio::time;
+use aqueduct::{Sender, CodecId, VideoFrame, PixelFormat, FrameRate, FrameClock, Packet, Discovery, AudioFrame, SineWaveGenerator, MetadataFrame};
+use aqueduct::convert::{convert, ImageFormat, ImageLayout};
+use bytes::BytesMut;
 use xcap::Monitor;
 
 #[tokio::main]
@@ -16,6 +15,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Start TCP Sender
     // Ports 8922-9021 are excluded on this machine. Using 9030.
     let sender = Sender::new(9030).await?;
+    // Most of a desktop doesn't change between frames, send only the differences
+    sender.set_codec(CodecId::DELTA)?;
     
     println!("Sender running on port 9030...");
     
@@ -30,16 +31,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Audio Generator (440Hz beep, 48kHz, Stereo)
     let mut audio_gen = SineWaveGenerator::new(440.0, 48000, 2);
 
-    let start_time = Instant::now();
-    let mut interval = time::interval(Duration::from_millis(33)); // ~30fps
-    let mut frame_count = 0;
+    // Wall-clock timestamps at exactly 29.97fps, so receivers can line us up with other senders
+    let mut clock = FrameClock::new(FrameRate::FPS_29_97)?;
 
     loop {
-        interval.tick().await;
-        
-        let timestamp = start_time.elapsed();
+        let tick = clock.tick().await;
+        if tick.missed > 0 {
+            eprintln!("Capture fell behind, skipped {} frames", tick.missed);
+        }
+        let timestamp = tick.timestamp;
+
+        // 1. Generate Audio
+        // 1601 or 1602 samples per frame, for the skipped frames as well so the audio has no gaps
+        for frame in tick.frame - tick.missed..=tick.frame {
+            let audio_data = audio_gen.generate(tick.frame_rate.samples_in_frame(frame, 48000));
+            let audio_frame = AudioFrame::new(48000, 2, clock.timestamp(frame), audio_data);
+            if let Err(e) = sender.enqueue(Packet::Audio(audio_frame)).await {
+                eprintln!("Error sending audio frame: {}", e);
+            }
+        }
 
-        // 1. Capture Video
+        // 2. Capture Video
         // xcap returns image::RgbaImage (Vec<u8> in RGBA format)
         let image = match monitor.capture_image() {
             Ok(img) => img,
@@ -52,52 +64,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let width = image.width();
         let height = image.height();
         
-        // Convert to Bytes. 
-        // xcap returns RGBA8.
-        let mut raw_data = image.into_raw();
+        // xcap returns RGBA8, convert it to BGRA (compatible with our PixelFormat::BGRA)
+        let raw_data = image.into_raw();
+        let mut bgra = BytesMut::with_capacity(raw_data.len());
+        convert(
+            &raw_data,
+            &ImageLayout::new(ImageFormat::Rgba, width, height),
+            &ImageLayout::new(PixelFormat::BGRA, width, height),
+            &mut bgra,
+        )?;
+        let data = bgra.freeze();
         
-        // Swap R and B to make it BGRA (compatible with our PixelFormat::BGRA)
-        for chunk in raw_data.chunks_exact_mut(4) {
-             let r = chunk[0];
-             let b = chunk[2];
-             chunk[0] = b;
-             chunk[2] = r;
-        }
-
-        let data = Bytes::from(raw_data);
+        let mut video_frame = VideoFrame::new(width, height, PixelFormat::BGRA, timestamp, data);
+        video_frame.frame_rate = tick.frame_rate;
         
-        let video_frame = VideoFrame {
-            width,
-            height,
-            format: PixelFormat::BGRA,
-            flags: FrameFlags::default(),
-            timestamp,
-            data,
-        };
-        
-        if let Err(e) = sender.send(Packet::Video(video_frame)) {
+        // Encoded on the sender's worker threads, the loop goes on capturing meanwhile
+        if let Err(e) = sender.enqueue(Packet::Video(video_frame)).await {
             eprintln!("Error sending video frame: {}", e);
         }
         
-        // 2. Generate Audio
-        // Generate ~33ms of audio (48000 * 0.033 = 1584 samples)
-        // Let's use exactly 1600 samples for simplicity (33.33ms)
-        let audio_samples = 1600;
-        let audio_data = audio_gen.generate(audio_samples);
-        
-        let audio_frame = AudioFrame {
-            sample_rate: 48000,
-            channels: 2,
-            timestamp,
-            data: audio_data,
-        };
-        
-        if let Err(e) = sender.send(Packet::Audio(audio_frame)) {
-             eprintln!("Error sending audio frame: {}", e);
-        }
-
         // 3. Send Metadata (every 30 frames ~ 1s)
-        if frame_count % 30 == 0 {
+        if tick.frame % 30 == 0 {
             let metadata = MetadataFrame {
                 timestamp,
                 content: format!("<tally><on_program>true</on_program><source>{}</source></tally>", monitor.name()),
@@ -106,7 +93,5 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                  eprintln!("Error sending metadata: {}", e);
             }
         }
-        
-        frame_count += 1;
     }
 }
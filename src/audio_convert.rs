@@ -0,0 +1,249 @@
+use bytes::{Bytes, BytesMut};
+use crate::error::{Result, AqueductError};
+use crate::protocol::{AudioFrame, SampleFormat, ChannelLayout};
+
+// Audio sample format and channel layout conversion.
+//
+// Integer samples map to float as v / 2^(bits - 1), like the OMT reference
+// implementation does. Floats are rounded to the nearest integer and clipped to
+// its range on the way back, out of range or NaN samples don't wrap around.
+// Every 24-bit value is exactly representable as f32, so integer to integer
+// conversions through float are exact too.
+
+/// Describes an audio buffer: its sample format, channel layout and channel count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioLayout {
+    pub format: SampleFormat,
+    pub channel_layout: ChannelLayout,
+    pub channels: u32,
+}
+
+impl AudioLayout {
+    pub fn new(format: SampleFormat, channel_layout: ChannelLayout, channels: u32) -> Self {
+        Self { format, channel_layout, channels }
+    }
+
+    // Bytes of one sample for every channel
+    pub fn frame_bytes(&self) -> usize {
+        self.format.bytes_per_sample() * self.channels as usize
+    }
+
+    // Byte offset of a sample in a buffer holding `samples` samples per channel
+    fn offset(&self, channel: usize, sample: usize, samples: usize) -> usize {
+        let index = match self.channel_layout {
+            ChannelLayout::Planar => channel * samples + sample,
+            ChannelLayout::Interleaved => sample * self.channels as usize + channel,
+        };
+        index * self.format.bytes_per_sample()
+    }
+}
+
+impl From<&AudioFrame> for AudioLayout {
+    fn from(frame: &AudioFrame) -> Self {
+        Self::new(frame.format, frame.channel_layout, frame.channels)
+    }
+}
+
+/// Converts `src` laid out as `from` into the `to` layout, appending the result to `dst`.
+pub fn convert_audio(src: &[u8], from: &AudioLayout, to: &AudioLayout, dst: &mut BytesMut) -> Result<()> {
+    if from.channels != to.channels {
+        return Err(AqueductError::Conversion(format!(
+            "Channel count mismatch: {} -> {} (use a remix to change it)", from.channels, to.channels
+        )));
+    }
+    if from.channels == 0 {
+        return Err(AqueductError::Conversion("Audio without channels".to_string()));
+    }
+    if !src.len().is_multiple_of(from.frame_bytes()) {
+        return Err(AqueductError::Conversion(format!(
+            "Audio buffer of {} bytes doesn't hold whole {:?} samples for {} channels",
+            src.len(), from.format, from.channels
+        )));
+    }
+
+    let samples = src.len() / from.frame_bytes();
+    let start = dst.len();
+    if from == to {
+        dst.extend_from_slice(src);
+        return Ok(());
+    }
+    dst.resize(start + samples * to.frame_bytes(), 0);
+    let out = &mut dst[start..];
+
+    let from_size = from.format.bytes_per_sample();
+    let to_size = to.format.bytes_per_sample();
+    for channel in 0..from.channels as usize {
+        for sample in 0..samples {
+            let src_offset = from.offset(channel, sample, samples);
+            let dst_offset = to.offset(channel, sample, samples);
+            let src_sample = &src[src_offset..src_offset + from_size];
+            let dst_sample = &mut out[dst_offset..dst_offset + to_size];
+            if from.format == to.format {
+                // Layout change only
+                dst_sample.copy_from_slice(src_sample);
+            } else {
+                write_sample(dst_sample, to.format, read_sample(src_sample, from.format));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Converts a frame to another sample format and channel layout.
+pub fn convert_audio_frame(frame: &AudioFrame, format: SampleFormat, channel_layout: ChannelLayout) -> Result<AudioFrame> {
+    if frame.is_compressed() {
+        return Err(AqueductError::Conversion(format!("Can't convert {} compressed audio", frame.codec)));
+    }
+    let from = AudioLayout::from(frame);
+    let to = AudioLayout::new(format, channel_layout, frame.channels);
+
+    let mut dst = BytesMut::with_capacity(frame.data.len() / from.format.bytes_per_sample() * to.format.bytes_per_sample());
+    convert_audio(&frame.data, &from, &to, &mut dst)?;
+
+    let mut converted = frame.clone();
+    converted.format = format;
+    converted.channel_layout = channel_layout;
+    converted.data = dst.freeze();
+    Ok(converted)
+}
+
+/// Samples as interleaved 32-bit floats, e.g. for an audio output.
+pub fn to_interleaved_f32(frame: &AudioFrame) -> Result<Bytes> {
+    if !frame.is_compressed() && frame.format == SampleFormat::F32 && frame.channel_layout == ChannelLayout::Interleaved {
+        return Ok(frame.data.clone());
+    }
+    Ok(convert_audio_frame(frame, SampleFormat::F32, ChannelLayout::Interleaved)?.data)
+}
+
+/// Samples as planar 32-bit floats, one block per channel like OMT's FPA1.
+pub fn to_planar_f32(frame: &AudioFrame) -> Result<Bytes> {
+    if !frame.is_compressed() && frame.format == SampleFormat::F32 && frame.channel_layout == ChannelLayout::Planar {
+        return Ok(frame.data.clone());
+    }
+    Ok(convert_audio_frame(frame, SampleFormat::F32, ChannelLayout::Planar)?.data)
+}
+
+fn read_sample(src: &[u8], format: SampleFormat) -> f32 {
+    match format {
+        SampleFormat::F32 => f32::from_le_bytes([src[0], src[1], src[2], src[3]]),
+        SampleFormat::S16 => i16::from_le_bytes([src[0], src[1]]) as f32 / 32768.0,
+        // Sign extend from the top byte
+        SampleFormat::S24 => (i32::from_le_bytes([0, src[0], src[1], src[2]]) >> 8) as f32 / 8388608.0,
+    }
+}
+
+fn write_sample(dst: &mut [u8], format: SampleFormat, value: f32) {
+    match format {
+        SampleFormat::F32 => dst.copy_from_slice(&value.to_le_bytes()),
+        SampleFormat::S16 => {
+            let sample = (value * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+            dst.copy_from_slice(&sample.to_le_bytes());
+        }
+        SampleFormat::S24 => {
+            let sample = (value * 8388608.0).round().clamp(-8388608.0, 8388607.0) as i32;
+            dst.copy_from_slice(&sample.to_le_bytes()[..3]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn f32_bytes(samples: &[f32]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    }
+
+    fn i16_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    }
+
+    fn i24_bytes(samples: &[i32]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_le_bytes()[..3].to_vec()).collect()
+    }
+
+    fn converted(src: &[u8], from: AudioLayout, to: AudioLayout) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        convert_audio(src, &from, &to, &mut dst).unwrap();
+        dst.to_vec()
+    }
+
+    fn mono(format: SampleFormat) -> AudioLayout {
+        AudioLayout::new(format, ChannelLayout::Interleaved, 1)
+    }
+
+    #[test]
+    fn float_to_integer_clips() {
+        let src = f32_bytes(&[0.0, 0.5, -0.5, -1.0, 1.0, 2.0, -2.0, f32::NAN]);
+        assert_eq!(
+            converted(&src, mono(SampleFormat::F32), mono(SampleFormat::S16)),
+            i16_bytes(&[0, 16384, -16384, -32768, 32767, 32767, -32768, 0]),
+        );
+        assert_eq!(
+            converted(&src, mono(SampleFormat::F32), mono(SampleFormat::S24)),
+            i24_bytes(&[0, 4194304, -4194304, -8388608, 8388607, 8388607, -8388608, 0]),
+        );
+    }
+
+    #[test]
+    fn integer_to_float() {
+        let src = i16_bytes(&[0, 16384, -32768, 32767]);
+        assert_eq!(
+            converted(&src, mono(SampleFormat::S16), mono(SampleFormat::F32)),
+            f32_bytes(&[0.0, 0.5, -1.0, 32767.0 / 32768.0]),
+        );
+        // Negative 24-bit samples are sign extended
+        let src = i24_bytes(&[-1, -8388608, 4194304]);
+        assert_eq!(
+            converted(&src, mono(SampleFormat::S24), mono(SampleFormat::F32)),
+            f32_bytes(&[-1.0 / 8388608.0, -1.0, 0.5]),
+        );
+    }
+
+    #[test]
+    fn integer_conversions_are_exact() {
+        let samples: Vec<i16> = (i16::MIN..=i16::MAX).step_by(7).collect();
+        let s24 = converted(&i16_bytes(&samples), mono(SampleFormat::S16), mono(SampleFormat::S24));
+        assert_eq!(s24, i24_bytes(&samples.iter().map(|&s| s as i32 * 256).collect::<Vec<_>>()));
+        assert_eq!(converted(&s24, mono(SampleFormat::S24), mono(SampleFormat::S16)), i16_bytes(&samples));
+    }
+
+    #[test]
+    fn planar_and_interleaved() {
+        let interleaved = AudioLayout::new(SampleFormat::S16, ChannelLayout::Interleaved, 3);
+        let planar = AudioLayout::new(SampleFormat::S16, ChannelLayout::Planar, 3);
+        let src = i16_bytes(&[1, 2, 3, 11, 12, 13]);
+        let out = converted(&src, interleaved, planar);
+        assert_eq!(out, i16_bytes(&[1, 11, 2, 12, 3, 13]));
+        assert_eq!(converted(&out, planar, interleaved), src);
+
+        // Format and layout at once
+        let planar_float = AudioLayout::new(SampleFormat::F32, ChannelLayout::Planar, 3);
+        let src = i16_bytes(&[16384, -16384, 0, 0, 16384, -16384]);
+        assert_eq!(converted(&src, interleaved, planar_float), f32_bytes(&[0.5, 0.0, -0.5, 0.5, 0.0, -0.5]));
+    }
+
+    #[test]
+    fn rejects_mismatched_buffers() {
+        let stereo = AudioLayout::new(SampleFormat::S16, ChannelLayout::Interleaved, 2);
+        let mut dst = BytesMut::new();
+        assert!(convert_audio(&[0; 8], &stereo, &mono(SampleFormat::S16), &mut dst).is_err());
+        assert!(convert_audio(&[0; 6], &stereo, &stereo, &mut dst).is_err());
+        let silent = AudioLayout { channels: 0, ..stereo };
+        assert!(convert_audio(&[], &silent, &silent, &mut dst).is_err());
+        assert!(dst.is_empty());
+    }
+
+    #[test]
+    fn frame_helpers() {
+        let frame = AudioFrame::new(48000, 2, Duration::ZERO, Bytes::from(f32_bytes(&[0.25, -0.25, 0.5, -0.5])));
+        // Already in the wanted layout, handed back without copying
+        assert_eq!(to_interleaved_f32(&frame).unwrap().as_ptr(), frame.data.as_ptr());
+        assert_eq!(&to_planar_f32(&frame).unwrap()[..], &f32_bytes(&[0.25, 0.5, -0.25, -0.5])[..]);
+
+        let s16 = convert_audio_frame(&frame, SampleFormat::S16, ChannelLayout::Planar).unwrap();
+        assert_eq!((s16.format, s16.channel_layout), (SampleFormat::S16, ChannelLayout::Planar));
+        assert_eq!(&s16.data[..], &i16_bytes(&[8192, 16384, -8192, -16384])[..]);
+    }
+}
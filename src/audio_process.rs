@@ -0,0 +1,507 @@
+use std::f64::consts::PI;
+use std::time::Duration;
+use bytes::{BufMut, BytesMut};
+use crate::audio_convert::{convert_audio, convert_audio_frame, AudioLayout};
+use crate::error::{Result, AqueductError};
+use crate::protocol::{AudioFrame, SampleFormat, ChannelLayout};
+
+// Sample rate conversion and channel remixing.
+//
+// Both work on uncompressed frames of any sample format and layout and hand back
+// frames in the same format and layout, so they fit after `Receiver::receive` as
+// well as before `Sender::send`. Processing happens in planar f32.
+//
+// The resampler is a polyphase windowed sinc filter. It keeps the tail of each
+// frame to filter across frame boundaries, so one Resampler must be used per
+// stream and fed its frames in order. Output frames don't have the same number of
+// samples as their input (1600 samples at 44.1 kHz become 1741 or 1742 at 48 kHz),
+// the timestamps are adjusted to the first sample they carry.
+
+/// How hard the resampler works to keep aliasing and imaging out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleQuality {
+    // Linear interpolation, for monitoring and talkback
+    Fast,
+    // 16 tap sinc
+    #[default]
+    Balanced,
+    // 64 tap sinc, for program audio
+    Best,
+}
+
+impl ResampleQuality {
+    // Taps on either side of the output sample and the fraction of the band kept
+    fn filter(self) -> (usize, f64) {
+        match self {
+            Self::Fast => (1, 1.0),
+            Self::Balanced => (8, 0.90),
+            Self::Best => (32, 0.95),
+        }
+    }
+}
+
+// Filter phases are exact when the rates need at most this many, and rounded to
+// this resolution otherwise
+const MAX_PHASES: usize = 4096;
+
+pub struct Resampler {
+    output_rate: u32,
+    quality: ResampleQuality,
+    state: Option<ResamplerState>,
+}
+
+// Filter and history for one input rate and channel count
+struct ResamplerState {
+    input_rate: u32,
+    channels: usize,
+    half: usize,
+    phases: usize,
+    // `phases` kernels of 2 * half taps
+    kernels: Vec<f32>,
+    // Input not fully used yet, per channel
+    buffers: Vec<Vec<f32>>,
+    // Position of the next output sample in the buffers: index + frac / output_rate
+    index: usize,
+    frac: u64,
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32, quality: ResampleQuality) -> Self {
+        Self {
+            output_rate,
+            quality,
+            state: None,
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    pub fn quality(&self) -> ResampleQuality {
+        self.quality
+    }
+
+    // Forgets the buffered input, e.g. after a discontinuity in the stream
+    pub fn reset(&mut self) {
+        self.state = None;
+    }
+
+    /// Resamples a frame to the output rate. Frames already at the output rate are
+    /// returned as is, a change of input rate or channel count resets the filter.
+    pub fn process(&mut self, frame: &AudioFrame) -> Result<AudioFrame> {
+        if frame.is_compressed() {
+            return Err(AqueductError::Conversion(format!("Can't resample {} compressed audio", frame.codec)));
+        }
+        if frame.sample_rate == 0 || self.output_rate == 0 || frame.channels == 0 {
+            return Err(AqueductError::Conversion(format!(
+                "Can't resample {} channels from {} Hz to {} Hz", frame.channels, frame.sample_rate, self.output_rate
+            )));
+        }
+        if frame.sample_rate == self.output_rate {
+            self.state = None;
+            return Ok(frame.clone());
+        }
+
+        let channels = frame.channels as usize;
+        if !self.state.as_ref().is_some_and(|state| state.input_rate == frame.sample_rate && state.channels == channels) {
+            self.state = Some(ResamplerState::new(frame.sample_rate, self.output_rate, channels, self.quality));
+        }
+        let state = self.state.as_mut().expect("state was just created");
+        let output_rate = self.output_rate as u64;
+        let input_rate = frame.sample_rate as u64;
+
+        // Where the next output sample sits relative to the first sample of this frame
+        let buffered = state.buffers[0].len();
+        let offset = state.index as f64 + state.frac as f64 / output_rate as f64 - buffered as f64;
+        let timestamp = if offset < 0.0 {
+            frame.timestamp.saturating_sub(Duration::from_secs_f64(-offset / input_rate as f64))
+        } else {
+            frame.timestamp + Duration::from_secs_f64(offset / input_rate as f64)
+        };
+
+        let planar = planar_f32(frame)?;
+        let samples = planar.len() / channels;
+        for (buffer, input) in state.buffers.iter_mut().zip(planar.chunks_exact(samples.max(1))) {
+            buffer.extend_from_slice(input);
+        }
+
+        let taps = state.half * 2;
+        let mut output: Vec<Vec<f32>> = vec![Vec::new(); channels];
+        while state.index + state.half < state.buffers[0].len() {
+            let phase = (state.frac * state.phases as u64 / output_rate) as usize;
+            let kernel = &state.kernels[phase * taps..(phase + 1) * taps];
+            let start = state.index + 1 - state.half;
+            for (buffer, out) in state.buffers.iter().zip(output.iter_mut()) {
+                let sum: f32 = buffer[start..start + taps].iter().zip(kernel).map(|(x, h)| x * h).sum();
+                out.push(sum);
+            }
+            state.frac += input_rate;
+            state.index += (state.frac / output_rate) as usize;
+            state.frac %= output_rate;
+        }
+
+        // Keep only what the next output samples still need
+        let used = (state.index + 1).saturating_sub(state.half).min(state.buffers[0].len());
+        for buffer in &mut state.buffers {
+            buffer.drain(..used);
+        }
+        state.index -= used;
+
+        let mut data = BytesMut::with_capacity(output[0].len() * channels * 4);
+        for sample in output.iter().flatten() {
+            data.put_f32_le(*sample);
+        }
+        let resampled = AudioFrame {
+            sample_rate: self.output_rate,
+            format: SampleFormat::F32,
+            channel_layout: ChannelLayout::Planar,
+            timestamp,
+            data: data.freeze(),
+            ..frame.clone()
+        };
+        restore_format(resampled, frame)
+    }
+}
+
+impl ResamplerState {
+    fn new(input_rate: u32, output_rate: u32, channels: usize, quality: ResampleQuality) -> Self {
+        let (half, band) = quality.filter();
+        let distinct_phases = output_rate as u64 / gcd(input_rate as u64, output_rate as u64);
+        let phases = (distinct_phases as usize).min(MAX_PHASES);
+        // Below the lower of the two Nyquist frequencies, in input samples
+        let cutoff = band * (output_rate as f64 / input_rate as f64).min(1.0);
+
+        let taps = half * 2;
+        let mut kernels = Vec::with_capacity(phases * taps);
+        for phase in 0..phases {
+            let fraction = phase as f64 / phases as f64;
+            let kernel: Vec<f64> = (0..taps)
+                .map(|tap| {
+                    // Distance from the output sample to the input sample of this tap
+                    let x = tap as f64 - (half as f64 - 1.0) - fraction;
+                    if quality == ResampleQuality::Fast {
+                        1.0 - x.abs()
+                    } else {
+                        cutoff * sinc(cutoff * x) * blackman(x / half as f64)
+                    }
+                })
+                .collect();
+            // Unity gain at DC whatever the phase
+            let sum: f64 = kernel.iter().sum();
+            kernels.extend(kernel.iter().map(|h| (h / sum) as f32));
+        }
+
+        Self {
+            input_rate,
+            channels,
+            half,
+            phases,
+            kernels,
+            // Silence before the first sample, so the first output lands on it
+            buffers: vec![vec![0.0; half - 1]; channels],
+            index: half - 1,
+            frac: 0,
+        }
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+// Blackman window over [-1, 1]
+fn blackman(u: f64) -> f64 {
+    if u.abs() >= 1.0 {
+        return 0.0;
+    }
+    0.42 + 0.5 * (PI * u).cos() + 0.08 * (2.0 * PI * u).cos()
+}
+
+/// Gains from every input channel to every output channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMatrix {
+    inputs: usize,
+    outputs: usize,
+    // One row of `inputs` gains per output channel
+    gains: Vec<f32>,
+}
+
+// -3 dB, how centre and surround channels are folded into stereo (ITU-R BS.775)
+const FOLD_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+impl ChannelMatrix {
+    // All gains zero
+    pub fn silent(inputs: u32, outputs: u32) -> Self {
+        Self {
+            inputs: inputs as usize,
+            outputs: outputs as usize,
+            gains: vec![0.0; inputs as usize * outputs as usize],
+        }
+    }
+
+    // Gains row by row, one row of `inputs` gains per output channel
+    pub fn new(inputs: u32, outputs: u32, gains: Vec<f32>) -> Result<Self> {
+        if gains.len() != inputs as usize * outputs as usize {
+            return Err(AqueductError::Conversion(format!(
+                "A {}x{} channel matrix needs {} gains, got {}", outputs, inputs, inputs * outputs, gains.len()
+            )));
+        }
+        Ok(Self {
+            inputs: inputs as usize,
+            outputs: outputs as usize,
+            gains,
+        })
+    }
+
+    pub fn identity(channels: u32) -> Self {
+        let mut matrix = Self::silent(channels, channels);
+        for channel in 0..channels {
+            matrix.set_gain(channel, channel, 1.0);
+        }
+        matrix
+    }
+
+    /// A sensible default between two channel counts. Mono is copied to both sides
+    /// of stereo, stereo is averaged to mono, and 5.1 (L R C LFE Ls Rs) and 7.1
+    /// (L R C LFE Ls Rs Lb Rb) fold down to stereo with the centre and surrounds at
+    /// -3 dB and the LFE dropped. Other counts map channels one to one, folding
+    /// extra inputs onto the outputs in turn or leaving extra outputs silent.
+    ///
+    /// Fold downs add channels together and can clip, `scale` brings them down.
+    pub fn for_channels(inputs: u32, outputs: u32) -> Self {
+        let mut matrix = Self::silent(inputs, outputs);
+        match (inputs, outputs) {
+            _ if inputs == outputs => return Self::identity(inputs),
+            (_, 0) | (0, _) => {}
+            (1, _) => {
+                for output in 0..outputs.min(2) {
+                    matrix.set_gain(output, 0, 1.0);
+                }
+            }
+            (_, 1) => {
+                for input in 0..inputs {
+                    matrix.set_gain(0, input, 1.0 / inputs as f32);
+                }
+            }
+            (6 | 8, 2) => {
+                for side in 0..2 {
+                    matrix.set_gain(side, side, 1.0); // L, R
+                    matrix.set_gain(side, 2, FOLD_GAIN); // C
+                    matrix.set_gain(side, 4 + side, FOLD_GAIN); // Ls, Rs
+                    if inputs == 8 {
+                        matrix.set_gain(side, 6 + side, FOLD_GAIN); // Lb, Rb
+                    }
+                }
+            }
+            _ => {
+                for input in 0..inputs {
+                    matrix.set_gain(input % outputs, input, 1.0);
+                }
+            }
+        }
+        matrix
+    }
+
+    pub fn inputs(&self) -> u32 {
+        self.inputs as u32
+    }
+
+    pub fn outputs(&self) -> u32 {
+        self.outputs as u32
+    }
+
+    pub fn gain(&self, output: u32, input: u32) -> f32 {
+        self.gains[output as usize * self.inputs + input as usize]
+    }
+
+    pub fn set_gain(&mut self, output: u32, input: u32, gain: f32) {
+        self.gains[output as usize * self.inputs + input as usize] = gain;
+    }
+
+    // Multiplies every gain, e.g. to keep a fold down from clipping
+    pub fn scale(mut self, factor: f32) -> Self {
+        for gain in &mut self.gains {
+            *gain *= factor;
+        }
+        self
+    }
+}
+
+/// Mixes a frame's channels into `matrix.outputs()` channels.
+pub fn remix(frame: &AudioFrame, matrix: &ChannelMatrix) -> Result<AudioFrame> {
+    if frame.is_compressed() {
+        return Err(AqueductError::Conversion(format!("Can't remix {} compressed audio", frame.codec)));
+    }
+    if frame.channels as usize != matrix.inputs {
+        return Err(AqueductError::Conversion(format!(
+            "Channel matrix expects {} channels, frame has {}", matrix.inputs, frame.channels
+        )));
+    }
+
+    let planar = planar_f32(frame)?;
+    let samples = planar.len().checked_div(matrix.inputs).unwrap_or(0);
+    let mut data = BytesMut::with_capacity(samples * matrix.outputs * 4);
+    for row in matrix.gains.chunks_exact(matrix.inputs.max(1)).take(matrix.outputs) {
+        for sample in 0..samples {
+            let mixed: f32 = row.iter()
+                .enumerate()
+                .filter(|(_, gain)| **gain != 0.0)
+                .map(|(input, gain)| planar[input * samples + sample] * gain)
+                .sum();
+            data.put_f32_le(mixed);
+        }
+    }
+
+    let remixed = AudioFrame {
+        channels: matrix.outputs as u32,
+        format: SampleFormat::F32,
+        channel_layout: ChannelLayout::Planar,
+        data: data.freeze(),
+        ..frame.clone()
+    };
+    restore_format(remixed, frame)
+}
+
+fn planar_f32(frame: &AudioFrame) -> Result<Vec<f32>> {
+    let from = AudioLayout::from(frame);
+    let samples = frame.samples_per_channel();
+    let mut planar = BytesMut::with_capacity(samples * frame.channels as usize * 4);
+    convert_audio(
+        &frame.data[..samples * from.frame_bytes()],
+        &from,
+        &AudioLayout::new(SampleFormat::F32, ChannelLayout::Planar, frame.channels),
+        &mut planar,
+    )?;
+    Ok(planar.chunks_exact(4).map(|s| f32::from_le_bytes([s[0], s[1], s[2], s[3]])).collect())
+}
+
+// Converts a planar f32 result back to the format and layout of the original frame
+fn restore_format(frame: AudioFrame, original: &AudioFrame) -> Result<AudioFrame> {
+    if original.format == SampleFormat::F32 && original.channel_layout == ChannelLayout::Planar {
+        return Ok(frame);
+    }
+    convert_audio_frame(&frame, original.format, original.channel_layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    // Interleaved float frame, `signal` giving each channel's sample at an absolute index
+    fn frame(rate: u32, channels: u32, start: usize, len: usize, signal: impl Fn(usize, usize) -> f32) -> AudioFrame {
+        let data: Vec<u8> = (start..start + len)
+            .flat_map(|i| (0..channels as usize).map(move |ch| (i, ch)))
+            .flat_map(|(i, ch)| signal(i, ch).to_le_bytes())
+            .collect();
+        let timestamp = Duration::from_secs_f64(start as f64 / rate as f64);
+        AudioFrame::new(rate, channels, timestamp, Bytes::from(data))
+    }
+
+    fn samples(frame: &AudioFrame, channel: usize) -> Vec<f32> {
+        frame.data.chunks_exact(4)
+            .skip(channel)
+            .step_by(frame.channels as usize)
+            .map(|s| f32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn output_length_across_frames() {
+        let mut resampler = Resampler::new(48000, ResampleQuality::Balanced);
+        let mut total = 0;
+        let mut next_timestamp = None;
+        for n in 0..20 {
+            let output = resampler.process(&frame(44100, 2, n * 1600, 1600, |_, _| 0.0)).unwrap();
+            assert_eq!((output.sample_rate, output.channels), (48000, 2));
+            let len = output.samples_per_channel();
+            // 1600 samples at 44.1 kHz are 1741.5 at 48 kHz, the first frame also fills the filter
+            if n > 0 {
+                assert!(len == 1741 || len == 1742, "frame {}: {} samples", n, len);
+                let expected: Duration = next_timestamp.unwrap();
+                assert!(output.timestamp.abs_diff(expected) < Duration::from_micros(21), "frame {}", n);
+            }
+            next_timestamp = Some(output.timestamp + Duration::from_secs_f64(len as f64 / 48000.0));
+            total += len;
+        }
+        // Nothing lost or made up at the frame boundaries, only the filter's lookahead is held back
+        let exact = 20 * 1600 * 48000 / 44100;
+        assert!(total <= exact && exact - total <= 8, "{} samples, expected about {}", total, exact);
+    }
+
+    #[test]
+    fn dc_passes_at_unity_gain() {
+        for quality in [ResampleQuality::Fast, ResampleQuality::Balanced, ResampleQuality::Best] {
+            let mut resampler = Resampler::new(48000, quality);
+            for n in 0..4 {
+                let output = resampler.process(&frame(44100, 1, n * 441, 441, |_, _| 0.5)).unwrap();
+                if n > 0 {
+                    for sample in samples(&output, 0) {
+                        assert!((sample - 0.5).abs() < 1e-3, "{:?}: {}", quality, sample);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sine_keeps_its_amplitude() {
+        let sine = |i: usize, _| 0.5 * (i as f32 * 1000.0 * std::f32::consts::TAU / 44100.0).sin();
+        let mut resampler = Resampler::new(48000, ResampleQuality::Best);
+        let mut peak = 0.0f32;
+        for n in 0..10 {
+            let output = resampler.process(&frame(44100, 1, n * 441, 441, sine)).unwrap();
+            if n > 1 {
+                peak = samples(&output, 0).iter().fold(peak, |peak, sample| peak.max(sample.abs()));
+            }
+        }
+        assert!((peak - 0.5).abs() < 0.01, "peak {}", peak);
+    }
+
+    #[test]
+    fn same_rate_passes_through() {
+        let input = frame(48000, 2, 0, 480, |i, ch| (i + ch) as f32 / 1000.0);
+        let output = Resampler::new(48000, ResampleQuality::Best).process(&input).unwrap();
+        assert_eq!(output.data, input.data);
+    }
+
+    #[test]
+    fn surround_downmix() {
+        // L R C LFE Ls Rs
+        let levels = [0.1, 0.2, 0.3, 0.4, 0.05, 0.06];
+        let input = frame(48000, 6, 0, 4, |_, ch| levels[ch]);
+        let matrix = ChannelMatrix::for_channels(6, 2);
+        let output = remix(&input, &matrix).unwrap();
+        assert_eq!(output.channels, 2);
+        assert_eq!((output.format, output.channel_layout), (SampleFormat::F32, ChannelLayout::Interleaved));
+
+        let left = 0.1 + (0.3 + 0.05) * FOLD_GAIN;
+        let right = 0.2 + (0.3 + 0.06) * FOLD_GAIN;
+        for (channel, expected) in [(0, left), (1, right)] {
+            for sample in samples(&output, channel) {
+                assert!((sample - expected).abs() < 1e-6, "channel {}: {} vs {}", channel, sample, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn remix_keeps_integer_formats() {
+        let input = AudioFrame {
+            format: SampleFormat::S16,
+            ..AudioFrame::new(48000, 1, Duration::ZERO, Bytes::from_static(&[0x00, 0x40, 0x00, 0xc0]))
+        };
+        let output = remix(&input, &ChannelMatrix::for_channels(1, 2)).unwrap();
+        assert_eq!(output.format, SampleFormat::S16);
+        assert_eq!(&output.data[..], &[0x00, 0x40, 0x00, 0x40, 0x00, 0xc0, 0x00, 0xc0]);
+
+        assert!(remix(&input, &ChannelMatrix::for_channels(2, 2)).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::error::{Result, AqueductError};
+
+// Bit level I/O for the lossless audio entropy coder's Rice codes. Bits are
+// written most significant first.
+
+// Unary prefix length at which a Rice code switches to a raw 32-bit value
+const RICE_ESCAPE: u32 = 24;
+
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    pub(crate) fn put_bits(&mut self, value: u32, count: u32) {
+        if count == 0 {
+            return;
+        }
+        self.acc = (self.acc << count) | (value as u64 & ((1u64 << count) - 1));
+        self.bits += count;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    // Rice code with parameter k: the quotient in unary, then k low bits. Values
+    // whose quotient reaches RICE_ESCAPE are written raw after the escape prefix.
+    pub(crate) fn put_rice(&mut self, value: u32, k: u32) {
+        let quotient = value >> k;
+        if quotient < RICE_ESCAPE {
+            self.put_bits(0, quotient);
+            self.put_bits(1, 1);
+            self.put_bits(value, k);
+        } else {
+            self.put_bits(0, RICE_ESCAPE);
+            self.put_bits(value, 32);
+        }
+    }
+
+    pub(crate) fn put_signed_rice(&mut self, value: i32, k: u32) {
+        self.put_rice(fold_signed(value), k);
+    }
+
+    // Flushes to a byte boundary and hands out the bytes, leaving the writer empty
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.put_bits(0, pad);
+        }
+        self.acc = 0;
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+pub(crate) struct BitReader<'a> {
+    bytes: &'a [u8],
+    next: usize,
+    // Upcoming bits, most significant first
+    cache: u64,
+    cached: u32,
+    // Bits handed out so far, checked against the input length
+    consumed: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, next: 0, cache: 0, cached: 0, consumed: 0 }
+    }
+
+    // Tops the cache up to at least 57 bits, padding with zeros past the end
+    fn refill(&mut self) {
+        while self.cached <= 56 {
+            let byte = self.bytes.get(self.next).copied().unwrap_or(0);
+            self.next += 1;
+            self.cache |= (byte as u64) << (56 - self.cached);
+            self.cached += 8;
+        }
+    }
+
+    pub(crate) fn skip(&mut self, count: u32) -> Result<()> {
+        self.consumed += count as usize;
+        if self.consumed > self.bytes.len() * 8 {
+            return Err(AqueductError::Codec("Bitstream truncated".to_string()));
+        }
+        self.cache = if count == 64 { 0 } else { self.cache << count };
+        self.cached -= count;
+        Ok(())
+    }
+
+    // Up to 32 bits, most significant first
+    pub(crate) fn get_bits(&mut self, count: u32) -> Result<u32> {
+        if count == 0 {
+            return Ok(0);
+        }
+        self.refill();
+        let value = (self.cache >> (64 - count)) as u32;
+        self.skip(count)?;
+        Ok(value)
+    }
+
+    pub(crate) fn get_rice(&mut self, k: u32) -> Result<u32> {
+        self.refill();
+        let zeros = self.cache.leading_zeros();
+        if zeros >= RICE_ESCAPE {
+            self.skip(RICE_ESCAPE)?;
+            return self.get_bits(32);
+        }
+        self.skip(zeros + 1)?;
+        Ok((zeros << k) | self.get_bits(k)?)
+    }
+
+    pub(crate) fn get_signed_rice(&mut self, k: u32) -> Result<i32> {
+        Ok(unfold_signed(self.get_rice(k)?))
+    }
+}
+
+// 0, 1, -1, 2, -2, ... as 0, 1, 2, 3, 4, ...
+fn fold_signed(value: i32) -> u32 {
+    if value > 0 { (value as u32) * 2 - 1 } else { value.unsigned_abs() * 2 }
+}
+
+fn unfold_signed(mapped: u32) -> i32 {
+    let magnitude = mapped.div_ceil(2) as i32;
+    if mapped & 1 == 1 { magnitude } else { magnitude.wrapping_neg() }
+}
@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use crate::error::{Result, AqueductError};
+use crate::protocol::FrameRate;
+use crate::wire::{duration_to_ticks, ticks_to_duration};
+
+// Media timestamps are wall-clock times: the time since the Unix epoch, in the
+// 100ns ticks OMT carries on the wire. Frames stamped with `now()` line up across
+// senders and survive restarts, provided the machines' clocks are synchronised
+// (NTP, or PTP for frame accuracy). Like Unix time, leap seconds aren't counted.
+//
+// Receivers measure how far the sender's clock is from theirs with an NTP style
+// exchange over the connection: the receiver stamps a request when it sends it,
+// the sender stamps it when it comes in and when the reply goes out, and the
+// receiver stamps the reply when it arrives. The four times give the round trip
+// and the offset between the clocks, assuming the path is symmetric.
+
+// Exchanges kept by a ClockSync, the one with the shortest round trip is used
+const CLOCK_SAMPLES: usize = 8;
+
+// The current wall-clock time as a media timestamp
+pub fn now() -> Duration {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    ticks_to_duration(duration_to_ticks(since_epoch))
+}
+
+/// How a sender's clock relates to the local one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockEstimate {
+    // Sender clock minus local clock, in 100ns ticks
+    pub offset: i64,
+    pub round_trip: Duration,
+}
+
+impl ClockEstimate {
+    // Local time at which the sender's clock read `timestamp`
+    pub fn to_local(&self, timestamp: Duration) -> Duration {
+        ticks_to_duration(duration_to_ticks(timestamp) - self.offset)
+    }
+
+    // What the sender's clock read at local time `timestamp`
+    pub fn to_sender(&self, timestamp: Duration) -> Duration {
+        ticks_to_duration(duration_to_ticks(timestamp) + self.offset)
+    }
+
+    pub fn sender_now(&self) -> Duration {
+        self.to_sender(now())
+    }
+
+    // One-way network delay, half the round trip
+    pub fn latency(&self) -> Duration {
+        self.round_trip / 2
+    }
+
+    // How long ago a frame stamped `timestamp` by the sender was captured
+    pub fn age(&self, timestamp: Duration) -> Duration {
+        self.sender_now().saturating_sub(timestamp)
+    }
+}
+
+/// Collects clock exchanges with a sender and estimates its clock from them.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockEstimate>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds one exchange: when the request left (`origin`), reached the sender
+    // (`receive`), when the reply left the sender (`transmit`) and reached us
+    // (`arrival`). Exchanges that don't add up are ignored.
+    pub fn update(&mut self, origin: Duration, receive: Duration, transmit: Duration, arrival: Duration) -> Option<ClockEstimate> {
+        let [t0, t1, t2, t3] = [origin, receive, transmit, arrival].map(duration_to_ticks);
+        let round_trip = (t3 - t0) - (t2 - t1);
+        if round_trip < 0 || t2 < t1 {
+            log::debug!("Ignoring inconsistent clock exchange {:?}", [t0, t1, t2, t3]);
+            return None;
+        }
+
+        if self.samples.len() == CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockEstimate {
+            offset: ((t1 - t0) + (t2 - t3)) / 2,
+            round_trip: ticks_to_duration(round_trip),
+        });
+        self.estimate()
+    }
+
+    // Queueing only ever adds delay, so the fastest recent exchange is the most accurate
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.samples.iter().min_by_key(|sample| sample.round_trip).copied()
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// One frame period of a `FrameClock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tick {
+    // Index of the frame since the clock started
+    pub frame: u64,
+    // Wall-clock timestamp for the frame's video and audio
+    pub timestamp: Duration,
+    // Frames skipped because the caller fell behind, just before this one
+    pub missed: u64,
+    pub frame_rate: FrameRate,
+}
+
+impl Tick {
+    // Audio samples per channel to send with this frame
+    pub fn audio_samples(&self, sample_rate: u32) -> usize {
+        self.frame_rate.samples_in_frame(self.frame, sample_rate)
+    }
+}
+
+/// Paces a sender at an exact frame rate.
+///
+/// Frame times are computed from the frame count rather than by adding up frame
+/// durations, so 29.97 or 59.94 don't drift. A caller that falls more than a frame
+/// behind skips the frames it missed instead of bursting to catch up, the next
+/// tick reports them.
+pub struct FrameClock {
+    frame_rate: FrameRate,
+    // The runtime's clock, the same one the sleeps use
+    start: Instant,
+    // Timestamp of frame 0
+    epoch: Duration,
+    next_frame: u64,
+    overruns: u64,
+}
+
+impl FrameClock {
+    // Starts now, frame 0 is stamped with the current wall-clock time
+    pub fn new(frame_rate: FrameRate) -> Result<Self> {
+        Self::with_epoch(frame_rate, now())
+    }
+
+    // Starts now with frame 0 stamped `epoch`, e.g. to continue another clock's timeline
+    pub fn with_epoch(frame_rate: FrameRate, epoch: Duration) -> Result<Self> {
+        if !frame_rate.is_known() {
+            return Err(AqueductError::Config(format!(
+                "Frame clock needs a known frame rate, got {}/{}", frame_rate.numerator, frame_rate.denominator
+            )));
+        }
+        Ok(Self {
+            frame_rate,
+            start: Instant::now(),
+            epoch,
+            next_frame: 0,
+            overruns: 0,
+        })
+    }
+
+    pub fn frame_rate(&self) -> FrameRate {
+        self.frame_rate
+    }
+
+    pub fn timestamp(&self, frame: u64) -> Duration {
+        self.epoch + self.frame_rate.frame_offset(frame)
+    }
+
+    // Frames skipped so far
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+
+    // Waits for the next frame. The first tick completes immediately.
+    pub async fn tick(&mut self) -> Tick {
+        let current = self.frame_rate.frames_in(self.start.elapsed());
+        let mut missed = 0;
+        if current > self.next_frame {
+            missed = current - self.next_frame;
+            self.overruns += missed;
+            self.next_frame = current;
+            log::debug!("Frame clock overrun, skipped {} frames", missed);
+        }
+
+        let frame = self.next_frame;
+        tokio::time::sleep_until(self.start + self.frame_rate.frame_offset(frame)).await;
+        self.next_frame += 1;
+        Tick {
+            frame,
+            timestamp: self.timestamp(frame),
+            missed,
+            frame_rate: self.frame_rate,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPOCH: Duration = Duration::from_secs(1_700_000_000);
+
+    #[test]
+    fn audio_samples_at_29_97() {
+        let clock = FrameClock::with_epoch(FrameRate::FPS_29_97, EPOCH).unwrap();
+        let samples: Vec<usize> = (0..5)
+            .map(|frame| Tick { frame, timestamp: clock.timestamp(frame), missed: 0, frame_rate: clock.frame_rate() })
+            .map(|tick| tick.audio_samples(48000))
+            .collect();
+        assert!(samples.iter().all(|&n| n == 1601 || n == 1602), "{:?}", samples);
+        // Five frames of 29.97 are exactly 1001/6000 s, 8008 samples
+        assert_eq!(samples.iter().sum::<usize>(), 8008);
+        assert_eq!(clock.timestamp(5) - clock.timestamp(0), Duration::from_nanos(166_833_333));
+    }
+
+    #[test]
+    fn needs_a_known_rate() {
+        assert!(matches!(FrameClock::new(FrameRate::UNKNOWN), Err(AqueductError::Config(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticks_on_schedule() {
+        let mut clock = FrameClock::with_epoch(FrameRate::FPS_29_97, EPOCH).unwrap();
+        let start = Instant::now();
+        for frame in 0..10 {
+            let tick = clock.tick().await;
+            assert_eq!((tick.frame, tick.missed), (frame, 0));
+            assert_eq!(tick.timestamp, EPOCH + FrameRate::FPS_29_97.frame_offset(frame));
+            // Ticks are due at their frame time, not a fixed step after the last one
+            let due = FrameRate::FPS_29_97.frame_offset(frame);
+            assert!(start.elapsed() >= due && start.elapsed() - due < Duration::from_millis(1));
+        }
+        assert_eq!(clock.overruns(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_missed_ticks() {
+        let mut clock = FrameClock::with_epoch(FrameRate::new(30, 1), EPOCH).unwrap();
+        assert_eq!(clock.tick().await.frame, 0);
+
+        // Halfway through frame 3, frames 1 and 2 went by unsent
+        tokio::time::advance(Duration::from_millis(3 * 33 + 17)).await;
+        let tick = clock.tick().await;
+        assert_eq!((tick.frame, tick.missed), (3, 2));
+        assert_eq!(tick.timestamp, EPOCH + Duration::from_millis(100));
+
+        let tick = clock.tick().await;
+        assert_eq!((tick.frame, tick.missed), (4, 0));
+        assert_eq!(clock.overruns(), 2);
+    }
+}
@@ -1,24 +1,381 @@
-use crate::error::Result;
+use crate::error::{Result, AqueductError};
 use bytes::{Bytes, BytesMut, BufMut};
-use crate::protocol::VideoFrame;
+use crate::protocol::{AudioFrame, VideoFrame};
+use crate::wire::{fourcc, MAX_VIDEO_FRAME_SIZE};
+use crate::jpeg::JpegCodec;
+use crate::delta::DeltaCodec;
+use crate::tiles::TileCodec;
+use crate::lossless_audio::LosslessAudioCodec;
 use lz4_flex::block::get_maximum_output_size;
+use std::collections::HashMap;
+use rayon::prelude::*;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Identifies how a video or audio payload is encoded, carried as a FourCC in the
+/// video or audio header.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CodecId(pub u32);
+
+impl CodecId {
+    // Raw pixels, the header carries the pixel format FourCC instead. For audio,
+    // plain float samples sent as FPA1.
+    pub const UNCOMPRESSED: CodecId = CodecId(fourcc(b"RAW "));
+    pub const LZ4: CodecId = CodecId(fourcc(b"LZ4 "));
+    pub const ZSTD: CodecId = CodecId(fourcc(b"ZSTD"));
+    pub const JPEG: CodecId = CodecId(fourcc(b"JPEG"));
+    // Inter-frame LZ4 deltas for screen content
+    pub const DELTA: CodecId = CodecId(fourcc(b"DLZ4"));
+    // Changed tiles only, for screen capture
+    pub const TILES: CodecId = CodecId(fourcc(b"TILE"));
+    // Lossless predictive audio, FPA1 being the uncompressed audio codec
+    pub const LOSSLESS_AUDIO: CodecId = CodecId(fourcc(b"LPA1"));
+
+    pub const fn from_fourcc(code: &[u8; 4]) -> Self {
+        Self(fourcc(code))
+    }
+
+    // FourCC as text with padding spaces trimmed, e.g. "LZ4"
+    pub fn name(self) -> String {
+        String::from_utf8_lossy(&self.0.to_le_bytes()).trim_end().to_string()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > 4 || !name.is_ascii() {
+            return None;
+        }
+        let mut code = *b"    ";
+        code[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Self::from_fourcc(&code))
+    }
+}
+
+impl fmt::Debug for CodecId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CodecId({})", self.name())
+    }
+}
+
+impl fmt::Display for CodecId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+pub trait VideoEncoder: Send {
+    fn encode(&mut self, frame: &VideoFrame) -> Result<Bytes> {
+        let mut dst = BytesMut::new();
+        self.encode_into(frame, &mut dst)?;
+        Ok(dst.freeze())
+    }
 
-pub trait VideoEncoder {
-    fn encode(&mut self, frame: &VideoFrame) -> Result<Bytes>;
     fn encode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()>;
+
+    // Counters for encoders that track them
+    fn stats(&self) -> Option<EncoderStats> {
+        None
+    }
+
+    // Asks inter-frame encoders to make the next frame decodable on its own
+    fn request_keyframe(&mut self) {}
+
+    // Whether the frame just encoded decodes on its own. Inter-frame encoders
+    // return false for frames that build on the ones before them.
+    fn is_keyframe(&self) -> bool {
+        true
+    }
 }
 
-pub trait VideoDecoder {
-    fn decode(&mut self, data: &[u8]) -> Result<Bytes>;
-    fn decode_into(&mut self, data: &[u8], dst: &mut BytesMut) -> Result<()>;
+/// How an encoder has handled the frames it was given so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderStats {
+    // Frames sent compressed
+    pub compressed_frames: u64,
+    // Frames sent as is because compression didn't pay off
+    pub stored_frames: u64,
+    // Stored frames where compression wasn't even attempted
+    pub skipped_frames: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
 }
 
-// Implementation of LZ4 codec as a placeholder for VMX
-pub struct Lz4Codec;
+impl EncoderStats {
+    pub fn ratio(&self) -> f64 {
+        if self.bytes_in == 0 { 1.0 } else { self.bytes_out as f64 / self.bytes_in as f64 }
+    }
+}
+
+// Decoders get the frame as received: `frame.data` holds the encoded payload and
+// the remaining fields describe the picture it decodes to.
+pub trait VideoDecoder: Send {
+    fn decode(&mut self, frame: &VideoFrame) -> Result<Bytes> {
+        let mut dst = BytesMut::new();
+        self.decode_into(frame, &mut dst)?;
+        Ok(dst.freeze())
+    }
+
+    fn decode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()>;
+}
+
+pub trait AudioEncoder: Send {
+    fn encode(&mut self, frame: &AudioFrame) -> Result<Bytes> {
+        let mut dst = BytesMut::new();
+        self.encode_into(frame, &mut dst)?;
+        Ok(dst.freeze())
+    }
+
+    fn encode_into(&mut self, frame: &AudioFrame, dst: &mut BytesMut) -> Result<()>;
+}
+
+// Audio decoders append samples in the frame's sample format and channel layout
+pub trait AudioDecoder: Send {
+    fn decode(&mut self, frame: &AudioFrame) -> Result<Bytes> {
+        let mut dst = BytesMut::new();
+        self.decode_into(frame, &mut dst)?;
+        Ok(dst.freeze())
+    }
+
+    fn decode_into(&mut self, frame: &AudioFrame, dst: &mut BytesMut) -> Result<()>;
+}
+
+type EncoderFactory = Arc<dyn Fn() -> Box<dyn VideoEncoder> + Send + Sync>;
+type DecoderFactory = Arc<dyn Fn() -> Box<dyn VideoDecoder> + Send + Sync>;
+type AudioEncoderFactory = Arc<dyn Fn() -> Box<dyn AudioEncoder> + Send + Sync>;
+type AudioDecoderFactory = Arc<dyn Fn() -> Box<dyn AudioDecoder> + Send + Sync>;
+
+/// Maps codec identifiers to encoder and decoder constructors.
+///
+/// `CodecId::UNCOMPRESSED` needs no entry, it is always supported.
+#[derive(Clone)]
+pub struct CodecRegistry {
+    encoders: HashMap<CodecId, EncoderFactory>,
+    decoders: HashMap<CodecId, DecoderFactory>,
+    audio_encoders: HashMap<CodecId, AudioEncoderFactory>,
+    audio_decoders: HashMap<CodecId, AudioDecoderFactory>,
+}
+
+impl CodecRegistry {
+    // A registry without any codec besides uncompressed
+    pub fn empty() -> Self {
+        Self {
+            encoders: HashMap::new(),
+            decoders: HashMap::new(),
+            audio_encoders: HashMap::new(),
+            audio_decoders: HashMap::new(),
+        }
+    }
+
+    pub fn register_encoder<F>(&mut self, id: CodecId, factory: F)
+    where F: Fn() -> Box<dyn VideoEncoder> + Send + Sync + 'static
+    {
+        self.encoders.insert(id, Arc::new(factory));
+    }
+
+    pub fn register_decoder<F>(&mut self, id: CodecId, factory: F)
+    where F: Fn() -> Box<dyn VideoDecoder> + Send + Sync + 'static
+    {
+        self.decoders.insert(id, Arc::new(factory));
+    }
+
+    pub fn register_audio_encoder<F>(&mut self, id: CodecId, factory: F)
+    where F: Fn() -> Box<dyn AudioEncoder> + Send + Sync + 'static
+    {
+        self.audio_encoders.insert(id, Arc::new(factory));
+    }
+
+    pub fn register_audio_decoder<F>(&mut self, id: CodecId, factory: F)
+    where F: Fn() -> Box<dyn AudioDecoder> + Send + Sync + 'static
+    {
+        self.audio_decoders.insert(id, Arc::new(factory));
+    }
+
+    pub fn encoder(&self, id: CodecId) -> Result<Box<dyn VideoEncoder>> {
+        self.encoders.get(&id)
+            .map(|factory| factory())
+            .ok_or_else(|| AqueductError::Config(format!("No encoder registered for codec {}", id)))
+    }
+
+    pub fn decoder(&self, id: CodecId) -> Result<Box<dyn VideoDecoder>> {
+        self.decoders.get(&id)
+            .map(|factory| factory())
+            .ok_or_else(|| AqueductError::Protocol(format!("No decoder registered for codec {}", id)))
+    }
+
+    pub fn audio_encoder(&self, id: CodecId) -> Result<Box<dyn AudioEncoder>> {
+        self.audio_encoders.get(&id)
+            .map(|factory| factory())
+            .ok_or_else(|| AqueductError::Config(format!("No audio encoder registered for codec {}", id)))
+    }
+
+    pub fn audio_decoder(&self, id: CodecId) -> Result<Box<dyn AudioDecoder>> {
+        self.audio_decoders.get(&id)
+            .map(|factory| factory())
+            .ok_or_else(|| AqueductError::Protocol(format!("No audio decoder registered for codec {}", id)))
+    }
+
+    pub fn has_encoder(&self, id: CodecId) -> bool {
+        id == CodecId::UNCOMPRESSED || self.encoders.contains_key(&id)
+    }
+
+    pub fn has_decoder(&self, id: CodecId) -> bool {
+        id == CodecId::UNCOMPRESSED || self.decoders.contains_key(&id)
+    }
+
+    pub fn has_audio_encoder(&self, id: CodecId) -> bool {
+        id == CodecId::UNCOMPRESSED || self.audio_encoders.contains_key(&id)
+    }
+
+    pub fn has_audio_decoder(&self, id: CodecId) -> bool {
+        id == CodecId::UNCOMPRESSED || self.audio_decoders.contains_key(&id)
+    }
+
+    // Video and audio codecs share the identifier space advertised in the handshake
+    pub fn encoder_ids(&self) -> Vec<CodecId> {
+        sorted_ids(self.encoders.keys().chain(self.audio_encoders.keys()))
+    }
+
+    pub fn decoder_ids(&self) -> Vec<CodecId> {
+        sorted_ids(self.decoders.keys().chain(self.audio_decoders.keys()))
+    }
+}
+
+impl Default for CodecRegistry {
+    // All codecs built into Aqueduct
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register_encoder(CodecId::LZ4, || Box::new(Lz4Codec::new()));
+        registry.register_decoder(CodecId::LZ4, || Box::new(Lz4Codec::new()));
+        registry.register_encoder(CodecId::ZSTD, || Box::new(ZstdCodec::new()));
+        registry.register_decoder(CodecId::ZSTD, || Box::new(ZstdCodec::new()));
+        registry.register_encoder(CodecId::JPEG, || Box::new(JpegCodec::new()));
+        registry.register_decoder(CodecId::JPEG, || Box::new(JpegCodec::new()));
+        registry.register_encoder(CodecId::DELTA, || Box::new(DeltaCodec::new()));
+        registry.register_decoder(CodecId::DELTA, || Box::new(DeltaCodec::new()));
+        registry.register_encoder(CodecId::TILES, || Box::new(TileCodec::new()));
+        registry.register_decoder(CodecId::TILES, || Box::new(TileCodec::new()));
+        registry.register_audio_encoder(CodecId::LOSSLESS_AUDIO, || Box::new(LosslessAudioCodec::new()));
+        registry.register_audio_decoder(CodecId::LOSSLESS_AUDIO, || Box::new(LosslessAudioCodec::new()));
+        registry
+    }
+}
+
+// Uncompressed first, then the rest in a stable order
+fn sorted_ids<'a>(ids: impl Iterator<Item = &'a CodecId>) -> Vec<CodecId> {
+    let mut ids: Vec<CodecId> = ids.copied().filter(|id| *id != CodecId::UNCOMPRESSED).collect();
+    ids.sort_by_key(|id| id.name());
+    ids.dedup();
+    ids.insert(0, CodecId::UNCOMPRESSED);
+    ids
+}
+
+// Lossless LZ4 codec, cheap on CPU but only effective on synthetic content.
+//
+// Payload: [Size: u32][LZ4 block], where Size is the uncompressed length. When the
+// top bit of Size is set the frame is stored: the data follows as is.
+//
+// Large frames are split into horizontal slices compressed independently and in
+// parallel. Bit 30 of Size then marks a slice table in front of the blocks:
+//
+//   [Size: u32][Slices: u16][Reserved: u16]
+//   Slices x [Uncompressed Length: u32][Compressed Length: u32]
+//   [LZ4 block] x Slices
+//
+// Slices start on line boundaries so receivers can also decode them concurrently.
+//
+// Frames that don't shrink by at least MIN_SAVING are stored, and compression is
+// then skipped for a growing number of frames before it is tried again, so noisy
+// camera content doesn't burn CPU on either end.
+pub struct Lz4Codec {
+    max_slices: usize,
+    // Frames left to store without trying to compress
+    skip: u32,
+    // Skip length to use after the next poor frame
+    backoff: u32,
+    stats: EncoderStats,
+}
+
+const LZ4_STORED: u32 = 1 << 31;
+const LZ4_SLICED: u32 = 1 << 30;
+const LZ4_SIZE_MASK: u32 = LZ4_SLICED - 1;
+const MIN_SAVING: f64 = 0.02;
+
+// Frames get one slice per SLICE_BYTES, up to max_slices
+const SLICE_BYTES: usize = 1 << 20;
+const DEFAULT_MAX_SLICES: usize = 16;
+const MIN_BACKOFF: u32 = 4;
+const MAX_BACKOFF: u32 = 120;
+
+impl Default for Lz4Codec {
+    fn default() -> Self {
+        Self {
+            max_slices: DEFAULT_MAX_SLICES,
+            skip: 0,
+            backoff: MIN_BACKOFF,
+            stats: EncoderStats::default(),
+        }
+    }
+}
 
 impl Lz4Codec {
     pub fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    // Upper bound on slices per frame, 1 disables slicing
+    pub fn with_max_slices(max_slices: usize) -> Self {
+        Self {
+            max_slices: max_slices.clamp(1, u16::MAX as usize),
+            ..Self::default()
+        }
+    }
+
+    // Compresses line aligned slices in parallel behind a slice table
+    fn compress_sliced(frame: &VideoFrame, slices: &[Range<usize>], dst: &mut BytesMut) -> Result<()> {
+        let start = dst.len();
+        let table_len = 8 + slices.len() * 8;
+        let max_lens: Vec<usize> = slices.iter().map(|slice| get_maximum_output_size(slice.len())).collect();
+        let body_start = start + table_len;
+        dst.resize(body_start + max_lens.iter().sum::<usize>(), 0);
+
+        // Every slice compresses into its own worst case sized region
+        let mut regions = Vec::with_capacity(slices.len());
+        let mut rest = &mut dst[body_start..];
+        for &max_len in &max_lens {
+            let (region, tail) = rest.split_at_mut(max_len);
+            regions.push(region);
+            rest = tail;
+        }
+        let sizes = slices.par_iter()
+            .zip(regions.into_par_iter())
+            .map(|(slice, region)| lz4_flex::compress_into(&frame.data[slice.clone()], region))
+            .collect::<std::result::Result<Vec<usize>, _>>()
+            .map_err(|e| AqueductError::Protocol(format!("Compression error: {}", e)))?;
+
+        // Then the blocks are packed together behind the table
+        let mut table = BytesMut::with_capacity(table_len);
+        table.put_u32_le(frame.data.len() as u32 | LZ4_SLICED);
+        table.put_u16_le(slices.len() as u16);
+        table.put_u16_le(0);
+        let (mut read, mut write) = (body_start, body_start);
+        for ((slice, &size), &max_len) in slices.iter().zip(&sizes).zip(&max_lens) {
+            table.put_u32_le(slice.len() as u32);
+            table.put_u32_le(size as u32);
+            dst.copy_within(read..read + size, write);
+            read += max_len;
+            write += size;
+        }
+        dst.truncate(write);
+        dst[start..body_start].copy_from_slice(&table);
+        Ok(())
+    }
+
+    fn store(&mut self, frame: &VideoFrame, dst: &mut BytesMut) {
+        dst.reserve(4 + frame.data.len());
+        dst.put_u32_le(frame.data.len() as u32 | LZ4_STORED);
+        dst.put_slice(&frame.data);
+        self.stats.stored_frames += 1;
     }
 }
 
@@ -30,6 +387,56 @@ impl VideoEncoder for Lz4Codec {
     }
 
     fn encode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        if frame.data.len() > LZ4_SIZE_MASK as usize {
+            return Err(AqueductError::Codec(format!("Frame too large for LZ4: {} bytes", frame.data.len())));
+        }
+        let start = dst.len();
+        self.stats.bytes_in += frame.data.len() as u64;
+
+        // Nothing to compress, e.g. a tile frame without changes. Don't let it count as poor compression.
+        if frame.data.is_empty() {
+            self.store(frame, dst);
+            self.stats.bytes_out += (dst.len() - start) as u64;
+            return Ok(());
+        }
+
+        if self.skip > 0 {
+            self.skip -= 1;
+            self.stats.skipped_frames += 1;
+            self.store(frame, dst);
+            self.stats.bytes_out += (dst.len() - start) as u64;
+            return Ok(());
+        }
+
+        let slices = slice_ranges(frame.data.len(), frame.stride as usize, self.max_slices);
+        if slices.len() > 1 {
+            Self::compress_sliced(frame, &slices, dst)?;
+        } else {
+            Self::compress(frame, dst)?;
+        }
+
+        // Fall back to storing when compression didn't pay off, and back off before trying again
+        let saving = 1.0 - (dst.len() - start) as f64 / (4 + frame.data.len()) as f64;
+        if saving < MIN_SAVING {
+            dst.truncate(start);
+            self.store(frame, dst);
+            self.skip = self.backoff;
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        } else {
+            self.stats.compressed_frames += 1;
+            self.backoff = MIN_BACKOFF;
+        }
+        self.stats.bytes_out += (dst.len() - start) as u64;
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<EncoderStats> {
+        Some(self.stats)
+    }
+}
+
+impl Lz4Codec {
+    fn compress(frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
         // Use lz4_flex's helper to get the exact maximum size required
         let max_len = get_maximum_output_size(frame.data.len());
         
@@ -56,51 +463,332 @@ impl VideoEncoder for Lz4Codec {
         dst.truncate(start_len + compressed_size);
         Ok(())
     }
+
+    fn decompress_sliced(data: &[u8], size: usize, dst: &mut BytesMut) -> Result<()> {
+        let truncated = || AqueductError::Protocol("Slice table truncated".to_string());
+        let count = u16::from_le_bytes([*data.get(4).ok_or_else(truncated)?, *data.get(5).ok_or_else(truncated)?]) as usize;
+        let table_len = 8 + count * 8;
+        let table = data.get(8..table_len).ok_or_else(truncated)?;
+
+        // Carve both the payload and the output into per slice regions
+        let start_len = dst.len();
+        dst.resize(start_len + size, 0);
+        let mut blocks = Vec::with_capacity(count);
+        let mut regions = Vec::with_capacity(count);
+        let mut input = &data[table_len..];
+        let mut output = &mut dst[start_len..];
+        for entry in table.chunks_exact(8) {
+            let uncompressed = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+            let compressed = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+            if compressed > input.len() || uncompressed > output.len() {
+                return Err(AqueductError::Protocol("Slice table doesn't match payload".to_string()));
+            }
+            let (block, rest) = input.split_at(compressed);
+            blocks.push(block);
+            input = rest;
+            let (region, rest) = std::mem::take(&mut output).split_at_mut(uncompressed);
+            regions.push(region);
+            output = rest;
+        }
+        if !output.is_empty() {
+            return Err(AqueductError::Protocol("Slice table doesn't cover the frame".to_string()));
+        }
+
+        blocks.into_par_iter()
+            .zip(regions.into_par_iter())
+            .try_for_each(|(block, region)| {
+                let size = lz4_flex::decompress_into(block, region)
+                    .map_err(|e| AqueductError::Protocol(format!("Decompression error: {}", e)))?;
+                if size != region.len() {
+                    return Err(AqueductError::Protocol("Slice decompressed to the wrong size".to_string()));
+                }
+                Ok(())
+            })
+    }
+}
+
+// Uncompressed sizes come from the payload, a few bytes could otherwise make us
+// allocate gigabytes. No codec here expands a frame beyond its pixels.
+fn check_declared_size(frame: &VideoFrame, size: usize) -> Result<()> {
+    let limit = frame.format.frame_size(frame.height, frame.stride).min(MAX_VIDEO_FRAME_SIZE);
+    if size > limit {
+        return Err(AqueductError::Protocol(format!(
+            "Declared size {} exceeds the {} bytes of a {}x{} {:?} frame",
+            size, limit, frame.width, frame.height, frame.format
+        )));
+    }
+    Ok(())
+}
+
+// Splits a frame into up to max_slices byte ranges that start on line boundaries
+fn slice_ranges(len: usize, stride: usize, max_slices: usize) -> Vec<Range<usize>> {
+    let stride = stride.max(1);
+    let lines = len.div_ceil(stride);
+    let count = (len / SLICE_BYTES).clamp(1, max_slices.max(1)).min(lines.max(1));
+    let lines_per_slice = lines.div_ceil(count);
+    (0..count)
+        .map(|i| (i * lines_per_slice * stride).min(len)..((i + 1) * lines_per_slice * stride).min(len))
+        .filter(|range| !range.is_empty())
+        .collect()
 }
 
 impl VideoDecoder for Lz4Codec {
-    fn decode(&mut self, data: &[u8]) -> Result<Bytes> {
+    fn decode(&mut self, frame: &VideoFrame) -> Result<Bytes> {
         // Read uncompressed size
+        let data = &frame.data;
         if data.len() < 4 {
              return Err(crate::error::AqueductError::Protocol("Data too short for header".to_string()));
         }
-        let uncompressed_size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let uncompressed_size = (u32::from_le_bytes([data[0], data[1], data[2], data[3]]) & LZ4_SIZE_MASK) as usize;
         
         let mut dst = BytesMut::with_capacity(uncompressed_size);
-        self.decode_into(data, &mut dst)?;
+        self.decode_into(frame, &mut dst)?;
         Ok(dst.freeze())
     }
 
-    fn decode_into(&mut self, data: &[u8], dst: &mut BytesMut) -> Result<()> {
+    fn decode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        let data = &frame.data;
         if data.len() < 4 {
              return Err(crate::error::AqueductError::Protocol("Data too short for header".to_string()));
         }
-        // lz4_flex::decompress_into needs the exact uncompressed size usually?
-        // decompress_size_prepended handles the header.
-        // But it allocates.
-        
-        // We use `decompress_into` (without size prepended logic, we handle header manually).
-        let uncompressed_size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
-        
-        // Ensure dst has space
-        if dst.capacity() < uncompressed_size {
-             dst.reserve(uncompressed_size - dst.len());
+        let size_field = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if size_field & LZ4_STORED != 0 {
+            let stored = data.get(4..4 + (size_field & !LZ4_STORED) as usize)
+                .ok_or_else(|| AqueductError::Protocol("Stored frame truncated".to_string()))?;
+            dst.extend_from_slice(stored);
+            return Ok(());
         }
-        
-        // We need to write into `dst`.
-        // Safe way: resize to uncompressed_size (zeroes it), then decompress into slice.
-        // Zeroing is cost.
-        // unsafe { dst.set_len(uncompressed_size) } is the way to avoid zeroing but requires `unsafe`.
-        // For this task, let's just use `resize` (safe) first.
-        dst.resize(uncompressed_size, 0);
-        
-        let size = lz4_flex::decompress_into(&data[4..], dst.as_mut())
-             .map_err(|e| crate::error::AqueductError::Protocol(format!("Decompression error: {}", e)))?;
-             
+
+        if size_field & LZ4_SLICED != 0 {
+            let size = (size_field & LZ4_SIZE_MASK) as usize;
+            check_declared_size(frame, size)?;
+            return Self::decompress_sliced(data, size, dst);
+        }
+
+        let uncompressed_size = size_field as usize;
+        check_declared_size(frame, uncompressed_size)?;
+
+        // Zeroed first, decompress_into writes into an initialised slice
+        let start_len = dst.len();
+        dst.resize(start_len + uncompressed_size, 0);
+        let size = lz4_flex::decompress_into(&data[4..], &mut dst[start_len..])
+            .map_err(|e| AqueductError::Protocol(format!("Decompression error: {}", e)))?;
         if size != uncompressed_size {
-             // Should not happen if header is correct
+            dst.truncate(start_len);
+            return Err(AqueductError::Protocol(format!(
+                "Frame decompressed to {} bytes, header says {}", size, uncompressed_size
+            )));
         }
-        
         Ok(())
     }
 }
+
+// Zstandard codec, slower than LZ4 but noticeably smaller, for links where bandwidth
+// costs more than CPU.
+//
+// Payload: [Size: u32][Zstandard frame], where Size is the uncompressed length.
+//
+// With a dictionary both ends must be set up with the same one, the frame carries
+// the dictionary ID and decoding fails on a mismatch.
+pub struct ZstdCodec {
+    level: i32,
+    dictionary: Option<Arc<[u8]>>,
+    // Contexts are created on first use, loading a dictionary is not free
+    compressor: Option<zstd::bulk::Compressor<'static>>,
+    decompressor: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+pub const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+impl Default for ZstdCodec {
+    fn default() -> Self {
+        Self::with_level(ZSTD_DEFAULT_LEVEL)
+    }
+}
+
+impl ZstdCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Level as understood by zstd: 1 (fastest) to 22, negative values trade ratio for speed
+    pub fn with_level(level: i32) -> Self {
+        Self {
+            level,
+            dictionary: None,
+            compressor: None,
+            decompressor: None,
+        }
+    }
+
+    pub fn with_dictionary(level: i32, dictionary: impl Into<Arc<[u8]>>) -> Self {
+        Self {
+            dictionary: Some(dictionary.into()),
+            ..Self::with_level(level)
+        }
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    fn compressor(&mut self) -> Result<&mut zstd::bulk::Compressor<'static>> {
+        if self.compressor.is_none() {
+            let compressor = match &self.dictionary {
+                Some(dictionary) => zstd::bulk::Compressor::with_dictionary(self.level, dictionary),
+                None => zstd::bulk::Compressor::new(self.level),
+            }.map_err(|e| AqueductError::Codec(format!("Zstd setup failed: {}", e)))?;
+            self.compressor = Some(compressor);
+        }
+        Ok(self.compressor.as_mut().unwrap())
+    }
+
+    fn decompressor(&mut self) -> Result<&mut zstd::bulk::Decompressor<'static>> {
+        if self.decompressor.is_none() {
+            let decompressor = match &self.dictionary {
+                Some(dictionary) => zstd::bulk::Decompressor::with_dictionary(dictionary),
+                None => zstd::bulk::Decompressor::new(),
+            }.map_err(|e| AqueductError::Codec(format!("Zstd setup failed: {}", e)))?;
+            self.decompressor = Some(decompressor);
+        }
+        Ok(self.decompressor.as_mut().unwrap())
+    }
+}
+
+impl VideoEncoder for ZstdCodec {
+    fn encode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        let size = u32::try_from(frame.data.len())
+            .map_err(|_| AqueductError::Codec(format!("Frame too large for Zstd: {} bytes", frame.data.len())))?;
+        dst.put_u32_le(size);
+
+        let start = dst.len();
+        dst.resize(start + zstd::zstd_safe::compress_bound(frame.data.len()), 0);
+        let compressed = self.compressor()?
+            .compress_to_buffer(&frame.data, &mut dst[start..])
+            .map_err(|e| AqueductError::Codec(format!("Zstd compression error: {}", e)))?;
+        dst.truncate(start + compressed);
+        Ok(())
+    }
+}
+
+impl VideoDecoder for ZstdCodec {
+    fn decode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        let data = &frame.data;
+        if data.len() < 4 {
+            return Err(AqueductError::Codec("Zstd payload too short".to_string()));
+        }
+        let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        check_declared_size(frame, size)?;
+
+        let start = dst.len();
+        dst.resize(start + size, 0);
+        let decompressed = self.decompressor()?
+            .decompress_to_buffer(&data[4..], &mut dst[start..])
+            .map_err(|e| AqueductError::Codec(format!("Zstd decompression error: {}", e)))?;
+        if decompressed != size {
+            return Err(AqueductError::Codec("Zstd frame decompressed to the wrong size".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PixelFormat;
+    use std::time::Duration;
+
+    // A gradient compresses well but not to nothing
+    fn bgra_frame(width: u32, height: u32) -> VideoFrame {
+        let data: Vec<u8> = (0..width * height * 4).map(|i| (i / 64) as u8).collect();
+        VideoFrame::new(width, height, PixelFormat::BGRA, Duration::ZERO, Bytes::from(data))
+    }
+
+    // The frame as a receiver gets it: same picture, encoded payload
+    fn encoded(frame: &VideoFrame, codec: CodecId, payload: Bytes) -> VideoFrame {
+        VideoFrame { codec, data: payload, ..frame.clone() }
+    }
+
+    fn lz4_payload(size_field: u32, rest: &[u8]) -> Bytes {
+        let mut payload = BytesMut::new();
+        payload.put_u32_le(size_field);
+        payload.put_slice(rest);
+        payload.freeze()
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        let frame = bgra_frame(64, 64);
+        let mut codec = Lz4Codec::new();
+        let payload = codec.encode(&frame).unwrap();
+        assert!(payload.len() < frame.data.len());
+        assert_eq!(codec.decode(&encoded(&frame, CodecId::LZ4, payload)).unwrap(), frame.data);
+    }
+
+    #[test]
+    fn lz4_sliced_round_trip() {
+        let frame = bgra_frame(1024, 1024);
+        let mut codec = Lz4Codec::new();
+        let payload = codec.encode(&frame).unwrap();
+        assert_ne!(u32::from_le_bytes(payload[..4].try_into().unwrap()) & LZ4_SLICED, 0);
+        assert_eq!(codec.decode(&encoded(&frame, CodecId::LZ4, payload)).unwrap(), frame.data);
+    }
+
+    #[test]
+    fn lz4_rejects_oversized_declarations() {
+        let frame = bgra_frame(64, 64);
+        let mut codec = Lz4Codec::new();
+        // Close to 1 GiB for a 16 KiB frame, whole and sliced
+        for size_field in [LZ4_SIZE_MASK, LZ4_SIZE_MASK | LZ4_SLICED] {
+            let result = codec.decode(&encoded(&frame, CodecId::LZ4, lz4_payload(size_field, &[1, 0, 0, 0, 0, 0, 0, 0])));
+            assert!(matches!(result, Err(AqueductError::Protocol(_))), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn lz4_rejects_wrong_sizes() {
+        let frame = bgra_frame(64, 64);
+        let mut codec = Lz4Codec::new();
+
+        // Valid block of 63 lines under a header claiming 64
+        let payload = codec.encode(&bgra_frame(64, 63)).unwrap();
+        let mut wrong = BytesMut::from(&payload[..]);
+        wrong[..4].copy_from_slice(&(frame.data.len() as u32).to_le_bytes());
+        let result = codec.decode(&encoded(&frame, CodecId::LZ4, wrong.freeze()));
+        assert!(matches!(&result, Err(AqueductError::Protocol(e)) if e.contains("header says")), "{:?}", result);
+
+        // Slice table claiming more slices than it has
+        assert!(codec.decode(&encoded(&frame, CodecId::LZ4, lz4_payload(16 | LZ4_SLICED, &[9, 0, 0, 0]))).is_err());
+
+        // Stored frame cut short
+        assert!(codec.decode(&encoded(&frame, CodecId::LZ4, lz4_payload(100 | LZ4_STORED, &[0; 10]))).is_err());
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        let frame = bgra_frame(64, 64);
+        let mut codec = ZstdCodec::new();
+        let payload = codec.encode(&frame).unwrap();
+        assert!(payload.len() < frame.data.len());
+        assert_eq!(codec.decode(&encoded(&frame, CodecId::ZSTD, payload)).unwrap(), frame.data);
+    }
+
+    #[test]
+    fn zstd_rejects_oversized_declarations() {
+        let frame = bgra_frame(64, 64);
+        let mut codec = ZstdCodec::new();
+        let payload = codec.encode(&frame).unwrap();
+
+        // 1 GiB for a 16 KiB frame, refused before anything is allocated
+        let mut huge = BytesMut::from(&payload[..]);
+        huge[..4].copy_from_slice(&(1u32 << 30).to_le_bytes());
+        let result = codec.decode(&encoded(&frame, CodecId::ZSTD, huge.freeze()));
+        assert!(matches!(result, Err(AqueductError::Protocol(_))), "{:?}", result);
+
+        // 63 lines under a header claiming 64
+        let payload = codec.encode(&bgra_frame(64, 63)).unwrap();
+        let mut wrong = BytesMut::from(&payload[..]);
+        wrong[..4].copy_from_slice(&(frame.data.len() as u32).to_le_bytes());
+        assert!(codec.decode(&encoded(&frame, CodecId::ZSTD, wrong.freeze())).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpStream};
+use crate::codec::{CodecId, CodecRegistry};
+use crate::error::{Result, AqueductError};
+use crate::handshake::Capabilities;
+use crate::queue::QueuePolicy;
+
+pub const DEFAULT_ENCODE_QUEUE_DEPTH: usize = 4;
+pub const DEFAULT_BACKLOG: i32 = 128;
+
+/// Everything a `Sender` can be set up with. `Sender::new` and friends use the
+/// defaults, listening on all IPv4 interfaces.
+#[derive(Clone)]
+pub struct SenderConfig {
+    // Address to listen on, IPv4 or IPv6. An interface's address restricts the
+    // sender to that NIC. Port 0 picks a free port, see `Sender::local_addr`.
+    pub bind_address: SocketAddr,
+    // Network interface to bind to by name (e.g. "eth1"), Linux only
+    pub interface: Option<String>,
+    pub capabilities: Capabilities,
+    pub registry: Arc<CodecRegistry>,
+    // Video codec to start with, LZ4 if the registry has it when None
+    pub codec: Option<CodecId>,
+    pub audio_codec: CodecId,
+    // How each receiver's queue deals with a receiver that can't keep up
+    pub queue_policy: QueuePolicy,
+    // Packets each encode worker holds before `enqueue` waits and `try_send` fails
+    pub encode_queue_depth: usize,
+    // Receivers beyond this are turned away in the handshake
+    pub max_receivers: Option<usize>,
+    pub socket: SocketOptions,
+}
+
+impl Default for SenderConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            interface: None,
+            capabilities: Capabilities::new("Aqueduct Sender"),
+            registry: Arc::new(CodecRegistry::default()),
+            codec: None,
+            audio_codec: CodecId::UNCOMPRESSED,
+            queue_policy: QueuePolicy::default(),
+            encode_queue_depth: DEFAULT_ENCODE_QUEUE_DEPTH,
+            max_receivers: None,
+            socket: SocketOptions::default(),
+        }
+    }
+}
+
+impl SenderConfig {
+    pub fn new(bind_address: SocketAddr) -> Self {
+        Self {
+            bind_address,
+            ..Self::default()
+        }
+    }
+
+    // All IPv4 interfaces on the given port
+    pub fn with_port(port: u16) -> Self {
+        Self::new(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+    }
+
+    pub fn with_interface(mut self, interface: impl Into<String>) -> Self {
+        self.interface = Some(interface.into());
+        self
+    }
+
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_registry(mut self, registry: Arc<CodecRegistry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn with_codec(mut self, codec: CodecId) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    pub fn with_audio_codec(mut self, codec: CodecId) -> Self {
+        self.audio_codec = codec;
+        self
+    }
+
+    pub fn with_queue_policy(mut self, policy: QueuePolicy) -> Self {
+        self.queue_policy = policy;
+        self
+    }
+
+    pub fn with_encode_queue_depth(mut self, depth: usize) -> Self {
+        self.encode_queue_depth = depth;
+        self
+    }
+
+    pub fn with_max_receivers(mut self, max: usize) -> Self {
+        self.max_receivers = Some(max);
+        self
+    }
+
+    pub fn with_socket_options(mut self, socket: SocketOptions) -> Self {
+        self.socket = socket;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    // Write small packets such as audio right away instead of batching them
+    pub nodelay: bool,
+    pub reuse_address: bool,
+    // For IPv6 addresses: refuse IPv4 clients instead of accepting both. None
+    // keeps the system default.
+    pub only_v6: Option<bool>,
+    pub send_buffer_size: Option<usize>,
+    // IP type of service byte, DSCP in the upper six bits, for networks that
+    // prioritise media traffic
+    pub tos: Option<u32>,
+    // Idle time before TCP keepalive probes, to notice receivers that vanished
+    pub keepalive: Option<Duration>,
+    pub backlog: i32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            // Windows lets another process steal a port bound with SO_REUSEADDR
+            reuse_address: cfg!(unix),
+            only_v6: None,
+            send_buffer_size: None,
+            tos: None,
+            keepalive: None,
+            backlog: DEFAULT_BACKLOG,
+        }
+    }
+}
+
+impl SocketOptions {
+    // Options of each accepted connection
+    pub(crate) fn apply(&self, stream: &TcpStream, peer: SocketAddr) -> Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = socket2::SockRef::from(stream);
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            // IPv6 has its own traffic class option, IPv4 clients of a dual-stack
+            // listener still go by the IPv4 one
+            match peer {
+                SocketAddr::V6(address) if address.ip().to_ipv4_mapped().is_none() => set_tclass_v6(&socket, tos)?,
+                _ => set_tos(&socket, tos)?,
+            }
+        }
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn bind_listener(config: &SenderConfig) -> Result<TcpListener> {
+    let address = config.bind_address;
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(config.socket.reuse_address)?;
+    if let (SocketAddr::V6(_), Some(only_v6)) = (address, config.socket.only_v6) {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(interface) = &config.interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.bind(&address.into())?;
+    socket.listen(config.socket.backlog)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+        .map_err(|e| AqueductError::Config(format!("Can't bind to interface {}: {}", interface, e)))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, interface: &str) -> Result<()> {
+    Err(AqueductError::Config(format!(
+        "Binding to interface {} by name isn't supported on this platform, bind to its address instead", interface
+    )))
+}
+
+#[cfg(not(any(target_os = "fuchsia", target_os = "redox", target_os = "solaris", target_os = "illumos", target_os = "haiku")))]
+fn set_tos(socket: &socket2::SockRef, tos: u32) -> Result<()> {
+    socket.set_tos(tos)?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "fuchsia", target_os = "redox", target_os = "solaris", target_os = "illumos", target_os = "haiku"))]
+fn set_tos(_socket: &socket2::SockRef, _tos: u32) -> Result<()> {
+    Err(AqueductError::Config("Setting the type of service isn't supported on this platform".to_string()))
+}
+
+#[cfg(any(
+    target_os = "android", target_os = "dragonfly", target_os = "freebsd", target_os = "fuchsia",
+    target_os = "linux", target_os = "macos", target_os = "netbsd", target_os = "openbsd",
+))]
+fn set_tclass_v6(socket: &socket2::SockRef, tclass: u32) -> Result<()> {
+    socket.set_tclass_v6(tclass)?;
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "android", target_os = "dragonfly", target_os = "freebsd", target_os = "fuchsia",
+    target_os = "linux", target_os = "macos", target_os = "netbsd", target_os = "openbsd",
+)))]
+fn set_tclass_v6(_socket: &socket2::SockRef, _tclass: u32) -> Result<()> {
+    Err(AqueductError::Config("Setting the IPv6 traffic class isn't supported on this platform".to_string()))
+}
@@ -0,0 +1,78 @@
+use serde::Deserialize;
+use std::time::Duration;
+use crate::handshake::root_element;
+use crate::protocol::MetadataFrame;
+use crate::wire::{duration_to_ticks, ticks_to_duration};
+
+const REFRESH_ELEMENT: &str = "AqueductRefresh";
+const CLOCK_REQUEST_ELEMENT: &str = "AqueductClockRequest";
+const CLOCK_REPLY_ELEMENT: &str = "AqueductClockReply";
+
+/// Requests a receiver sends back to the sender once media is flowing, and the
+/// sender's replies, carried as OMT metadata frames like the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    // Make the next video frame a keyframe
+    RequestKeyframe,
+    // Asks for the sender's clock, `origin` is the receiver's time when sending
+    ClockRequest { origin: Duration },
+    // Sender's times when the request came in and when the reply went out
+    ClockReply { origin: Duration, receive: Duration, transmit: Duration },
+}
+
+// Times are in 100ns ticks, as in the frame header
+#[derive(Deserialize)]
+struct ClockXml {
+    #[serde(rename = "Origin")]
+    origin: i64,
+    #[serde(rename = "Receive", default)]
+    receive: i64,
+    #[serde(rename = "Transmit", default)]
+    transmit: i64,
+}
+
+impl ControlMessage {
+    pub fn to_metadata(&self) -> MetadataFrame {
+        let content = match self {
+            Self::RequestKeyframe => format!("<{} />", REFRESH_ELEMENT),
+            Self::ClockRequest { origin } => format!(
+                "<{0}><Origin>{1}</Origin></{0}>",
+                CLOCK_REQUEST_ELEMENT, duration_to_ticks(*origin)
+            ),
+            Self::ClockReply { origin, receive, transmit } => format!(
+                "<{0}><Origin>{1}</Origin><Receive>{2}</Receive><Transmit>{3}</Transmit></{0}>",
+                CLOCK_REPLY_ELEMENT, duration_to_ticks(*origin), duration_to_ticks(*receive), duration_to_ticks(*transmit)
+            ),
+        };
+        MetadataFrame {
+            timestamp: Duration::ZERO,
+            content,
+        }
+    }
+
+    // None for metadata that isn't a control message
+    pub fn from_metadata(frame: &MetadataFrame) -> Option<Self> {
+        match root_element(&frame.content)? {
+            REFRESH_ELEMENT => Some(Self::RequestKeyframe),
+            CLOCK_REQUEST_ELEMENT => {
+                let clock = parse_clock(&frame.content)?;
+                Some(Self::ClockRequest { origin: ticks_to_duration(clock.origin) })
+            }
+            CLOCK_REPLY_ELEMENT => {
+                let clock = parse_clock(&frame.content)?;
+                Some(Self::ClockReply {
+                    origin: ticks_to_duration(clock.origin),
+                    receive: ticks_to_duration(clock.receive),
+                    transmit: ticks_to_duration(clock.transmit),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_clock(content: &str) -> Option<ClockXml> {
+    serde_xml_rs::from_str(content)
+        .map_err(|e| log::debug!("Malformed clock message {}: {}", content, e))
+        .ok()
+}
@@ -0,0 +1,879 @@
+use bytes::{Bytes, BytesMut};
+use crate::error::{Result, AqueductError};
+use crate::protocol::{VideoFrame, PixelFormat, Colorimetry, ColorMatrix, ColorRange};
+
+// Pixel format conversion.
+//
+// Every format is unpacked into a 16-bit 4:4:4 intermediate two lines at a time
+// (enough to cover 4:2:0 chroma), converted between YUV and RGB if needed and
+// packed into the destination. 8-bit samples map to 16-bit as v * 257.
+// The hot paths (BGRA <-> UYVY, NV12 -> BGRA) bypass the intermediate and use
+// fixed point math over plain slices, written so the compiler can vectorize them.
+//
+// Only the matrix and range of the colorimetry are applied. Transfer function and
+// primaries are passed through, no tone mapping or gamut conversion is done.
+
+/// Everything `convert` can read or write: the OMT pixel formats plus the
+/// RGB layouts capture devices and UI toolkits usually hand out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Pixel(PixelFormat),
+    Rgba,
+    Rgb24,
+}
+
+impl ImageFormat {
+    // Saturates for absurd widths, see `checked_stride`
+    pub fn default_stride(self, width: u32) -> u32 {
+        self.checked_stride(width).unwrap_or(u32::MAX)
+    }
+
+    // None when the stride doesn't fit in a u32
+    pub fn checked_stride(self, width: u32) -> Option<u32> {
+        match self {
+            Self::Pixel(format) => format.checked_stride(width),
+            Self::Rgba => width.checked_mul(4),
+            Self::Rgb24 => width.checked_mul(3),
+        }
+    }
+
+    pub fn frame_size(self, height: u32, stride: u32) -> usize {
+        match self {
+            Self::Pixel(format) => format.frame_size(height, stride),
+            Self::Rgba | Self::Rgb24 => stride as usize * height as usize,
+        }
+    }
+
+    pub fn is_yuv(self) -> bool {
+        !matches!(self, Self::Pixel(PixelFormat::BGRA) | Self::Rgba | Self::Rgb24)
+    }
+}
+
+impl From<PixelFormat> for ImageFormat {
+    fn from(format: PixelFormat) -> Self {
+        Self::Pixel(format)
+    }
+}
+
+/// Describes an image buffer: its format, size, stride of the first plane and colorimetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLayout {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub colorimetry: Colorimetry,
+}
+
+impl ImageLayout {
+    // Tightly packed image with the default colorimetry for its size
+    pub fn new(format: impl Into<ImageFormat>, width: u32, height: u32) -> Self {
+        let format = format.into();
+        Self {
+            format,
+            width,
+            height,
+            stride: format.default_stride(width),
+            colorimetry: Colorimetry::for_height(height),
+        }
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.format.frame_size(self.height, self.stride)
+    }
+}
+
+impl From<&VideoFrame> for ImageLayout {
+    fn from(frame: &VideoFrame) -> Self {
+        Self {
+            format: frame.format.into(),
+            width: frame.width,
+            height: frame.height,
+            stride: frame.stride,
+            colorimetry: frame.colorimetry,
+        }
+    }
+}
+
+/// Converts `src` laid out as `from` into the `to` layout, appending the result to `dst`.
+pub fn convert(src: &[u8], from: &ImageLayout, to: &ImageLayout, dst: &mut BytesMut) -> Result<()> {
+    if from.width != to.width || from.height != to.height {
+        return Err(AqueductError::Conversion(format!(
+            "Size mismatch: {}x{} -> {}x{} (scaling is not supported)",
+            from.width, from.height, to.width, to.height
+        )));
+    }
+    for layout in [from, to] {
+        if layout.stride < layout.format.default_stride(layout.width) {
+            return Err(AqueductError::Conversion(format!(
+                "Stride {} too small for {:?} at width {}", layout.stride, layout.format, layout.width
+            )));
+        }
+    }
+    if src.len() < from.frame_size() {
+        return Err(AqueductError::Conversion(format!(
+            "Source buffer too short: {} bytes, expected {}", src.len(), from.frame_size()
+        )));
+    }
+
+    let start = dst.len();
+    dst.resize(start + to.frame_size(), 0);
+    let out = &mut dst[start..];
+
+    if !convert_fast(src, from, to, out) {
+        convert_generic(src, from, to, out);
+    }
+    Ok(())
+}
+
+/// Converts a frame to another pixel format, keeping its colorimetry and packing lines tightly.
+pub fn convert_frame(frame: &VideoFrame, format: PixelFormat) -> Result<VideoFrame> {
+    let from = ImageLayout::from(frame);
+    let to = ImageLayout {
+        format: format.into(),
+        stride: format.default_stride(frame.width),
+        ..from
+    };
+
+    let mut dst = BytesMut::with_capacity(to.frame_size());
+    convert(&frame.data, &from, &to, &mut dst)?;
+
+    let mut converted = frame.clone();
+    converted.format = format;
+    converted.stride = to.stride;
+    converted.flags.high_bit_depth = matches!(format, PixelFormat::P216 | PixelFormat::PA16);
+    converted.data = dst.freeze();
+    Ok(converted)
+}
+
+/// Converts a frame to tightly packed BGRA, e.g. for display.
+pub fn to_bgra(frame: &VideoFrame) -> Result<Bytes> {
+    if frame.format == PixelFormat::BGRA && frame.stride == frame.format.default_stride(frame.width) {
+        return Ok(frame.data.clone());
+    }
+    Ok(convert_frame(frame, PixelFormat::BGRA)?.data)
+}
+
+// --- Colour math -----------------------------------------------------------
+
+// Floating point matrix, used by the generic path on 16-bit samples
+#[derive(Debug, Clone, Copy)]
+struct YuvCoefficients {
+    kr: f32,
+    kg: f32,
+    kb: f32,
+    full_range: bool,
+}
+
+impl YuvCoefficients {
+    fn new(colorimetry: &Colorimetry) -> Self {
+        let (kr, kb) = match colorimetry.matrix {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+            ColorMatrix::Bt2020 => (0.2627, 0.0593),
+        };
+        Self {
+            kr,
+            kg: 1.0 - kr - kb,
+            kb,
+            full_range: colorimetry.range == ColorRange::Full,
+        }
+    }
+
+    fn yuv_to_rgb(&self, y: u16, u: u16, v: u16) -> [u16; 3] {
+        // Work in 8-bit units so the range offsets read like the specs
+        let (y, u, v) = (y as f32 / 257.0, u as f32 / 257.0, v as f32 / 257.0);
+        let (y, u, v) = if self.full_range {
+            (y / 255.0, (u - 128.0) / 255.0, (v - 128.0) / 255.0)
+        } else {
+            ((y - 16.0) / 219.0, (u - 128.0) / 224.0, (v - 128.0) / 224.0)
+        };
+
+        let r = y + 2.0 * (1.0 - self.kr) * v;
+        let b = y + 2.0 * (1.0 - self.kb) * u;
+        let g = (y - self.kr * r - self.kb * b) / self.kg;
+        [unit_to_u16(r), unit_to_u16(g), unit_to_u16(b)]
+    }
+
+    fn rgb_to_yuv(&self, r: u16, g: u16, b: u16) -> [u16; 3] {
+        let (r, g, b) = (r as f32 / 65535.0, g as f32 / 65535.0, b as f32 / 65535.0);
+        let y = self.kr * r + self.kg * g + self.kb * b;
+        let u = (b - y) / (2.0 * (1.0 - self.kb));
+        let v = (r - y) / (2.0 * (1.0 - self.kr));
+
+        let (y, u, v) = if self.full_range {
+            (y * 255.0, 128.0 + u * 255.0, 128.0 + v * 255.0)
+        } else {
+            (16.0 + y * 219.0, 128.0 + u * 224.0, 128.0 + v * 224.0)
+        };
+        [eight_bit_to_u16(y), eight_bit_to_u16(u), eight_bit_to_u16(v)]
+    }
+}
+
+fn unit_to_u16(x: f32) -> u16 {
+    (x.clamp(0.0, 1.0) * 65535.0 + 0.5) as u16
+}
+
+fn eight_bit_to_u16(x: f32) -> u16 {
+    (x.clamp(0.0, 255.0) * 257.0 + 0.5) as u16
+}
+
+fn expand(v: u8) -> u16 {
+    v as u16 * 257
+}
+
+fn narrow(v: u16) -> u8 {
+    ((v as u32 + 128) / 257).min(255) as u8
+}
+
+// Q14 fixed point version of the matrix for the 8-bit fast paths
+const FIXED_SHIFT: i32 = 14;
+const FIXED_ROUND: i32 = 1 << (FIXED_SHIFT - 1);
+
+#[derive(Debug, Clone, Copy)]
+struct FixedCoefficients {
+    y_offset: i32,
+    // YUV -> RGB
+    y_scale: i32,
+    rv: i32,
+    gu: i32,
+    gv: i32,
+    bu: i32,
+    // RGB -> YUV
+    yr: i32,
+    yg: i32,
+    yb: i32,
+    ur: i32,
+    ug: i32,
+    ub: i32,
+    vr: i32,
+    vg: i32,
+    vb: i32,
+}
+
+impl FixedCoefficients {
+    fn new(colorimetry: &Colorimetry) -> Self {
+        let k = YuvCoefficients::new(colorimetry);
+        let fixed = |x: f32| (x * (1 << FIXED_SHIFT) as f32).round() as i32;
+        let (y_offset, y_scale, c_scale) = if k.full_range {
+            (0, 1.0, 1.0)
+        } else {
+            (16, 255.0 / 219.0, 255.0 / 224.0)
+        };
+
+        Self {
+            y_offset,
+            y_scale: fixed(y_scale),
+            rv: fixed(2.0 * (1.0 - k.kr) * c_scale),
+            gu: fixed(2.0 * (1.0 - k.kb) * k.kb / k.kg * c_scale),
+            gv: fixed(2.0 * (1.0 - k.kr) * k.kr / k.kg * c_scale),
+            bu: fixed(2.0 * (1.0 - k.kb) * c_scale),
+            yr: fixed(k.kr / y_scale),
+            yg: fixed(k.kg / y_scale),
+            yb: fixed(k.kb / y_scale),
+            ur: fixed(-k.kr / (2.0 * (1.0 - k.kb)) / c_scale),
+            ug: fixed(-k.kg / (2.0 * (1.0 - k.kb)) / c_scale),
+            ub: fixed(0.5 / c_scale),
+            vr: fixed(0.5 / c_scale),
+            vg: fixed(-k.kg / (2.0 * (1.0 - k.kr)) / c_scale),
+            vb: fixed(-k.kb / (2.0 * (1.0 - k.kr)) / c_scale),
+        }
+    }
+
+    // Writes one BGRA pixel
+    #[inline(always)]
+    fn yuv_to_bgra(&self, y: u8, u: i32, v: i32, out: &mut [u8]) {
+        let c = (y as i32 - self.y_offset) * self.y_scale + FIXED_ROUND;
+        out[0] = clamp_u8((c + self.bu * u) >> FIXED_SHIFT);
+        out[1] = clamp_u8((c - self.gu * u - self.gv * v) >> FIXED_SHIFT);
+        out[2] = clamp_u8((c + self.rv * v) >> FIXED_SHIFT);
+        out[3] = 255;
+    }
+
+    #[inline(always)]
+    fn luma(&self, r: i32, g: i32, b: i32) -> u8 {
+        clamp_u8(self.y_offset + ((self.yr * r + self.yg * g + self.yb * b + FIXED_ROUND) >> FIXED_SHIFT))
+    }
+
+    #[inline(always)]
+    fn chroma(&self, r: i32, g: i32, b: i32) -> (u8, u8) {
+        let u = 128 + ((self.ur * r + self.ug * g + self.ub * b + FIXED_ROUND) >> FIXED_SHIFT);
+        let v = 128 + ((self.vr * r + self.vg * g + self.vb * b + FIXED_ROUND) >> FIXED_SHIFT);
+        (clamp_u8(u), clamp_u8(v))
+    }
+}
+
+#[inline(always)]
+fn clamp_u8(x: i32) -> u8 {
+    x.clamp(0, 255) as u8
+}
+
+// --- Fast paths ------------------------------------------------------------
+
+fn convert_fast(src: &[u8], from: &ImageLayout, to: &ImageLayout, out: &mut [u8]) -> bool {
+    use ImageFormat::{Pixel, Rgba};
+
+    // Same colours on both sides: a straight copy, only strides may differ
+    let same_colors = !from.format.is_yuv()
+        || (from.colorimetry.matrix == to.colorimetry.matrix && from.colorimetry.range == to.colorimetry.range);
+    if from.format == to.format && same_colors {
+        copy_planes(src, from, to, out);
+        return true;
+    }
+
+    let width = from.width as usize;
+    let height = from.height as usize;
+    let (src_stride, dst_stride) = (from.stride as usize, to.stride as usize);
+
+    match (from.format, to.format) {
+        (Pixel(PixelFormat::BGRA), Rgba) | (Rgba, Pixel(PixelFormat::BGRA)) => {
+            for y in 0..height {
+                let src_row = &src[y * src_stride..][..width * 4];
+                let dst_row = &mut out[y * dst_stride..][..width * 4];
+                for (s, d) in src_row.chunks_exact(4).zip(dst_row.chunks_exact_mut(4)) {
+                    d[0] = s[2];
+                    d[1] = s[1];
+                    d[2] = s[0];
+                    d[3] = s[3];
+                }
+            }
+            true
+        }
+        (Pixel(PixelFormat::UYVY), Pixel(PixelFormat::BGRA)) => {
+            let k = FixedCoefficients::new(&from.colorimetry);
+            for y in 0..height {
+                let src_row = &src[y * src_stride..][..from.format.default_stride(from.width) as usize];
+                let dst_row = &mut out[y * dst_stride..][..width * 4];
+                uyvy_row_to_bgra(&k, src_row, dst_row, width);
+            }
+            true
+        }
+        (Pixel(PixelFormat::BGRA), Pixel(PixelFormat::UYVY)) => {
+            let k = FixedCoefficients::new(&to.colorimetry);
+            for y in 0..height {
+                let src_row = &src[y * src_stride..][..width * 4];
+                let dst_row = &mut out[y * dst_stride..][..to.format.default_stride(to.width) as usize];
+                bgra_row_to_uyvy(&k, src_row, dst_row, width);
+            }
+            true
+        }
+        (Pixel(PixelFormat::NV12), Pixel(PixelFormat::BGRA)) => {
+            let k = FixedCoefficients::new(&from.colorimetry);
+            let chroma = &src[src_stride * height..];
+            for y in 0..height {
+                let luma_row = &src[y * src_stride..][..width];
+                let chroma_row = &chroma[(y / 2) * src_stride..][..width.div_ceil(2) * 2];
+                let dst_row = &mut out[y * dst_stride..][..width * 4];
+                nv12_row_to_bgra(&k, luma_row, chroma_row, dst_row, width);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+fn uyvy_row_to_bgra(k: &FixedCoefficients, src: &[u8], dst: &mut [u8], width: usize) {
+    let pairs = width / 2;
+    for (s, d) in src.chunks_exact(4).zip(dst.chunks_exact_mut(8)).take(pairs) {
+        let (u, v) = (s[0] as i32 - 128, s[2] as i32 - 128);
+        k.yuv_to_bgra(s[1], u, v, &mut d[0..4]);
+        k.yuv_to_bgra(s[3], u, v, &mut d[4..8]);
+    }
+    if width % 2 == 1 {
+        let s = &src[pairs * 4..pairs * 4 + 4];
+        k.yuv_to_bgra(s[1], s[0] as i32 - 128, s[2] as i32 - 128, &mut dst[pairs * 8..pairs * 8 + 4]);
+    }
+}
+
+fn bgra_row_to_uyvy(k: &FixedCoefficients, src: &[u8], dst: &mut [u8], width: usize) {
+    let pairs = width / 2;
+    for (s, d) in src.chunks_exact(8).zip(dst.chunks_exact_mut(4)).take(pairs) {
+        let (b0, g0, r0) = (s[0] as i32, s[1] as i32, s[2] as i32);
+        let (b1, g1, r1) = (s[4] as i32, s[5] as i32, s[6] as i32);
+        // Chroma from the average of both pixels
+        let (u, v) = k.chroma((r0 + r1 + 1) >> 1, (g0 + g1 + 1) >> 1, (b0 + b1 + 1) >> 1);
+        d[0] = u;
+        d[1] = k.luma(r0, g0, b0);
+        d[2] = v;
+        d[3] = k.luma(r1, g1, b1);
+    }
+    if width % 2 == 1 {
+        let s = &src[pairs * 8..pairs * 8 + 4];
+        let (b, g, r) = (s[0] as i32, s[1] as i32, s[2] as i32);
+        let (u, v) = k.chroma(r, g, b);
+        let y = k.luma(r, g, b);
+        dst[pairs * 4..pairs * 4 + 4].copy_from_slice(&[u, y, v, y]);
+    }
+}
+
+fn nv12_row_to_bgra(k: &FixedCoefficients, luma: &[u8], chroma: &[u8], dst: &mut [u8], width: usize) {
+    let pairs = width / 2;
+    for ((l, c), d) in luma.chunks_exact(2).zip(chroma.chunks_exact(2)).zip(dst.chunks_exact_mut(8)).take(pairs) {
+        let (u, v) = (c[0] as i32 - 128, c[1] as i32 - 128);
+        k.yuv_to_bgra(l[0], u, v, &mut d[0..4]);
+        k.yuv_to_bgra(l[1], u, v, &mut d[4..8]);
+    }
+    if width % 2 == 1 {
+        let c = &chroma[pairs * 2..pairs * 2 + 2];
+        k.yuv_to_bgra(luma[pairs * 2], c[0] as i32 - 128, c[1] as i32 - 128, &mut dst[pairs * 8..pairs * 8 + 4]);
+    }
+}
+
+// Copies every plane line by line, for conversions that only change the stride
+fn copy_planes(src: &[u8], from: &ImageLayout, to: &ImageLayout, out: &mut [u8]) {
+    let src_planes = planes(from);
+    let dst_planes = planes(to);
+    for (src_plane, dst_plane) in src_planes.iter().zip(&dst_planes) {
+        for y in 0..src_plane.lines {
+            let src_row = &src[src_plane.offset + y * src_plane.stride..][..src_plane.line_bytes];
+            out[dst_plane.offset + y * dst_plane.stride..][..src_plane.line_bytes].copy_from_slice(src_row);
+        }
+    }
+}
+
+struct Plane {
+    offset: usize,
+    stride: usize,
+    lines: usize,
+    line_bytes: usize, // Bytes of actual samples per line, without padding
+}
+
+// Plane layout of an image, matching PixelFormat::frame_size
+fn planes(layout: &ImageLayout) -> Vec<Plane> {
+    let h = layout.height as usize;
+    let w = layout.width as usize;
+    let stride = layout.stride as usize;
+    let chroma_lines = layout.height.div_ceil(2) as usize;
+    let chroma_pairs = layout.width.div_ceil(2) as usize;
+    let first = Plane {
+        offset: 0,
+        stride,
+        lines: h,
+        line_bytes: layout.format.default_stride(layout.width) as usize,
+    };
+
+    match layout.format {
+        ImageFormat::Pixel(PixelFormat::UYVA) => vec![
+            first,
+            Plane { offset: stride * h, stride: stride / 2, lines: h, line_bytes: w },
+        ],
+        ImageFormat::Pixel(PixelFormat::NV12) => vec![
+            first,
+            Plane { offset: stride * h, stride, lines: chroma_lines, line_bytes: chroma_pairs * 2 },
+        ],
+        ImageFormat::Pixel(PixelFormat::YV12) => {
+            let chroma_stride = stride / 2;
+            vec![
+                first,
+                Plane { offset: stride * h, stride: chroma_stride, lines: chroma_lines, line_bytes: chroma_pairs },
+                Plane {
+                    offset: stride * h + chroma_stride * chroma_lines,
+                    stride: chroma_stride,
+                    lines: chroma_lines,
+                    line_bytes: chroma_pairs,
+                },
+            ]
+        }
+        ImageFormat::Pixel(PixelFormat::P216) => vec![
+            first,
+            Plane { offset: stride * h, stride, lines: h, line_bytes: chroma_pairs * 4 },
+        ],
+        ImageFormat::Pixel(PixelFormat::PA16) => vec![
+            first,
+            Plane { offset: stride * h, stride, lines: h, line_bytes: chroma_pairs * 4 },
+            Plane { offset: 2 * stride * h, stride, lines: h, line_bytes: w * 2 },
+        ],
+        _ => vec![first],
+    }
+}
+
+// --- Generic path ----------------------------------------------------------
+
+// Up to two lines of 4:4:4 samples. c0..c2 hold Y/U/V or R/G/B depending on `yuv`.
+struct Strip {
+    width: usize,
+    yuv: bool,
+    c: [Vec<u16>; 4],
+}
+
+impl Strip {
+    fn new(width: usize) -> Self {
+        Self {
+            width,
+            yuv: false,
+            c: std::array::from_fn(|_| vec![0; width * 2]),
+        }
+    }
+
+    #[inline]
+    fn set(&mut self, row: usize, x: usize, px: [u16; 4]) {
+        let i = row * self.width + x;
+        for (channel, value) in self.c.iter_mut().zip(px) {
+            channel[i] = value;
+        }
+    }
+
+    #[inline]
+    fn get(&self, row: usize, x: usize) -> [u16; 4] {
+        let i = row * self.width + x;
+        [self.c[0][i], self.c[1][i], self.c[2][i], self.c[3][i]]
+    }
+
+    // Average of a channel over a 2x2 block for 4:2:0 chroma, clipped to what exists
+    fn average(&self, channel: usize, x: usize, rows: usize) -> u16 {
+        let mut sum = 0u32;
+        let mut count = 0u32;
+        for row in 0..rows {
+            for dx in 0..2 {
+                if x + dx < self.width {
+                    sum += self.c[channel][row * self.width + x + dx] as u32;
+                    count += 1;
+                }
+            }
+        }
+        ((sum + count / 2) / count.max(1)) as u16
+    }
+
+    fn convert_to_rgb(&mut self, k: &YuvCoefficients, rows: usize) {
+        for i in 0..rows * self.width {
+            let rgb = k.yuv_to_rgb(self.c[0][i], self.c[1][i], self.c[2][i]);
+            self.c[0][i] = rgb[0];
+            self.c[1][i] = rgb[1];
+            self.c[2][i] = rgb[2];
+        }
+        self.yuv = false;
+    }
+
+    fn convert_to_yuv(&mut self, k: &YuvCoefficients, rows: usize) {
+        for i in 0..rows * self.width {
+            let yuv = k.rgb_to_yuv(self.c[0][i], self.c[1][i], self.c[2][i]);
+            self.c[0][i] = yuv[0];
+            self.c[1][i] = yuv[1];
+            self.c[2][i] = yuv[2];
+        }
+        self.yuv = true;
+    }
+}
+
+fn convert_generic(src: &[u8], from: &ImageLayout, to: &ImageLayout, out: &mut [u8]) {
+    let width = from.width as usize;
+    let height = from.height as usize;
+    let from_k = YuvCoefficients::new(&from.colorimetry);
+    let to_k = YuvCoefficients::new(&to.colorimetry);
+    let recode_yuv = from.format.is_yuv() && to.format.is_yuv() && !(
+        from.colorimetry.matrix == to.colorimetry.matrix && from.colorimetry.range == to.colorimetry.range
+    );
+
+    let mut strip = Strip::new(width);
+    for y0 in (0..height).step_by(2) {
+        let rows = (height - y0).min(2);
+        unpack(src, from, y0, rows, &mut strip);
+
+        if strip.yuv && (!to.format.is_yuv() || recode_yuv) {
+            strip.convert_to_rgb(&from_k, rows);
+        }
+        if !strip.yuv && to.format.is_yuv() {
+            strip.convert_to_yuv(&to_k, rows);
+        }
+
+        pack(&strip, to, y0, rows, out);
+    }
+}
+
+#[inline]
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[inline]
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn unpack(src: &[u8], layout: &ImageLayout, y0: usize, rows: usize, strip: &mut Strip) {
+    let width = layout.width as usize;
+    let height = layout.height as usize;
+    let stride = layout.stride as usize;
+    strip.yuv = layout.format.is_yuv();
+
+    for row in 0..rows {
+        let y = y0 + row;
+        let line = &src[y * stride..];
+        match layout.format {
+            ImageFormat::Pixel(PixelFormat::BGRA) => {
+                for x in 0..width {
+                    let p = &line[x * 4..x * 4 + 4];
+                    strip.set(row, x, [expand(p[2]), expand(p[1]), expand(p[0]), expand(p[3])]);
+                }
+            }
+            ImageFormat::Rgba => {
+                for x in 0..width {
+                    let p = &line[x * 4..x * 4 + 4];
+                    strip.set(row, x, [expand(p[0]), expand(p[1]), expand(p[2]), expand(p[3])]);
+                }
+            }
+            ImageFormat::Rgb24 => {
+                for x in 0..width {
+                    let p = &line[x * 3..x * 3 + 3];
+                    strip.set(row, x, [expand(p[0]), expand(p[1]), expand(p[2]), u16::MAX]);
+                }
+            }
+            ImageFormat::Pixel(format @ (PixelFormat::UYVY | PixelFormat::UYVA)) => {
+                let alpha_offset = stride * height + y * (stride / 2);
+                for x in 0..width {
+                    let mp = &line[(x / 2) * 4..(x / 2) * 4 + 4];
+                    let a = if format == PixelFormat::UYVA { expand(src[alpha_offset + x]) } else { u16::MAX };
+                    strip.set(row, x, [expand(mp[1 + 2 * (x & 1)]), expand(mp[0]), expand(mp[2]), a]);
+                }
+            }
+            ImageFormat::Pixel(PixelFormat::NV12) => {
+                let chroma = &src[stride * height + (y / 2) * stride..];
+                for (x, &luma) in line[..width].iter().enumerate() {
+                    let c = (x / 2) * 2;
+                    strip.set(row, x, [expand(luma), expand(chroma[c]), expand(chroma[c + 1]), u16::MAX]);
+                }
+            }
+            ImageFormat::Pixel(PixelFormat::YV12) => {
+                let chroma_stride = stride / 2;
+                let chroma_lines = height.div_ceil(2);
+                let v_plane = &src[stride * height + (y / 2) * chroma_stride..];
+                let u_plane = &src[stride * height + chroma_stride * chroma_lines + (y / 2) * chroma_stride..];
+                for (x, &luma) in line[..width].iter().enumerate() {
+                    strip.set(row, x, [expand(luma), expand(u_plane[x / 2]), expand(v_plane[x / 2]), u16::MAX]);
+                }
+            }
+            ImageFormat::Pixel(format @ (PixelFormat::P216 | PixelFormat::PA16)) => {
+                let chroma = &src[stride * height + y * stride..];
+                let alpha_offset = 2 * stride * height + y * stride;
+                for x in 0..width {
+                    let c = (x / 2) * 4;
+                    let a = if format == PixelFormat::PA16 { read_u16(src, alpha_offset + x * 2) } else { u16::MAX };
+                    strip.set(row, x, [read_u16(line, x * 2), read_u16(chroma, c), read_u16(chroma, c + 2), a]);
+                }
+            }
+        }
+    }
+}
+
+fn pack(strip: &Strip, layout: &ImageLayout, y0: usize, rows: usize, out: &mut [u8]) {
+    let width = layout.width as usize;
+    let height = layout.height as usize;
+    let stride = layout.stride as usize;
+
+    for row in 0..rows {
+        let y = y0 + row;
+        match layout.format {
+            ImageFormat::Pixel(PixelFormat::BGRA) => {
+                let line = &mut out[y * stride..];
+                for x in 0..width {
+                    let [r, g, b, a] = strip.get(row, x);
+                    line[x * 4..x * 4 + 4].copy_from_slice(&[narrow(b), narrow(g), narrow(r), narrow(a)]);
+                }
+            }
+            ImageFormat::Rgba => {
+                let line = &mut out[y * stride..];
+                for x in 0..width {
+                    let [r, g, b, a] = strip.get(row, x);
+                    line[x * 4..x * 4 + 4].copy_from_slice(&[narrow(r), narrow(g), narrow(b), narrow(a)]);
+                }
+            }
+            ImageFormat::Rgb24 => {
+                let line = &mut out[y * stride..];
+                for x in 0..width {
+                    let [r, g, b, _] = strip.get(row, x);
+                    line[x * 3..x * 3 + 3].copy_from_slice(&[narrow(r), narrow(g), narrow(b)]);
+                }
+            }
+            ImageFormat::Pixel(format @ (PixelFormat::UYVY | PixelFormat::UYVA)) => {
+                for x in (0..width).step_by(2) {
+                    let x1 = if x + 1 < width { x + 1 } else { x };
+                    let mp = [
+                        narrow(average_row(strip, 1, row, x)),
+                        narrow(strip.get(row, x)[0]),
+                        narrow(average_row(strip, 2, row, x)),
+                        narrow(strip.get(row, x1)[0]),
+                    ];
+                    out[y * stride + (x / 2) * 4..][..4].copy_from_slice(&mp);
+                }
+                if format == PixelFormat::UYVA {
+                    let alpha = &mut out[stride * height + y * (stride / 2)..][..width];
+                    for (x, a) in alpha.iter_mut().enumerate() {
+                        *a = narrow(strip.get(row, x)[3]);
+                    }
+                }
+            }
+            ImageFormat::Pixel(PixelFormat::NV12) => {
+                for (x, luma) in out[y * stride..][..width].iter_mut().enumerate() {
+                    *luma = narrow(strip.get(row, x)[0]);
+                }
+                // Chroma is written once per pair of lines, from both of them
+                if row == 0 {
+                    let chroma = &mut out[stride * height + (y / 2) * stride..];
+                    for x in (0..width).step_by(2) {
+                        chroma[x] = narrow(strip.average(1, x, rows));
+                        chroma[x + 1] = narrow(strip.average(2, x, rows));
+                    }
+                }
+            }
+            ImageFormat::Pixel(PixelFormat::YV12) => {
+                for (x, luma) in out[y * stride..][..width].iter_mut().enumerate() {
+                    *luma = narrow(strip.get(row, x)[0]);
+                }
+                if row == 0 {
+                    let chroma_stride = stride / 2;
+                    let chroma_lines = height.div_ceil(2);
+                    let v_offset = stride * height + (y / 2) * chroma_stride;
+                    let u_offset = stride * height + chroma_stride * chroma_lines + (y / 2) * chroma_stride;
+                    for x in (0..width).step_by(2) {
+                        out[v_offset + x / 2] = narrow(strip.average(2, x, rows));
+                        out[u_offset + x / 2] = narrow(strip.average(1, x, rows));
+                    }
+                }
+            }
+            ImageFormat::Pixel(format @ (PixelFormat::P216 | PixelFormat::PA16)) => {
+                for x in 0..width {
+                    let [luma, _, _, a] = strip.get(row, x);
+                    write_u16(out, y * stride + x * 2, luma);
+                    if format == PixelFormat::PA16 {
+                        write_u16(out, 2 * stride * height + y * stride + x * 2, a);
+                    }
+                }
+                let chroma = stride * height + y * stride;
+                for x in (0..width).step_by(2) {
+                    write_u16(out, chroma + (x / 2) * 4, average_row(strip, 1, row, x));
+                    write_u16(out, chroma + (x / 2) * 4 + 2, average_row(strip, 2, row, x));
+                }
+            }
+        }
+    }
+}
+
+// Horizontal average of a pixel pair on one row, for 4:2:2 chroma
+fn average_row(strip: &Strip, channel: usize, row: usize, x: usize) -> u16 {
+    let a = strip.c[channel][row * strip.width + x] as u32;
+    let b = if x + 1 < strip.width { strip.c[channel][row * strip.width + x + 1] as u32 } else { a };
+    (a + b).div_ceil(2) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Colour bars with every pair of pixels the same, so 4:2:2 loses nothing
+    fn bgra_bars(width: u32, height: u32) -> Vec<u8> {
+        const BARS: [[u8; 3]; 8] = [
+            [235, 235, 235], [16, 235, 235], [235, 235, 16], [16, 235, 16],
+            [235, 16, 235], [16, 16, 235], [235, 16, 16], [16, 16, 16],
+        ];
+        let mut data = Vec::new();
+        for _ in 0..height {
+            for x in 0..width {
+                let [b, g, r] = BARS[(x / 2) as usize % BARS.len()];
+                data.extend_from_slice(&[b, g, r, 255]);
+            }
+        }
+        data
+    }
+
+    fn converted(src: &[u8], from: &ImageLayout, to: &ImageLayout) -> BytesMut {
+        let mut dst = BytesMut::new();
+        convert(src, from, to, &mut dst).unwrap();
+        dst
+    }
+
+    fn assert_close(a: &[u8], b: &[u8], tolerance: u8) {
+        assert_eq!(a.len(), b.len());
+        for (i, (x, y)) in a.iter().zip(b).enumerate() {
+            assert!(x.abs_diff(*y) <= tolerance, "byte {}: {} vs {}", i, x, y);
+        }
+    }
+
+    #[test]
+    fn bgra_uyvy_round_trip() {
+        let bgra = ImageLayout::new(PixelFormat::BGRA, 16, 4);
+        let uyvy = ImageLayout::new(PixelFormat::UYVY, 16, 4);
+        let src = bgra_bars(16, 4);
+        let back = converted(&converted(&src, &bgra, &uyvy), &uyvy, &bgra);
+        assert_close(&back, &src, 2);
+    }
+
+    #[test]
+    fn padded_stride_source() {
+        let tight = ImageLayout::new(PixelFormat::BGRA, 16, 4);
+        let padded = ImageLayout { stride: tight.stride + 16, ..tight };
+        let src = bgra_bars(16, 4);
+        // Padding filled with garbage that must not leak into the output
+        let mut padded_src = Vec::new();
+        for row in src.chunks(tight.stride as usize) {
+            padded_src.extend_from_slice(row);
+            padded_src.extend_from_slice(&[0xaa; 16]);
+        }
+
+        for format in [PixelFormat::UYVY, PixelFormat::NV12, PixelFormat::P216] {
+            let to = ImageLayout::new(format, 16, 4);
+            assert_eq!(converted(&padded_src, &padded, &to), converted(&src, &tight, &to), "{:?}", format);
+        }
+        assert_eq!(&converted(&padded_src, &padded, &tight)[..], &src[..]);
+    }
+
+    #[test]
+    fn bt601_and_bt709_reference_values() {
+        // Two pure red pixels, limited range
+        let red = [0, 0, 255, 255, 0, 0, 255, 255];
+        let bgra = ImageLayout::new(PixelFormat::BGRA, 2, 1);
+        let uyvy = ImageLayout::new(PixelFormat::UYVY, 2, 1);
+
+        // BT.601: Y = 16 + 219 * 0.299, Cb = 128 - 224 * 0.299 / 1.772, Cr = 240
+        let bt601 = ImageLayout { colorimetry: Colorimetry::BT601, ..uyvy };
+        assert_close(&converted(&red, &bgra, &bt601), &[90, 81, 240, 81], 1);
+        // BT.709: Y = 16 + 219 * 0.2126, Cb = 128 - 224 * 0.2126 / 1.8556, Cr = 240
+        let bt709 = ImageLayout { colorimetry: Colorimetry::BT709, ..uyvy };
+        assert_close(&converted(&red, &bgra, &bt709), &[102, 63, 240, 63], 1);
+
+        // And back to red from either
+        let bgra601 = ImageLayout { colorimetry: Colorimetry::BT601, ..bgra };
+        assert_close(&converted(&[90, 81, 240, 81], &bt601, &bgra601), &red, 2);
+        let bgra709 = ImageLayout { colorimetry: Colorimetry::BT709, ..bgra };
+        assert_close(&converted(&[102, 63, 240, 63], &bt709, &bgra709), &red, 2);
+    }
+
+    #[test]
+    fn fast_paths_match_generic_path() {
+        let (width, height) = (16, 4);
+        let bgra = ImageLayout::new(PixelFormat::BGRA, width, height);
+        let src_bgra = bgra_bars(width, height);
+        let nv12 = ImageLayout::new(PixelFormat::NV12, width, height);
+        let mut src_nv12 = vec![0; nv12.frame_size()];
+        convert_generic(&src_bgra, &bgra, &nv12, &mut src_nv12);
+        let uyvy = ImageLayout::new(PixelFormat::UYVY, width, height);
+        let mut src_uyvy = vec![0; uyvy.frame_size()];
+        convert_generic(&src_bgra, &bgra, &uyvy, &mut src_uyvy);
+
+        let rgba = ImageLayout::new(ImageFormat::Rgba, width, height);
+        let cases: [(&[u8], &ImageLayout, &ImageLayout); 5] = [
+            (&src_bgra, &bgra, &uyvy),
+            (&src_uyvy, &uyvy, &bgra),
+            (&src_nv12, &nv12, &bgra),
+            (&src_bgra, &bgra, &rgba),
+            (&src_bgra, &rgba, &bgra),
+        ];
+        for (src, from, to) in cases {
+            let mut fast = vec![0; to.frame_size()];
+            let mut generic = vec![0; to.frame_size()];
+            assert!(convert_fast(src, from, to, &mut fast), "{:?} -> {:?}", from.format, to.format);
+            convert_generic(src, from, to, &mut generic);
+            assert_close(&fast, &generic, 1);
+        }
+    }
+
+    #[test]
+    fn huge_widths_are_rejected() {
+        assert_eq!(ImageFormat::Rgba.checked_stride(0x4000_0000), None);
+        assert_eq!(ImageFormat::Rgb24.checked_stride(0x6000_0000), None);
+        assert_eq!(ImageFormat::Rgba.default_stride(u32::MAX), u32::MAX);
+
+        let from = ImageLayout::new(ImageFormat::Rgba, 0x4000_0000, 1);
+        let to = ImageLayout::new(PixelFormat::BGRA, 0x4000_0000, 1);
+        assert!(convert(&[0; 64], &from, &to, &mut BytesMut::new()).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use bytes::{BufMut, BytesMut};
+use crate::codec::{EncoderStats, Lz4Codec, VideoDecoder, VideoEncoder};
+use crate::error::{Result, AqueductError};
+use crate::protocol::{VideoFrame, PixelFormat};
+
+// Inter-frame delta codec for screen content.
+//
+// Keyframes are plain LZ4 frames. Every other frame is XORed with the previous one
+// before LZ4 compression: unchanged pixels turn into runs of zeros that compress to
+// almost nothing, so a mostly static desktop costs a fraction of the bandwidth.
+//
+// Payload:
+//
+//   [Kind: u8][Reserved: u8][Reserved: u16][Sequence: u32] then an LZ4 payload
+//
+// Sequence increments with every frame. A decoder that missed a frame, or joined
+// after the last keyframe, has no valid reference and fails with
+// `AqueductError::KeyframeRequired` until the next keyframe arrives. Receivers
+// answer that by asking the sender for a refresh.
+
+const KIND_KEYFRAME: u8 = 0;
+const KIND_DELTA: u8 = 1;
+const HEADER_LEN: usize = 8;
+
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 300;
+
+// What a reference frame must match for a delta against it to make sense
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Shape {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    stride: u32,
+    len: usize,
+}
+
+impl Shape {
+    fn of(frame: &VideoFrame, len: usize) -> Self {
+        Self {
+            width: frame.width,
+            height: frame.height,
+            format: frame.format,
+            stride: frame.stride,
+            len,
+        }
+    }
+}
+
+pub struct DeltaCodec {
+    keyframe_interval: u32,
+    lz4: Lz4Codec,
+    reference: Vec<u8>,
+    shape: Option<Shape>,
+    sequence: u32,
+    // Frames since the last keyframe
+    since_keyframe: u32,
+    keyframe_requested: bool,
+    // The last encoded frame was a keyframe
+    keyframe: bool,
+    residual: BytesMut,
+}
+
+impl Default for DeltaCodec {
+    fn default() -> Self {
+        Self::with_keyframe_interval(DEFAULT_KEYFRAME_INTERVAL)
+    }
+}
+
+impl DeltaCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Frames between keyframes, including the keyframe. 1 makes every frame a keyframe.
+    pub fn with_keyframe_interval(keyframe_interval: u32) -> Self {
+        Self {
+            keyframe_interval: keyframe_interval.max(1),
+            lz4: Lz4Codec::new(),
+            reference: Vec::new(),
+            shape: None,
+            sequence: 0,
+            since_keyframe: 0,
+            keyframe_requested: false,
+            keyframe: false,
+            residual: BytesMut::new(),
+        }
+    }
+}
+
+impl VideoEncoder for DeltaCodec {
+    fn encode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        let shape = Shape::of(frame, frame.data.len());
+        let keyframe = self.keyframe_requested
+            || self.shape != Some(shape)
+            || self.since_keyframe >= self.keyframe_interval;
+        self.keyframe = keyframe;
+
+        self.sequence = self.sequence.wrapping_add(1);
+        dst.put_u8(if keyframe { KIND_KEYFRAME } else { KIND_DELTA });
+        dst.put_u8(0);
+        dst.put_u16_le(0);
+        dst.put_u32_le(self.sequence);
+
+        if keyframe {
+            self.lz4.encode_into(frame, dst)?;
+            self.reference.clear();
+            self.reference.extend_from_slice(&frame.data);
+            self.shape = Some(shape);
+            self.since_keyframe = 1;
+            self.keyframe_requested = false;
+        } else {
+            // XOR against the reference, which then becomes this frame
+            self.residual.clear();
+            self.residual.reserve(frame.data.len());
+            for (reference, &current) in self.reference.iter_mut().zip(frame.data.iter()) {
+                self.residual.put_u8(*reference ^ current);
+                *reference = current;
+            }
+            let residual = VideoFrame {
+                data: self.residual.split().freeze(),
+                ..frame.clone()
+            };
+            self.lz4.encode_into(&residual, dst)?;
+            self.since_keyframe += 1;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<EncoderStats> {
+        self.lz4.stats()
+    }
+
+    fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    fn is_keyframe(&self) -> bool {
+        self.keyframe
+    }
+}
+
+impl VideoDecoder for DeltaCodec {
+    fn decode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        let data = &frame.data;
+        if data.len() < HEADER_LEN {
+            return Err(AqueductError::Codec("Delta payload too short".to_string()));
+        }
+        let kind = data[0];
+        let sequence = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let payload = VideoFrame {
+            data: data.slice(HEADER_LEN..),
+            ..frame.clone()
+        };
+
+        match kind {
+            KIND_KEYFRAME => {
+                self.reference.clear();
+                self.residual.clear();
+                self.lz4.decode_into(&payload, &mut self.residual)?;
+                self.reference.extend_from_slice(&self.residual);
+                self.shape = Some(Shape::of(frame, self.reference.len()));
+            }
+            KIND_DELTA => {
+                let chained = self.sequence.wrapping_add(1) == sequence
+                    && self.shape.is_some_and(|shape| shape == Shape::of(frame, shape.len));
+                if !chained {
+                    // Stay broken until a keyframe, later deltas don't chain either
+                    self.shape = None;
+                    return Err(AqueductError::KeyframeRequired);
+                }
+                self.residual.clear();
+                self.lz4.decode_into(&payload, &mut self.residual)?;
+                if self.residual.len() != self.reference.len() {
+                    self.shape = None;
+                    return Err(AqueductError::Codec("Delta size doesn't match its reference".to_string()));
+                }
+                for (reference, residual) in self.reference.iter_mut().zip(self.residual.iter()) {
+                    *reference ^= residual;
+                }
+            }
+            other => return Err(AqueductError::Codec(format!("Unknown delta frame kind: {}", other))),
+        }
+
+        self.sequence = sequence;
+        dst.extend_from_slice(&self.reference);
+        Ok(())
+    }
+}
@@ -11,11 +11,28 @@ pub enum AqueductError {
     #[error("Protocol Error: {0}")]
     Protocol(String),
 
+    #[error("Handshake Error: {0}")]
+    Handshake(String),
+
+    #[error("Conversion Error: {0}")]
+    Conversion(String),
+
+    #[error("Codec Error: {0}")]
+    Codec(String),
+
+    // An inter-frame decoder lost its reference and has to wait for a keyframe
+    #[error("Keyframe required")]
+    KeyframeRequired,
+
     #[error("Discovery Error: {0}")]
     Discovery(String),
 
     #[error("Invalid Configuration: {0}")]
     Config(String),
+
+    // A bounded queue had no room and the caller asked not to wait
+    #[error("Queue full")]
+    QueueFull,
 }
 
 pub type Result<T> = std::result::Result<T, AqueductError>;
@@ -0,0 +1,709 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::time::Duration;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::error::{Result, AqueductError};
+use crate::codec::CodecId;
+use crate::protocol::{
+    Packet, VideoFrame, AudioFrame, MetadataFrame, FrameFlags, FrameRate, PixelFormat, SampleFormat,
+    ChannelLayout,
+};
+use crate::wire::{
+    FrameHeader, FrameType, VideoHeader, VideoExtension, AudioHeader, ChunkHeader, FRAME_HEADER_LEN,
+    VIDEO_HEADER_LEN, AUDIO_HEADER_LEN, CHUNK_HEADER_LEN, MAX_FRAME_LEN, VIDEO_FLAG_EXTENSION, FOURCC_FPA1, FOURCC_PCM,
+    active_channel_mask, audio_format, audio_format_from_field, color_space,
+    colorimetry_from_color_space, interleaved_to_planar, planar_to_interleaved,
+};
+
+/// Sans-IO OMT framing.
+///
+/// Turns `Packet`s into OMT frames and back without touching any socket, so it can
+/// be driven by `tokio_util::codec::Framed` over TCP, Unix sockets, in-memory pipes
+/// or files. Video payloads pass through untouched: compression happens before
+/// encoding (`Sender::send`) and after decoding (`Receiver::receive`), with
+/// `VideoFrame::codec` saying which codec the payload uses. The same goes for
+/// compressed audio. Uncompressed float audio goes out as OMT's planar FPA1 and
+/// is transposed back if the sender's frame was interleaved, integer samples are
+/// sent as they are. Chunked frames are reassembled by `decode`, the sending
+/// side splits frames with `encode_chunk_header`.
+///
+/// `OmtCodec::plain` writes only what any OMT peer understands and refuses
+/// packets that need Aqueduct's extensions to be read correctly.
+#[derive(Debug, Default)]
+pub struct OmtCodec {
+    // Frame being put back together from chunks
+    reassembly: Option<Reassembly>,
+    plain: bool,
+}
+
+#[derive(Debug)]
+struct Reassembly {
+    sequence: u32,
+    total_len: usize,
+    frame: BytesMut,
+}
+
+impl OmtCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // For peers that never sent a hello. Pixel aspect ratio and colorimetry beyond
+    // the OMT color space are left out, compressed frames, padded rows and integer
+    // audio are errors.
+    pub fn plain() -> Self {
+        Self {
+            plain: true,
+            ..Self::default()
+        }
+    }
+
+    // Adds a chunk to the frame being reassembled, the frame once it is complete
+    fn reassemble(&mut self, payload: &[u8]) -> Result<Option<Packet>> {
+        let chunk = ChunkHeader::read(payload)?;
+        let total_len = chunk.total_len as usize;
+        if chunk.offset == 0 {
+            if !(FRAME_HEADER_LEN..=FRAME_HEADER_LEN + MAX_FRAME_LEN).contains(&total_len) {
+                return Err(AqueductError::Protocol(format!("Invalid chunked frame length: {}", total_len)));
+            }
+            self.reassembly = Some(Reassembly {
+                sequence: chunk.sequence,
+                total_len,
+                frame: BytesMut::with_capacity(total_len),
+            });
+        }
+
+        // Every chunk must continue the frame in progress, the length it was started with included
+        let piece = &payload[CHUNK_HEADER_LEN..];
+        let reassembly = match self.reassembly.as_mut() {
+            Some(reassembly)
+                if reassembly.sequence == chunk.sequence
+                    && reassembly.total_len == total_len
+                    && reassembly.frame.len() == chunk.offset as usize
+                    && piece.len() <= total_len - reassembly.frame.len() => reassembly,
+            _ => {
+                self.reassembly = None;
+                return Err(AqueductError::Protocol(format!(
+                    "Chunk at {} of frame {} ({} bytes) doesn't continue the frame in progress",
+                    chunk.offset, chunk.sequence, total_len
+                )));
+            }
+        };
+        reassembly.frame.extend_from_slice(piece);
+        if reassembly.frame.len() < total_len {
+            return Ok(None);
+        }
+
+        let mut frame = self.reassembly.take().map(|reassembly| reassembly.frame).unwrap_or_default();
+        let header = FrameHeader::read(&frame)?;
+        if frame.len() != FRAME_HEADER_LEN + header.data_len as usize {
+            return Err(AqueductError::Protocol("Chunks don't add up to their frame".to_string()));
+        }
+        frame.advance(FRAME_HEADER_LEN);
+        decode_frame(&header, frame.freeze()).map(Some)
+    }
+}
+
+// Frame and chunk headers for the `len` bytes at `offset` of an encoded frame of
+// `total_len` bytes. The piece itself follows them on the wire.
+pub fn encode_chunk_header(timestamp: Duration, sequence: u32, offset: usize, len: usize, total_len: usize, dst: &mut BytesMut) {
+    FrameHeader::new(FrameType::Chunk, timestamp, CHUNK_HEADER_LEN + len).write(dst);
+    ChunkHeader {
+        sequence,
+        offset: offset as u32,
+        total_len: total_len as u32,
+    }.write(dst);
+}
+
+impl Encoder<&Packet> for OmtCodec {
+    type Error = AqueductError;
+
+    fn encode(&mut self, packet: &Packet, dst: &mut BytesMut) -> Result<()> {
+        match packet {
+            Packet::Video(frame) => {
+                if self.plain && (frame.is_compressed() || frame.stride != frame.format.default_stride(frame.width)) {
+                    return Err(AqueductError::Protocol(format!(
+                        "Plain OMT peers can't read {} video with stride {}", frame.codec, frame.stride
+                    )));
+                }
+                let extension = VideoExtension {
+                    stride: frame.stride,
+                    pixel_aspect_ratio: frame.pixel_aspect_ratio,
+                    colorimetry: frame.colorimetry,
+                    pixel_format: if frame.is_compressed() { frame.format.fourcc() } else { 0 },
+                };
+                let color_space = color_space(frame.colorimetry.matrix);
+                let needs_extension = !self.plain && (frame.is_compressed()
+                    || frame.stride != frame.format.default_stride(frame.width)
+                    || frame.pixel_aspect_ratio != 1.0
+                    || frame.colorimetry != colorimetry_from_color_space(color_space, frame.height));
+                let extension_len = if needs_extension { extension.encoded_len() } else { 0 };
+                let mut flags = frame.flags.to_bits();
+                if needs_extension {
+                    flags |= VIDEO_FLAG_EXTENSION;
+                }
+
+                let data_len = VIDEO_HEADER_LEN + extension_len + frame.data.len();
+                dst.reserve(FRAME_HEADER_LEN + data_len);
+                FrameHeader::new(FrameType::Video, frame.timestamp, data_len).write(dst);
+                VideoHeader {
+                    codec: if frame.is_compressed() { frame.codec.0 } else { frame.format.fourcc() },
+                    width: frame.width,
+                    height: frame.height,
+                    frame_rate_n: frame.frame_rate.numerator,
+                    frame_rate_d: frame.frame_rate.denominator,
+                    aspect_ratio: frame.display_aspect_ratio(),
+                    flags,
+                    color_space,
+                }.write(dst);
+                if needs_extension {
+                    extension.write(dst);
+                }
+                dst.put_slice(&frame.data);
+            }
+            Packet::Audio(frame) if self.plain && (frame.is_compressed() || frame.format != SampleFormat::F32) => {
+                return Err(AqueductError::Protocol(format!(
+                    "Plain OMT peers can't read {} {:?} audio", frame.codec, frame.format
+                )));
+            }
+            Packet::Audio(frame) if frame.is_compressed() => {
+                // Codecs carry the sample count in their payload
+                let data_len = frame.data.len();
+                dst.reserve(FRAME_HEADER_LEN + AUDIO_HEADER_LEN + data_len);
+                FrameHeader::new(FrameType::Audio, frame.timestamp, AUDIO_HEADER_LEN + data_len)
+                    .write(dst);
+                AudioHeader {
+                    codec: frame.codec.0,
+                    sample_rate: frame.sample_rate,
+                    samples_per_channel: 0,
+                    channels: frame.channels,
+                    active_channels: active_channel_mask(frame.channels),
+                    format: audio_format(frame.format, frame.channel_layout),
+                }.write(dst);
+                dst.put_slice(&frame.data);
+            }
+            Packet::Audio(frame) => {
+                let channels = frame.channels.max(1) as usize;
+                let samples_per_channel = frame.samples_per_channel();
+                let data_len = samples_per_channel * channels * frame.format.bytes_per_sample();
+                // Float goes out as OMT's planar FPA1, integer samples as they are
+                let float = frame.format == SampleFormat::F32;
+
+                dst.reserve(FRAME_HEADER_LEN + AUDIO_HEADER_LEN + data_len);
+                FrameHeader::new(FrameType::Audio, frame.timestamp, AUDIO_HEADER_LEN + data_len)
+                    .write(dst);
+                AudioHeader {
+                    codec: if float { FOURCC_FPA1 } else { FOURCC_PCM },
+                    sample_rate: frame.sample_rate,
+                    samples_per_channel: samples_per_channel as u32,
+                    channels: frame.channels,
+                    active_channels: active_channel_mask(frame.channels),
+                    // The field is reserved in OMT, and FPA1 is planar on the wire anyway
+                    format: if self.plain { 0 } else { audio_format(frame.format, frame.channel_layout) },
+                }.write(dst);
+                if float && frame.channel_layout == ChannelLayout::Interleaved {
+                    interleaved_to_planar(&frame.data[..data_len], channels, dst);
+                } else {
+                    dst.put_slice(&frame.data[..data_len]);
+                }
+            }
+            Packet::Metadata(frame) => {
+                // Metadata payload is a null terminated UTF-8 XML string
+                let bytes = frame.content.as_bytes();
+                dst.reserve(FRAME_HEADER_LEN + bytes.len() + 1);
+                FrameHeader::new(FrameType::Metadata, frame.timestamp, bytes.len() + 1)
+                    .write(dst);
+                dst.put_slice(bytes);
+                dst.put_u8(0);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Encoder<Packet> for OmtCodec {
+    type Error = AqueductError;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<()> {
+        Encoder::<&Packet>::encode(self, &packet, dst)
+    }
+}
+
+impl Decoder for OmtCodec {
+    type Item = Packet;
+    type Error = AqueductError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>> {
+        // Chunks that don't complete a frame are consumed without returning
+        // anything, carry on with whatever else is buffered
+        loop {
+            if src.len() < FRAME_HEADER_LEN {
+                return Ok(None);
+            }
+
+            // Peek header, this also validates version and size
+            let header = FrameHeader::read(&src[..FRAME_HEADER_LEN])?;
+            let len = header.data_len as usize;
+
+            let total_len = FRAME_HEADER_LEN + len;
+            if src.len() < total_len {
+                // Let the caller read the whole frame in as few reads as possible
+                src.reserve(total_len - src.len());
+                return Ok(None);
+            }
+
+            src.advance(FRAME_HEADER_LEN);
+            let payload = src.split_to(len).freeze();
+            if header.frame_type == FrameType::Chunk as u8 {
+                match self.reassemble(&payload)? {
+                    Some(packet) => return Ok(Some(packet)),
+                    None => continue,
+                }
+            }
+            return decode_frame(&header, payload).map(Some);
+        }
+    }
+}
+
+fn decode_frame(header: &FrameHeader, payload: Bytes) -> Result<Packet> {
+    // Per-frame metadata trails the payload. We don't surface it yet.
+    let payload = payload.slice(..payload.len() - header.metadata_len as usize);
+    let timestamp = header.timestamp();
+
+    match FrameType::from_u8(header.frame_type) {
+        Some(FrameType::Video) => {
+            let video = VideoHeader::read(&payload)?;
+            // A pixel format FourCC means raw pixels, anything else names a codec
+            let raw_format = PixelFormat::from_fourcc(video.codec);
+
+            // Without an extension, derive what we can from the OMT header
+            let mut defaults = VideoExtension {
+                stride: 0,
+                pixel_aspect_ratio: pixel_aspect_ratio(video.aspect_ratio, video.width, video.height),
+                colorimetry: colorimetry_from_color_space(video.color_space, video.height),
+                pixel_format: 0,
+            };
+            let mut offset = VIDEO_HEADER_LEN;
+            if video.flags & VIDEO_FLAG_EXTENSION != 0 {
+                let (extension, len) = VideoExtension::read(&payload[offset..], defaults)?;
+                offset += len;
+                defaults = extension;
+            }
+            let extension = defaults;
+
+            let (codec, format) = match raw_format {
+                Some(format) => (CodecId::UNCOMPRESSED, format),
+                None => {
+                    let format = PixelFormat::from_fourcc(extension.pixel_format).ok_or_else(|| {
+                        AqueductError::Protocol(format!(
+                            "Invalid pixel format for codec {}: {:#010x}",
+                            CodecId(video.codec), extension.pixel_format
+                        ))
+                    })?;
+                    (CodecId(video.codec), format)
+                }
+            };
+            let (stride, frame_size) = video.frame_layout(format, extension.stride)?;
+            let data = payload.slice(offset..);
+            if codec == CodecId::UNCOMPRESSED && data.len() < frame_size {
+                return Err(AqueductError::Protocol(format!(
+                    "Video payload too short: {} bytes, expected {}", data.len(), frame_size
+                )));
+            }
+
+            Ok(Packet::Video(VideoFrame {
+                width: video.width,
+                height: video.height,
+                format,
+                codec,
+                flags: FrameFlags::from_bits(video.flags),
+                frame_rate: FrameRate::new(video.frame_rate_n, video.frame_rate_d),
+                pixel_aspect_ratio: extension.pixel_aspect_ratio,
+                stride,
+                colorimetry: extension.colorimetry,
+                timestamp,
+                data,
+            }))
+        }
+        Some(FrameType::Audio) => {
+            let audio = AudioHeader::read(&payload)?;
+            let (format, channel_layout) = audio_format_from_field(audio.format)?;
+            let mut frame = AudioFrame {
+                sample_rate: audio.sample_rate,
+                channels: audio.channels,
+                format,
+                channel_layout,
+                codec: CodecId::UNCOMPRESSED,
+                timestamp,
+                data: payload.slice(AUDIO_HEADER_LEN..),
+            };
+            if audio.codec != FOURCC_FPA1 && audio.codec != FOURCC_PCM {
+                // Compressed, decoded by the receiver
+                frame.codec = CodecId(audio.codec);
+                return Ok(Packet::Audio(frame));
+            }
+            if audio.codec == FOURCC_FPA1 && format != SampleFormat::F32 {
+                return Err(AqueductError::Protocol(format!("FPA1 audio marked as {:?}", format)));
+            }
+
+            // Both counts come from the wire, a wrapped product could pass the length check
+            let expected = (audio.samples_per_channel as usize)
+                .checked_mul(audio.channels as usize)
+                .and_then(|samples| samples.checked_mul(format.bytes_per_sample()))
+                .filter(|&expected| expected <= frame.data.len())
+                .ok_or_else(|| AqueductError::Protocol("Audio packet too short".to_string()))?;
+            frame.data.truncate(expected);
+
+            // FPA1 is always planar on the wire, the layout is the one the sender used
+            if audio.codec == FOURCC_FPA1 && channel_layout == ChannelLayout::Interleaved {
+                let mut data = BytesMut::with_capacity(expected);
+                planar_to_interleaved(&frame.data, audio.channels as usize, &mut data);
+                frame.data = data.freeze();
+            }
+            Ok(Packet::Audio(frame))
+        }
+        Some(FrameType::Metadata) => {
+            let text = payload.strip_suffix(&[0]).unwrap_or(&payload);
+
+            Ok(Packet::Metadata(MetadataFrame {
+                timestamp,
+                content: String::from_utf8_lossy(text).to_string(),
+            }))
+        }
+        Some(FrameType::Chunk) => Err(AqueductError::Protocol("Chunk inside a chunked frame".to_string())),
+        None => Err(AqueductError::Protocol(format!("Unknown packet type: {}", header.frame_type))),
+    }
+}
+
+// OMT only carries the display aspect ratio, recover the pixel aspect ratio from it
+fn pixel_aspect_ratio(display_aspect_ratio: f32, width: u32, height: u32) -> f32 {
+    if display_aspect_ratio <= 0.0 || width == 0 || height == 0 {
+        return 1.0;
+    }
+    let par = display_aspect_ratio * height as f32 / width as f32;
+    // Snap float noise back to square pixels
+    if (par - 1.0).abs() < 1e-3 { 1.0 } else { par }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ColorRange, Colorimetry};
+
+    const ONE_SECOND: Duration = Duration::from_secs(1);
+
+    fn encode(codec: &mut OmtCodec, packet: &Packet) -> BytesMut {
+        let mut dst = BytesMut::new();
+        codec.encode(packet, &mut dst).unwrap();
+        dst
+    }
+
+    fn decode_one(bytes: &[u8]) -> Packet {
+        let mut src = BytesMut::from(bytes);
+        let packet = OmtCodec::new().decode(&mut src).unwrap().expect("a whole frame");
+        assert!(src.is_empty());
+        packet
+    }
+
+    fn video(packet: Packet) -> VideoFrame {
+        match packet {
+            Packet::Video(frame) => frame,
+            other => panic!("expected video, got {:?}", other),
+        }
+    }
+
+    fn audio(packet: Packet) -> AudioFrame {
+        match packet {
+            Packet::Audio(frame) => frame,
+            other => panic!("expected audio, got {:?}", other),
+        }
+    }
+
+    fn f32_bytes(samples: &[f32]) -> Bytes {
+        samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    }
+
+    // 2x1 UYVY at 29.97 fps, BT.709, one second in
+    const VIDEO_FRAME: [u8; 52] = [
+        // Frame header: version, type, timestamp in ticks, metadata length, data length
+        0x01, 0x02, 0x80, 0x96, 0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00,
+        // Video header: codec, width, height, frame rate, aspect ratio, flags, color space
+        b'U', b'Y', b'V', b'Y', 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x30, 0x75, 0x00, 0x00,
+        0xe9, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0xc5, 0x02, 0x00, 0x00,
+        // Pixels
+        0x10, 0x80, 0x20, 0x80,
+    ];
+
+    fn uyvy_frame() -> VideoFrame {
+        let mut frame = VideoFrame::new(2, 1, PixelFormat::UYVY, ONE_SECOND, Bytes::from_static(&[0x10, 0x80, 0x20, 0x80]));
+        frame.frame_rate = FrameRate::FPS_29_97;
+        frame.colorimetry = Colorimetry::BT709;
+        frame
+    }
+
+    #[test]
+    fn video_golden_bytes() {
+        let bytes = encode(&mut OmtCodec::new(), &Packet::Video(uyvy_frame()));
+        assert_eq!(&bytes[..], &VIDEO_FRAME[..]);
+
+        let frame = video(decode_one(&VIDEO_FRAME));
+        assert_eq!((frame.width, frame.height, frame.format), (2, 1, PixelFormat::UYVY));
+        assert_eq!(frame.codec, CodecId::UNCOMPRESSED);
+        assert_eq!(frame.frame_rate, FrameRate::FPS_29_97);
+        assert_eq!(frame.pixel_aspect_ratio, 1.0);
+        assert_eq!(frame.stride, 4);
+        assert_eq!(frame.colorimetry, Colorimetry::BT709);
+        assert_eq!(frame.timestamp, ONE_SECOND);
+        assert_eq!(&frame.data[..], &[0x10, 0x80, 0x20, 0x80]);
+    }
+
+    // Two interleaved float channels, two samples each, one second in
+    const AUDIO_FRAME: [u8; 56] = [
+        0x01, 0x04, 0x80, 0x96, 0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00,
+        // Audio header: codec, sample rate, samples per channel, channels, active channels, format
+        b'F', b'P', b'A', b'1', 0x80, 0xbb, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        // Planar samples: 0.5, 0.25 then -0.5, -0.25
+        0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x80, 0x3e, 0x00, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x80, 0xbe,
+    ];
+
+    #[test]
+    fn audio_golden_bytes() {
+        let frame = AudioFrame::new(48000, 2, ONE_SECOND, f32_bytes(&[0.5, -0.5, 0.25, -0.25]));
+        let bytes = encode(&mut OmtCodec::new(), &Packet::Audio(frame.clone()));
+        assert_eq!(&bytes[..], &AUDIO_FRAME[..]);
+
+        let decoded = audio(decode_one(&AUDIO_FRAME));
+        assert_eq!((decoded.sample_rate, decoded.channels), (48000, 2));
+        assert_eq!((decoded.format, decoded.channel_layout), (SampleFormat::F32, ChannelLayout::Interleaved));
+        assert_eq!(decoded.codec, CodecId::UNCOMPRESSED);
+        assert_eq!(decoded.timestamp, ONE_SECOND);
+        assert_eq!(decoded.data, frame.data);
+    }
+
+    #[test]
+    fn metadata_golden_bytes() {
+        let frame = MetadataFrame { timestamp: ONE_SECOND, content: "<a/>".to_string() };
+        let bytes = encode(&mut OmtCodec::new(), &Packet::Metadata(frame));
+        let expected = [
+            0x01, 0x01, 0x80, 0x96, 0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+            b'<', b'a', b'/', b'>', 0x00,
+        ];
+        assert_eq!(&bytes[..], &expected[..]);
+
+        match decode_one(&expected) {
+            Packet::Metadata(frame) => {
+                assert_eq!(frame.content, "<a/>");
+                assert_eq!(frame.timestamp, ONE_SECOND);
+            }
+            other => panic!("expected metadata, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn video_extension_round_trip() {
+        // Padded lines, anamorphic pixels and full range need the extension
+        let mut frame = VideoFrame::new(2, 2, PixelFormat::BGRA, ONE_SECOND, Bytes::from(vec![7u8; 32]));
+        frame.stride = 16;
+        frame.pixel_aspect_ratio = 4.0 / 3.0;
+        frame.colorimetry.range = ColorRange::Full;
+        frame.flags.alpha = true;
+        let bytes = encode(&mut OmtCodec::new(), &Packet::Video(frame.clone()));
+        let flags = u32::from_le_bytes(bytes[FRAME_HEADER_LEN + 24..FRAME_HEADER_LEN + 28].try_into().unwrap());
+        assert_ne!(flags & VIDEO_FLAG_EXTENSION, 0);
+
+        let decoded = video(decode_one(&bytes));
+        assert_eq!(decoded.stride, 16);
+        assert!((decoded.pixel_aspect_ratio - frame.pixel_aspect_ratio).abs() < 1e-6);
+        assert_eq!(decoded.colorimetry, frame.colorimetry);
+        assert_eq!(decoded.flags, frame.flags);
+        assert_eq!(decoded.data, frame.data);
+    }
+
+    #[test]
+    fn compressed_video_round_trip() {
+        let mut frame = VideoFrame::new(64, 64, PixelFormat::NV12, ONE_SECOND, Bytes::from_static(b"compressed"));
+        frame.codec = CodecId::LZ4;
+        let decoded = video(decode_one(&encode(&mut OmtCodec::new(), &Packet::Video(frame))));
+        assert_eq!(decoded.codec, CodecId::LZ4);
+        assert_eq!(decoded.format, PixelFormat::NV12);
+        assert_eq!(&decoded.data[..], b"compressed");
+    }
+
+    #[test]
+    fn integer_audio_round_trip() {
+        let mut frame = AudioFrame::new(48000, 2, ONE_SECOND, Bytes::from_static(&[1, 0, 2, 0, 3, 0, 4, 0]));
+        frame.format = SampleFormat::S16;
+        let bytes = encode(&mut OmtCodec::new(), &Packet::Audio(frame));
+        assert_eq!(&bytes[FRAME_HEADER_LEN..FRAME_HEADER_LEN + 4], b"PCM ");
+
+        let decoded = audio(decode_one(&bytes));
+        assert_eq!(decoded.format, SampleFormat::S16);
+        assert_eq!(decoded.channel_layout, ChannelLayout::Interleaved);
+        assert_eq!(&decoded.data[..], &[1, 0, 2, 0, 3, 0, 4, 0]);
+    }
+
+    #[test]
+    fn decode_waits_for_whole_frames() {
+        let mut stream = BytesMut::new();
+        stream.extend_from_slice(&VIDEO_FRAME);
+        stream.extend_from_slice(&AUDIO_FRAME);
+
+        let mut codec = OmtCodec::new();
+        let mut src = BytesMut::new();
+        let mut packets = Vec::new();
+        for byte in stream {
+            src.extend_from_slice(&[byte]);
+            if let Some(packet) = codec.decode(&mut src).unwrap() {
+                packets.push(packet);
+            }
+        }
+        assert!(src.is_empty());
+        assert!(matches!(packets[..], [Packet::Video(_), Packet::Audio(_)]));
+    }
+
+    #[test]
+    fn chunked_frame_reassembly() {
+        let frame = VideoFrame::new(16, 4, PixelFormat::BGRA, ONE_SECOND, Bytes::from((0..=255u8).collect::<Vec<_>>()));
+        let encoded = encode(&mut OmtCodec::new(), &Packet::Video(frame.clone()));
+
+        let mut src = BytesMut::new();
+        for (i, piece) in encoded.chunks(100).enumerate() {
+            encode_chunk_header(ONE_SECOND, 9, i * 100, piece.len(), encoded.len(), &mut src);
+            src.extend_from_slice(piece);
+        }
+        let mut codec = OmtCodec::new();
+        let decoded = video(codec.decode(&mut src).unwrap().expect("a reassembled frame"));
+        assert!(src.is_empty());
+        assert_eq!(decoded.data, frame.data);
+    }
+
+    #[test]
+    fn chunk_out_of_sequence() {
+        let encoded = encode(&mut OmtCodec::new(), &Packet::Video(uyvy_frame()));
+        let mut src = BytesMut::new();
+        encode_chunk_header(ONE_SECOND, 1, 10, 10, encoded.len(), &mut src);
+        src.extend_from_slice(&encoded[10..20]);
+        assert!(matches!(OmtCodec::new().decode(&mut src), Err(AqueductError::Protocol(_))));
+    }
+
+    #[test]
+    fn chunk_of_another_frame() {
+        let frame = VideoFrame::new(16, 4, PixelFormat::BGRA, ONE_SECOND, Bytes::from((0..=255u8).collect::<Vec<_>>()));
+        let encoded = encode(&mut OmtCodec::new(), &Packet::Video(frame.clone()));
+        let len = encoded.len();
+
+        // A second chunk with another sequence, another total length, or running past the end
+        for (sequence, piece, total_len) in [(8, 100, len), (9, 100, len + 1), (9, 100, u32::MAX as usize), (9, len - 99, len)] {
+            let mut src = BytesMut::new();
+            encode_chunk_header(ONE_SECOND, 9, 0, 100, len, &mut src);
+            src.extend_from_slice(&encoded[..100]);
+            encode_chunk_header(ONE_SECOND, sequence, 100, piece, total_len, &mut src);
+            src.extend_from_slice(&[0; 256][..piece]);
+
+            let mut codec = OmtCodec::new();
+            let result = codec.decode(&mut src);
+            assert!(matches!(result, Err(AqueductError::Protocol(_))), "{:?}", result);
+
+            // The frame in progress is abandoned, the next one starts afresh
+            for (i, piece) in encoded.chunks(100).enumerate() {
+                encode_chunk_header(ONE_SECOND, 10, i * 100, piece.len(), len, &mut src);
+                src.extend_from_slice(piece);
+            }
+            assert_eq!(video(codec.decode(&mut src).unwrap().expect("a reassembled frame")).data, frame.data);
+        }
+    }
+
+    #[test]
+    fn plain_codec() {
+        let mut codec = OmtCodec::plain();
+        // Plain OMT peers expect zero in the reserved audio field
+        let frame = AudioFrame::new(48000, 2, ONE_SECOND, f32_bytes(&[0.5, -0.5, 0.25, -0.25]));
+        let bytes = encode(&mut codec, &Packet::Audio(frame));
+        assert_eq!(&bytes[FRAME_HEADER_LEN + 20..FRAME_HEADER_LEN + 24], &[0, 0, 0, 0]);
+        assert_eq!(&bytes[FRAME_HEADER_LEN + AUDIO_HEADER_LEN..], &AUDIO_FRAME[FRAME_HEADER_LEN + AUDIO_HEADER_LEN..]);
+
+        let mut frame = uyvy_frame();
+        frame.codec = CodecId::LZ4;
+        let mut dst = BytesMut::new();
+        assert!(codec.encode(&Packet::Video(frame), &mut dst).is_err());
+        let mut frame = uyvy_frame();
+        frame.stride = 8;
+        assert!(codec.encode(&Packet::Video(frame), &mut dst).is_err());
+    }
+
+    #[test]
+    fn libomt_frame_with_per_frame_metadata() {
+        // libomt appends per-frame metadata after the payload and counts it in both lengths
+        let mut bytes = BytesMut::from(&VIDEO_FRAME[..]);
+        let trailer = b"<m/>\0";
+        bytes[12..16].copy_from_slice(&(36 + trailer.len() as u32).to_le_bytes());
+        bytes[10..12].copy_from_slice(&(trailer.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(trailer);
+
+        let frame = video(decode_one(&bytes));
+        assert_eq!(&frame.data[..], &[0x10, 0x80, 0x20, 0x80]);
+    }
+
+    #[test]
+    fn libomt_planar_audio() {
+        // Zero in the reserved field: planar float, passed on as it came
+        let mut bytes = BytesMut::from(&AUDIO_FRAME[..]);
+        bytes[FRAME_HEADER_LEN + 20..FRAME_HEADER_LEN + 24].copy_from_slice(&[0, 0, 0, 0]);
+
+        let frame = audio(decode_one(&bytes));
+        assert_eq!(frame.channel_layout, ChannelLayout::Planar);
+        assert_eq!(frame.data, f32_bytes(&[0.5, 0.25, -0.5, -0.25]));
+    }
+
+    #[test]
+    fn hostile_frames() {
+        let protocol_error = |bytes: &[u8]| {
+            let result = OmtCodec::new().decode(&mut BytesMut::from(bytes));
+            assert!(matches!(result, Err(AqueductError::Protocol(_))), "{:?}", result);
+        };
+
+        // Width whose stride overflows a u32
+        let mut bytes = VIDEO_FRAME;
+        bytes[16..20].copy_from_slice(b"BGRA");
+        bytes[20..24].copy_from_slice(&0x4000_0000u32.to_le_bytes());
+        protocol_error(&bytes);
+
+        // Fewer pixels than the header promises
+        let mut bytes = VIDEO_FRAME;
+        bytes[24..28].copy_from_slice(&2u32.to_le_bytes());
+        protocol_error(&bytes);
+
+        // Huge extension stride
+        let mut frame = uyvy_frame();
+        frame.stride = 8;
+        frame.data = Bytes::from(vec![0u8; 8]);
+        let mut bytes = encode(&mut OmtCodec::new(), &Packet::Video(frame));
+        let stride = FRAME_HEADER_LEN + VIDEO_HEADER_LEN + 2;
+        bytes[stride..stride + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        protocol_error(&bytes);
+
+        // Truncated video header
+        let mut bytes = BytesMut::from(&VIDEO_FRAME[..FRAME_HEADER_LEN + 8]);
+        bytes[12..16].copy_from_slice(&8u32.to_le_bytes());
+        protocol_error(&bytes);
+
+        // Fewer samples than the audio header promises
+        let mut bytes = AUDIO_FRAME;
+        bytes[FRAME_HEADER_LEN + 8..FRAME_HEADER_LEN + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        protocol_error(&bytes);
+
+        // Sample and channel counts whose product overflows, wrapping to a length that fits
+        let mut bytes = AUDIO_FRAME;
+        bytes[FRAME_HEADER_LEN + 8..FRAME_HEADER_LEN + 12].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        bytes[FRAME_HEADER_LEN + 12..FRAME_HEADER_LEN + 16].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        protocol_error(&bytes);
+        let mut bytes = AUDIO_FRAME;
+        bytes[FRAME_HEADER_LEN + 8..FRAME_HEADER_LEN + 16].copy_from_slice(&[0xff; 8]);
+        protocol_error(&bytes);
+
+        // Chunked frame claiming more than MAX_FRAME_LEN
+        let mut bytes = BytesMut::new();
+        encode_chunk_header(ONE_SECOND, 1, 0, 0, usize::MAX >> 32, &mut bytes);
+        protocol_error(&bytes);
+    }
+}
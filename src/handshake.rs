@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use crate::error::{Result, AqueductError};
+use crate::codec::CodecId;
+use crate::protocol::{MetadataFrame, PixelFormat};
+
+// Version of the Aqueduct session protocol, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Version given to peers that never sent a hello, such as libomt senders and receivers
+pub const PLAIN_OMT_VERSION: u32 = 0;
+
+// How long either side waits for the peer's hello before treating it as a plain
+// OMT peer. Aqueduct peers send theirs as soon as the connection opens.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+const HELLO_ELEMENT: &str = "AqueductHello";
+const REJECT_ELEMENT: &str = "AqueductReject";
+
+// libomt senders stream nothing until the receiver subscribes to each kind of media
+const OMT_SUBSCRIPTIONS: [&str; 3] = [
+    r#"<OMTSubscribe Video="true" />"#,
+    r#"<OMTSubscribe Audio="true" />"#,
+    r#"<OMTSubscribe Metadata="true" />"#,
+];
+
+// Optional protocol features, listed in the hello's Features element
+const FEATURE_CHUNKED_VIDEO: &str = "ChunkedVideo";
+
+/// What one side of a connection supports, exchanged when the connection opens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u32,
+    pub name: String,
+    pub codecs: Vec<CodecId>,
+    pub pixel_formats: Vec<PixelFormat>,
+    // Large video frames may arrive split into chunks, see `wire::ChunkHeader`
+    pub chunked_video: bool,
+}
+
+impl Capabilities {
+    pub fn new(name: &str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            name: name.to_string(),
+            codecs: vec![CodecId::UNCOMPRESSED, CodecId::LZ4],
+            pixel_formats: PixelFormat::ALL.to_vec(),
+            chunked_video: true,
+        }
+    }
+
+    // What a peer without a hello is assumed to handle: uncompressed video in any
+    // OMT pixel format and planar float audio, none of Aqueduct's extensions
+    pub fn plain_omt() -> Self {
+        Self {
+            version: PLAIN_OMT_VERSION,
+            name: "OMT".to_string(),
+            codecs: vec![CodecId::UNCOMPRESSED],
+            pixel_formats: PixelFormat::ALL.to_vec(),
+            chunked_video: false,
+        }
+    }
+
+    pub fn is_plain_omt(&self) -> bool {
+        self.version == PLAIN_OMT_VERSION
+    }
+
+    pub fn supports_pixel_format(&self, format: PixelFormat) -> bool {
+        self.pixel_formats.contains(&format)
+    }
+
+    pub fn supports_codec(&self, codec: CodecId) -> bool {
+        self.codecs.contains(&codec)
+    }
+}
+
+/// The parts of both peers' capabilities that the session can actually use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub codecs: Vec<CodecId>,
+    pub pixel_formats: Vec<PixelFormat>,
+    pub chunked_video: bool,
+    // Aqueduct's additions to OMT (video extension header, integer audio, control
+    // messages) may be used. False for plain OMT peers.
+    pub extensions: bool,
+}
+
+pub fn negotiate(local: &Capabilities, remote: &Capabilities) -> Result<Negotiated> {
+    if !remote.is_plain_omt() && local.version != remote.version {
+        return Err(AqueductError::Handshake(format!(
+            "Protocol version mismatch: local {}, remote {} ({})",
+            local.version, remote.version, remote.name
+        )));
+    }
+
+    let codecs: Vec<CodecId> = local.codecs.iter()
+        .filter(|codec| remote.codecs.contains(codec))
+        .copied()
+        .collect();
+    if codecs.is_empty() {
+        return Err(AqueductError::Handshake(format!(
+            "No common codec: local {:?}, remote {:?} ({})",
+            local.codecs, remote.codecs, remote.name
+        )));
+    }
+
+    let pixel_formats: Vec<PixelFormat> = local.pixel_formats.iter()
+        .filter(|format| remote.pixel_formats.contains(format))
+        .copied()
+        .collect();
+    if pixel_formats.is_empty() {
+        return Err(AqueductError::Handshake(format!(
+            "No common pixel format: local {:?}, remote {:?} ({})",
+            local.pixel_formats, remote.pixel_formats, remote.name
+        )));
+    }
+
+    Ok(Negotiated {
+        codecs,
+        pixel_formats,
+        chunked_video: local.chunked_video && remote.chunked_video,
+        extensions: !remote.is_plain_omt(),
+    })
+}
+
+/// Control messages exchanged as OMT metadata frames before any media flows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeMessage {
+    Hello(Capabilities),
+    Reject(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "AqueductHello")]
+struct HelloXml {
+    #[serde(rename = "Version")]
+    version: u32,
+    #[serde(rename = "Name", default)]
+    name: String,
+    #[serde(rename = "Codecs", default)]
+    codecs: String,
+    #[serde(rename = "PixelFormats", default)]
+    pixel_formats: String,
+    #[serde(rename = "Features", default)]
+    features: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "AqueductReject")]
+struct RejectXml {
+    #[serde(rename = "Reason", default)]
+    reason: String,
+}
+
+impl HandshakeMessage {
+    pub fn to_metadata(&self) -> Result<MetadataFrame> {
+        let content = match self {
+            Self::Hello(caps) => serde_xml_rs::to_string(&HelloXml {
+                version: caps.version,
+                name: caps.name.clone(),
+                codecs: caps.codecs.iter()
+                    .map(|codec| codec.name())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                pixel_formats: caps.pixel_formats.iter()
+                    .map(|format| format.name())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                features: if caps.chunked_video { FEATURE_CHUNKED_VIDEO.to_string() } else { String::new() },
+            }),
+            Self::Reject(reason) => serde_xml_rs::to_string(&RejectXml { reason: reason.clone() }),
+        }.map_err(|e| AqueductError::Serialization(e.to_string()))?;
+
+        Ok(MetadataFrame {
+            timestamp: Duration::ZERO,
+            content,
+        })
+    }
+
+    // Whether the frame is a hello or reject, rather than metadata of a plain OMT peer
+    pub fn is_handshake(frame: &MetadataFrame) -> bool {
+        matches!(root_element(&frame.content), Some(HELLO_ELEMENT | REJECT_ELEMENT))
+    }
+
+    pub fn from_metadata(frame: &MetadataFrame) -> Result<Self> {
+        match root_element(&frame.content) {
+            Some(HELLO_ELEMENT) => {
+                let hello: HelloXml = serde_xml_rs::from_str(&frame.content)
+                    .map_err(|e| AqueductError::Serialization(e.to_string()))?;
+
+                let mut pixel_formats = Vec::new();
+                for name in split_list(&hello.pixel_formats) {
+                    // Unknown formats are skipped so newer peers can still talk to us
+                    if let Some(format) = PixelFormat::from_name(name) {
+                        pixel_formats.push(format);
+                    }
+                }
+
+                let chunked_video = split_list(&hello.features).any(|feature| feature == FEATURE_CHUNKED_VIDEO);
+                Ok(Self::Hello(Capabilities {
+                    version: hello.version,
+                    name: hello.name,
+                    codecs: split_list(&hello.codecs).filter_map(CodecId::from_name).collect(),
+                    pixel_formats,
+                    chunked_video,
+                }))
+            }
+            Some(REJECT_ELEMENT) => {
+                let reject: RejectXml = serde_xml_rs::from_str(&frame.content)
+                    .map_err(|e| AqueductError::Serialization(e.to_string()))?;
+                Ok(Self::Reject(reject.reason))
+            }
+            _ => Err(AqueductError::Handshake(format!("Expected handshake message, got: {}", frame.content))),
+        }
+    }
+}
+
+// What a receiver sends a plain OMT sender to start video, audio and metadata
+pub fn omt_subscriptions() -> impl Iterator<Item = MetadataFrame> {
+    OMT_SUBSCRIPTIONS.into_iter().map(|content| MetadataFrame {
+        timestamp: Duration::ZERO,
+        content: content.to_string(),
+    })
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+// Name of the first element in an XML document, skipping the declaration
+pub(crate) fn root_element(xml: &str) -> Option<&str> {
+    let mut rest = xml.trim_start();
+    if rest.starts_with("<?") {
+        rest = rest[rest.find("?>")? + 2..].trim_start();
+    }
+    let rest = rest.strip_prefix('<')?;
+    let end = rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/')?;
+    Some(&rest[..end])
+}
@@ -0,0 +1,98 @@
+use bytes::{BufMut, BytesMut};
+use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+use image::{ColorType, ImageDecoder};
+use crate::codec::{VideoEncoder, VideoDecoder};
+use crate::convert::{convert, ImageFormat, ImageLayout};
+use crate::error::{Result, AqueductError};
+use crate::protocol::VideoFrame;
+
+// Baseline JPEG for confidence monitors and browser previews.
+//
+// Any pixel format is converted to RGB before encoding and back to the frame's
+// format after decoding, using the frame's colorimetry. The payload is a plain
+// JPEG file, so it can be handed to a browser as is. Alpha is not kept, decoded
+// frames are opaque.
+
+pub const JPEG_DEFAULT_QUALITY: u8 = 75;
+
+pub struct JpegCodec {
+    quality: u8,
+    rgb: BytesMut,
+}
+
+impl Default for JpegCodec {
+    fn default() -> Self {
+        Self::with_quality(JPEG_DEFAULT_QUALITY)
+    }
+}
+
+impl JpegCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 1 (smallest) to 100 (best)
+    pub fn with_quality(quality: u8) -> Self {
+        Self {
+            quality: quality.clamp(1, 100),
+            rgb: BytesMut::new(),
+        }
+    }
+
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+}
+
+fn rgb_layout(frame: &VideoFrame) -> ImageLayout {
+    ImageLayout {
+        colorimetry: frame.colorimetry,
+        ..ImageLayout::new(ImageFormat::Rgb24, frame.width, frame.height)
+    }
+}
+
+fn check_dimensions(frame: &VideoFrame) -> Result<()> {
+    if frame.width == 0 || frame.height == 0 || frame.width > u16::MAX as u32 || frame.height > u16::MAX as u32 {
+        return Err(AqueductError::Codec(format!(
+            "JPEG can't code a {}x{} frame", frame.width, frame.height
+        )));
+    }
+    Ok(())
+}
+
+impl VideoEncoder for JpegCodec {
+    fn encode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        check_dimensions(frame)?;
+        let layout = rgb_layout(frame);
+        self.rgb.clear();
+        convert(&frame.data, &ImageLayout::from(frame), &layout, &mut self.rgb)?;
+
+        let mut writer = dst.writer();
+        JpegEncoder::new_with_quality(&mut writer, self.quality)
+            .encode(&self.rgb, frame.width, frame.height, ColorType::Rgb8)
+            .map_err(|e| AqueductError::Codec(format!("JPEG encoding error: {}", e)))
+    }
+}
+
+impl VideoDecoder for JpegCodec {
+    fn decode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        check_dimensions(frame)?;
+        let decoder = JpegDecoder::new(&frame.data[..])
+            .map_err(|e| AqueductError::Codec(format!("JPEG decoding error: {}", e)))?;
+        if decoder.dimensions() != (frame.width, frame.height) {
+            return Err(AqueductError::Codec(format!(
+                "JPEG is {:?}, frame header says {}x{}", decoder.dimensions(), frame.width, frame.height
+            )));
+        }
+        if decoder.color_type() != ColorType::Rgb8 {
+            return Err(AqueductError::Codec(format!("Unsupported JPEG color type: {:?}", decoder.color_type())));
+        }
+
+        let layout = rgb_layout(frame);
+        self.rgb.clear();
+        self.rgb.resize(layout.frame_size(), 0);
+        decoder.read_image(&mut self.rgb)
+            .map_err(|e| AqueductError::Codec(format!("JPEG decoding error: {}", e)))?;
+        convert(&self.rgb, &layout, &ImageLayout::from(frame), dst)
+    }
+}
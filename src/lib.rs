@@ -4,10 +4,46 @@ pub mod transport;
 pub mod error;
 pub mod codec;
 pub mod audio_source;
+pub mod wire;
+pub mod framing;
+pub mod handshake;
+pub mod convert;
+pub mod audio_convert;
+pub mod audio_process;
+pub mod jpeg;
+pub mod delta;
+pub mod tiles;
+pub mod control;
+pub mod lossless_audio;
+pub mod playout;
+pub mod clock;
+pub mod queue;
+pub mod config;
+mod bits;
 
-pub use protocol::{Packet, VideoFrame, AudioFrame, MetadataFrame, PixelFormat, FrameFlags};
+pub use protocol::{
+    Packet, VideoFrame, AudioFrame, MetadataFrame, PixelFormat, FrameFlags, FrameRate,
+    Colorimetry, ColorMatrix, ColorRange, TransferFunction, ColorPrimaries, SampleFormat, ChannelLayout,
+};
 pub use discovery::Discovery;
-pub use transport::{Sender, Receiver};
+pub use transport::{Sender, Receiver, ConnectionStats};
+pub use framing::OmtCodec;
+pub use handshake::Capabilities;
+pub use convert::{ImageFormat, ImageLayout};
+pub use audio_convert::AudioLayout;
+pub use audio_process::{Resampler, ResampleQuality, ChannelMatrix};
 pub use error::{AqueductError, Result};
-pub use codec::{VideoEncoder, VideoDecoder, Lz4Codec};
+pub use codec::{
+    CodecId, CodecRegistry, EncoderStats, VideoEncoder, VideoDecoder, AudioEncoder, AudioDecoder,
+    Lz4Codec, ZstdCodec,
+};
+pub use jpeg::JpegCodec;
+pub use delta::DeltaCodec;
+pub use tiles::TileCodec;
+pub use control::ControlMessage;
+pub use clock::{ClockEstimate, ClockSync, FrameClock, Tick};
+pub use queue::{QueuePolicy, QueueStats, VideoPolicy};
+pub use config::{SenderConfig, SocketOptions};
+pub use lossless_audio::LosslessAudioCodec;
+pub use playout::{Playout, PlayoutBuffer, PlayoutConfig, PlayoutStats};
 pub use audio_source::SineWaveGenerator;
@@ -0,0 +1,436 @@
+use bytes::{Buf, BufMut, BytesMut};
+use crate::bits::{BitReader, BitWriter};
+use crate::codec::{AudioEncoder, AudioDecoder};
+use crate::error::{Result, AqueductError};
+use crate::audio_convert::{convert_audio, AudioLayout};
+use crate::protocol::{AudioFrame, SampleFormat, ChannelLayout};
+
+// Lossless predictive audio codec, in the spirit of FLAC.
+//
+// Samples are turned into 16 or 24-bit integers, each channel is run through the
+// fixed polynomial predictor (order 0 to 3) that fits it best, and the residuals
+// are Rice coded in partitions with their own parameter.
+//
+// Payload:
+//
+//   [Version: u8][Bits: u8][Reserved: u16][Samples Per Channel: u32]
+//   then per channel [Length: u32][Bitstream]
+//
+// Channel bitstream: [Order: 2 bits][Shift: 5 bits], Order warm-up samples of
+// Bits - Shift bits, then per partition of PARTITION_LEN residuals
+// [Rice Parameter: 5 bits][Residuals]. Shift counts low bits that are zero in every
+// sample of the channel, so 16-bit sources cost the same at either depth.
+//
+// Integer frames are coded at their own depth. The codec never loses anything: a
+// float frame with samples that don't sit exactly on the integer grid is sent as
+// planar floats instead (Bits 32, no bitstream, each channel is [Length: u32] and
+// its samples). Float sources fed from 16 or 24-bit devices therefore compress,
+// others are passed through unless the codec is built with `quantizing`, which
+// rounds them to the chosen depth.
+//
+// Decoding gives back the frame's sample format and channel layout, as carried in
+// the audio header.
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+const FLOAT_BITS: u8 = 32;
+const MAX_ORDER: usize = 3;
+const PARTITION_LEN: usize = 256;
+const MAX_RICE_PARAMETER: u32 = 30;
+
+pub const DEFAULT_AUDIO_BIT_DEPTH: u8 = 24;
+
+pub struct LosslessAudioCodec {
+    bits: u8,
+    quantize: bool,
+    planar: BytesMut,
+    samples: Vec<i32>,
+    residuals: Vec<i32>,
+}
+
+impl Default for LosslessAudioCodec {
+    fn default() -> Self {
+        Self::with_bit_depth(DEFAULT_AUDIO_BIT_DEPTH)
+    }
+}
+
+impl LosslessAudioCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Depth float frames are coded at, 16 or 24. Float frames that don't fit are
+    // sent as floats, integer frames are always coded at their own depth.
+    pub fn with_bit_depth(bits: u8) -> Self {
+        Self {
+            bits: if bits <= 16 { 16 } else { 24 },
+            quantize: false,
+            planar: BytesMut::new(),
+            samples: Vec::new(),
+            residuals: Vec::new(),
+        }
+    }
+
+    // Rounds every float frame to `bits`, lossy for sources with more precision than that
+    pub fn quantizing(bits: u8) -> Self {
+        Self {
+            quantize: true,
+            ..Self::with_bit_depth(bits)
+        }
+    }
+
+    pub fn bit_depth(&self) -> u8 {
+        self.bits
+    }
+}
+
+// Fills `samples` with planar float samples as integers of `bits` bits, or returns
+// false when that would lose precision and `quantize` isn't set
+fn load_float(planar: &[u8], bits: u8, quantize: bool, samples: &mut Vec<i32>) -> bool {
+    let scale = (1i64 << (bits - 1)) as f64;
+    let max = (1i64 << (bits - 1)) - 1;
+    let min = -(1i64 << (bits - 1));
+    samples.clear();
+    for sample in planar.chunks_exact(4) {
+        let value = f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]);
+        if !value.is_finite() && !quantize {
+            return false;
+        }
+        let scaled = (value as f64 * scale).round();
+        let integer = (scaled as i64).clamp(min, max);
+        if !quantize && (integer as f64 / scale) as f32 != value {
+            return false;
+        }
+        samples.push(integer as i32);
+    }
+    true
+}
+
+fn load_integers(planar: &[u8], format: SampleFormat, samples: &mut Vec<i32>) {
+    samples.clear();
+    match format {
+        SampleFormat::S16 => samples.extend(planar.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]]) as i32)),
+        SampleFormat::S24 => samples.extend(planar.chunks_exact(3).map(|s| i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8)),
+        SampleFormat::F32 => {}
+    }
+}
+
+impl AudioEncoder for LosslessAudioCodec {
+    fn encode_into(&mut self, frame: &AudioFrame, dst: &mut BytesMut) -> Result<()> {
+        if frame.channels == 0 {
+            return Err(AqueductError::Codec("Audio frame without channels".to_string()));
+        }
+        let channels = frame.channels as usize;
+        let samples_per_channel = frame.samples_per_channel();
+
+        // Channels are coded one after the other, whatever the frame's layout
+        let from = AudioLayout::from(frame);
+        self.planar.clear();
+        convert_audio(
+            &frame.data[..samples_per_channel * from.frame_bytes()],
+            &from,
+            &AudioLayout::new(frame.format, ChannelLayout::Planar, frame.channels),
+            &mut self.planar,
+        )?;
+        let bits = match frame.format {
+            SampleFormat::S16 => 16,
+            SampleFormat::S24 => 24,
+            SampleFormat::F32 if load_float(&self.planar, self.bits, self.quantize, &mut self.samples) => self.bits,
+            SampleFormat::F32 => FLOAT_BITS,
+        };
+        if bits != FLOAT_BITS && frame.format != SampleFormat::F32 {
+            load_integers(&self.planar, frame.format, &mut self.samples);
+        }
+
+        dst.put_u8(VERSION);
+        dst.put_u8(bits);
+        dst.put_u16_le(0);
+        dst.put_u32_le(samples_per_channel as u32);
+
+        if bits == FLOAT_BITS {
+            let channel_bytes = samples_per_channel * 4;
+            for ch in 0..channels {
+                dst.put_u32_le(channel_bytes as u32);
+                dst.put_slice(&self.planar[ch * channel_bytes..(ch + 1) * channel_bytes]);
+            }
+            return Ok(());
+        }
+
+        let mut writer = BitWriter::default();
+        for ch in 0..channels {
+            let channel = &mut self.samples[ch * samples_per_channel..(ch + 1) * samples_per_channel];
+            let shift = channel.iter()
+                .fold(0i32, |acc, &sample| acc | sample)
+                .trailing_zeros()
+                .min(bits as u32 - 1);
+            for sample in channel.iter_mut() {
+                *sample >>= shift;
+            }
+            let channel = &*channel;
+            let order = best_order(channel);
+            writer.put_bits(order as u32, 2);
+            writer.put_bits(shift, 5);
+            for &sample in &channel[..order] {
+                writer.put_bits(sample as u32, bits as u32 - shift);
+            }
+            self.residuals.clear();
+            self.residuals.extend((order..channel.len()).map(|i| residual(channel, i, order)));
+            for partition in self.residuals.chunks(PARTITION_LEN) {
+                let k = rice_parameter(partition);
+                writer.put_bits(k, 5);
+                for &value in partition {
+                    writer.put_signed_rice(value, k);
+                }
+            }
+            let bits = writer.finish();
+            dst.put_u32_le(bits.len() as u32);
+            dst.put_slice(&bits);
+        }
+        Ok(())
+    }
+}
+
+impl AudioDecoder for LosslessAudioCodec {
+    fn decode_into(&mut self, frame: &AudioFrame, dst: &mut BytesMut) -> Result<()> {
+        let mut data = &frame.data[..];
+        if data.len() < HEADER_LEN {
+            return Err(AqueductError::Codec("Lossless audio payload too short".to_string()));
+        }
+        let version = data.get_u8();
+        let bits = data.get_u8();
+        data.advance(2);
+        let samples_per_channel = data.get_u32_le() as usize;
+        if version != VERSION {
+            return Err(AqueductError::Codec(format!("Unsupported lossless audio version: {}", version)));
+        }
+        if !matches!(bits, 16 | 24 | FLOAT_BITS) {
+            return Err(AqueductError::Codec(format!("Invalid lossless audio bit depth: {}", bits)));
+        }
+
+        // Every channel is a length prefixed block
+        let channels = frame.channels as usize;
+        if channels == 0 || channels * 4 > data.len() {
+            return Err(AqueductError::Codec("Lossless audio payload truncated".to_string()));
+        }
+        let mut blocks = Vec::with_capacity(channels);
+        for _ in 0..channels {
+            if data.len() < 4 {
+                return Err(AqueductError::Codec("Lossless audio payload truncated".to_string()));
+            }
+            let len = data.get_u32_le() as usize;
+            if data.len() < len {
+                return Err(AqueductError::Codec("Lossless audio payload truncated".to_string()));
+            }
+            blocks.push(&data[..len]);
+            data.advance(len);
+        }
+
+        // Every sample costs at least a bit, don't let a corrupt count allocate more than that
+        if blocks.iter().any(|block| block.len() * 8 < samples_per_channel) {
+            return Err(AqueductError::Codec("Lossless audio payload truncated".to_string()));
+        }
+        if bits == FLOAT_BITS && blocks.iter().any(|block| block.len() != samples_per_channel * 4) {
+            return Err(AqueductError::Codec("Lossless audio channel has the wrong length".to_string()));
+        }
+
+        // Rebuild planar samples in the format they were coded in, then hand them
+        // out in the frame's format and layout
+        self.planar.clear();
+        let coded_format = match bits {
+            16 => SampleFormat::S16,
+            24 => SampleFormat::S24,
+            _ => SampleFormat::F32,
+        };
+        if bits == FLOAT_BITS {
+            for block in &blocks {
+                self.planar.extend_from_slice(block);
+            }
+        } else {
+            self.samples.clear();
+            for block in &blocks {
+                decode_channel(block, bits as u32, samples_per_channel, &mut self.samples)?;
+            }
+            self.planar.reserve(self.samples.len() * coded_format.bytes_per_sample());
+            for &sample in &self.samples {
+                self.planar.put_slice(&sample.to_le_bytes()[..coded_format.bytes_per_sample()]);
+            }
+        }
+        convert_audio(
+            &self.planar,
+            &AudioLayout::new(coded_format, ChannelLayout::Planar, frame.channels),
+            &AudioLayout::from(frame),
+            dst,
+        )
+    }
+}
+
+// Appends `len` samples to `samples`
+fn decode_channel(block: &[u8], bits: u32, len: usize, samples: &mut Vec<i32>) -> Result<()> {
+    let mut reader = BitReader::new(block);
+    let order = reader.get_bits(2)? as usize;
+    let shift = reader.get_bits(5)?;
+    if order > len {
+        return Err(AqueductError::Codec("Lossless audio predictor longer than the frame".to_string()));
+    }
+    if shift >= bits {
+        return Err(AqueductError::Codec(format!("Invalid lossless audio shift: {}", shift)));
+    }
+    let start = samples.len();
+    let warm_up_bits = bits - shift;
+    for _ in 0..order {
+        // Sign extend the warm-up sample
+        let raw = reader.get_bits(warm_up_bits)?;
+        samples.push(((raw << (32 - warm_up_bits)) as i32) >> (32 - warm_up_bits));
+    }
+    while samples.len() - start < len {
+        let k = reader.get_bits(5)?;
+        if k > MAX_RICE_PARAMETER {
+            return Err(AqueductError::Codec(format!("Invalid Rice parameter: {}", k)));
+        }
+        let partition_end = (samples.len() + PARTITION_LEN).min(start + len);
+        while samples.len() < partition_end {
+            let channel = &samples[start..];
+            let value = reader.get_signed_rice(k)? as i64 + prediction(channel, channel.len(), order);
+            samples.push(value as i32);
+        }
+    }
+    for sample in &mut samples[start..] {
+        *sample <<= shift;
+    }
+    Ok(())
+}
+
+// Fixed polynomial predictors, order 0 predicts silence
+fn prediction(samples: &[i32], i: usize, order: usize) -> i64 {
+    let at = |back: usize| samples[i - back] as i64;
+    match order {
+        0 => 0,
+        1 => at(1),
+        2 => 2 * at(1) - at(2),
+        _ => 3 * at(1) - 3 * at(2) + at(3),
+    }
+}
+
+fn residual(samples: &[i32], i: usize, order: usize) -> i32 {
+    (samples[i] as i64 - prediction(samples, i, order)) as i32
+}
+
+// The order with the smallest residuals, short frames get lower orders
+fn best_order(samples: &[i32]) -> usize {
+    (0..=MAX_ORDER.min(samples.len()))
+        .min_by_key(|&order| {
+            (order..samples.len())
+                .map(|i| residual(samples, i, order).unsigned_abs() as u64)
+                .sum::<u64>()
+        })
+        .unwrap_or(0)
+}
+
+// Rice parameter close to log2 of the mean folded residual
+fn rice_parameter(residuals: &[i32]) -> u32 {
+    let sum: u64 = residuals.iter().map(|&value| value.unsigned_abs() as u64 * 2).sum();
+    let mean = sum / residuals.len().max(1) as u64;
+    (u64::BITS - mean.leading_zeros()).saturating_sub(1).min(MAX_RICE_PARAMETER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::time::Duration;
+    use crate::codec::CodecId;
+
+    const SAMPLES: usize = 1000;
+
+    // Two channels of a decaying tone, integers of `bits` bits
+    fn tone(bits: u32) -> Vec<i32> {
+        let peak = ((1 << (bits - 1)) - 1) as f64;
+        (0..SAMPLES).flat_map(|i| {
+            let t = i as f64 / 48000.0;
+            let left = (t * 440.0 * std::f64::consts::TAU).sin() * peak * (1.0 - i as f64 / SAMPLES as f64);
+            [left.round() as i32, (-left / 2.0).round() as i32]
+        }).collect()
+    }
+
+    fn frame(format: SampleFormat, data: Vec<u8>) -> AudioFrame {
+        AudioFrame { format, ..AudioFrame::new(48000, 2, Duration::ZERO, Bytes::from(data)) }
+    }
+
+    fn round_trip(codec: &mut LosslessAudioCodec, frame: &AudioFrame) -> Bytes {
+        let payload = codec.encode(frame).unwrap();
+        let encoded = AudioFrame { codec: CodecId::LOSSLESS_AUDIO, data: payload.clone(), ..frame.clone() };
+        assert_eq!(codec.decode(&encoded).unwrap(), frame.data);
+        payload
+    }
+
+    #[test]
+    fn lossless_16_bit() {
+        let data = tone(16).iter().flat_map(|&s| (s as i16).to_le_bytes()).collect();
+        let frame = frame(SampleFormat::S16, data);
+        let payload = round_trip(&mut LosslessAudioCodec::new(), &frame);
+        assert_eq!(payload[1], 16);
+        assert!(payload.len() < frame.data.len());
+    }
+
+    #[test]
+    fn lossless_24_bit() {
+        let data = tone(24).iter().flat_map(|&s| s.to_le_bytes()[..3].to_vec()).collect();
+        let frame = frame(SampleFormat::S24, data);
+        let payload = round_trip(&mut LosslessAudioCodec::new(), &frame);
+        assert_eq!(payload[1], 24);
+        assert!(payload.len() < frame.data.len());
+    }
+
+    #[test]
+    fn float_on_the_integer_grid() {
+        // Floats from a 16-bit device sit exactly on the 24-bit grid too
+        let data = tone(16).iter().flat_map(|&s| (s as f32 / 32768.0).to_le_bytes()).collect();
+        let frame = frame(SampleFormat::F32, data);
+        let payload = round_trip(&mut LosslessAudioCodec::new(), &frame);
+        assert_eq!(payload[1], 24);
+        assert!(payload.len() < frame.data.len() / 2);
+    }
+
+    #[test]
+    fn float_fallback() {
+        let data = tone(24).iter().flat_map(|&s| (s as f32 / 8388608.0 + 1e-9).to_le_bytes()).collect();
+        let frame = frame(SampleFormat::F32, data);
+        let payload = round_trip(&mut LosslessAudioCodec::new(), &frame);
+        assert_eq!(payload[1], FLOAT_BITS);
+
+        // Unless asked to round
+        let payload = LosslessAudioCodec::quantizing(24).encode(&frame).unwrap();
+        assert_eq!(payload[1], 24);
+    }
+
+    #[test]
+    fn rejects_hostile_blocks() {
+        let data = tone(16).iter().flat_map(|&s| (s as i16).to_le_bytes()).collect();
+        let frame = frame(SampleFormat::S16, data);
+        let mut codec = LosslessAudioCodec::new();
+        let payload = codec.encode(&frame).unwrap();
+        let decode = |codec: &mut LosslessAudioCodec, data: Vec<u8>| {
+            codec.decode(&AudioFrame { codec: CodecId::LOSSLESS_AUDIO, data: Bytes::from(data), ..frame.clone() })
+        };
+
+        // Cut short, in the header, the block lengths and the bitstream
+        for len in [4, HEADER_LEN + 2, payload.len() / 2, payload.len() - 1] {
+            assert!(decode(&mut codec, payload[..len].to_vec()).is_err(), "{} bytes", len);
+        }
+
+        // Four billion samples claimed for a few hundred bytes of bitstream,
+        // refused before anything is allocated for them
+        let mut huge = payload.to_vec();
+        huge[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode(&mut codec, huge).is_err());
+
+        // Block lengths beyond the payload
+        let mut long = payload.to_vec();
+        long[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode(&mut codec, long).is_err());
+
+        let mut bits = payload.to_vec();
+        bits[1] = 20;
+        assert!(decode(&mut codec, bits).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::error::{Result, AqueductError};
+use crate::protocol::Packet;
+use crate::transport::Receiver;
+
+// Receiver-side jitter buffer.
+//
+// Sender timestamps are mapped onto the local clock when the first frame comes in:
+// that frame is due `target_latency` later, and every other frame is due as far
+// from it as its timestamp is from the first one. Video and audio share the
+// sender's clock, so releasing both on that schedule keeps them in sync however
+// unevenly the network delivered them.
+//
+// Frames that arrive after their due time are late. Late audio is still played,
+// late video is dropped by default since showing it would put the picture behind
+// the sound. A frame more than `resync_threshold` off its slot (the sender
+// restarted, its clock jumped, the network stalled for a long time) re-anchors the
+// schedule on that frame instead.
+//
+// Metadata isn't scheduled: tally and control messages go out as they arrive.
+
+pub const DEFAULT_TARGET_LATENCY: Duration = Duration::from_millis(80);
+pub const DEFAULT_RESYNC_THRESHOLD: Duration = Duration::from_secs(1);
+
+// Packets read ahead of the playout buffer by a Playout's reader task
+const READ_AHEAD: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayoutConfig {
+    // How long frames are held to absorb jitter
+    pub target_latency: Duration,
+    // How far off its slot a frame may be before the schedule is re-anchored
+    pub resync_threshold: Duration,
+    // Late video is dropped rather than shown behind the audio
+    pub drop_late_video: bool,
+}
+
+impl Default for PlayoutConfig {
+    fn default() -> Self {
+        Self {
+            target_latency: DEFAULT_TARGET_LATENCY,
+            resync_threshold: DEFAULT_RESYNC_THRESHOLD,
+            drop_late_video: true,
+        }
+    }
+}
+
+impl PlayoutConfig {
+    pub fn with_target_latency(target_latency: Duration) -> Self {
+        Self {
+            target_latency,
+            ..Self::default()
+        }
+    }
+}
+
+/// What a playout buffer did with the frames it was given so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlayoutStats {
+    pub released_video: u64,
+    pub released_audio: u64,
+    // Frames that arrived after their due time, whether released or dropped
+    pub late_video: u64,
+    pub late_audio: u64,
+    // Late video, and video overtaken by a newer frame before it was released
+    pub dropped_video: u64,
+    pub resyncs: u64,
+}
+
+struct Scheduled {
+    due: Instant,
+    // Arrival order, to keep frames with the same due time in order
+    sequence: u64,
+    packet: Packet,
+}
+
+impl Scheduled {
+    fn is_video(&self) -> bool {
+        matches!(self.packet, Packet::Video(_))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.sequence).cmp(&(other.due, other.sequence))
+    }
+}
+
+/// Holds received frames until their presentation time.
+///
+/// Sans-IO: the caller pushes packets as they are received and pops them when
+/// they are due, passing the current time to both. `Playout` drives one from a
+/// `Receiver`.
+pub struct PlayoutBuffer {
+    config: PlayoutConfig,
+    // Local time and sender timestamp the schedule is anchored on
+    anchor: Option<(Instant, Duration)>,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    metadata: VecDeque<Packet>,
+    sequence: u64,
+    stats: PlayoutStats,
+}
+
+impl PlayoutBuffer {
+    pub fn new(config: PlayoutConfig) -> Self {
+        Self {
+            config,
+            anchor: None,
+            queue: BinaryHeap::new(),
+            metadata: VecDeque::new(),
+            sequence: 0,
+            stats: PlayoutStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &PlayoutConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> PlayoutStats {
+        self.stats
+    }
+
+    // Frames waiting for their due time
+    pub fn len(&self) -> usize {
+        self.queue.len() + self.metadata.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, packet: Packet, now: Instant) {
+        let timestamp = match &packet {
+            Packet::Video(frame) => frame.timestamp,
+            Packet::Audio(frame) => frame.timestamp,
+            Packet::Metadata(_) => {
+                self.metadata.push_back(packet);
+                return;
+            }
+        };
+
+        let due = self.due(timestamp, now);
+        let video = matches!(packet, Packet::Video(_));
+        if due < now {
+            if video {
+                self.stats.late_video += 1;
+                if self.config.drop_late_video {
+                    log::debug!("Dropping video frame {:?} late", now - due);
+                    self.stats.dropped_video += 1;
+                    return;
+                }
+            } else {
+                self.stats.late_audio += 1;
+            }
+        }
+
+        self.sequence += 1;
+        self.queue.push(Reverse(Scheduled { due, sequence: self.sequence, packet }));
+    }
+
+    // The next packet that is due, if any
+    pub fn pop(&mut self, now: Instant) -> Option<Packet> {
+        if let Some(packet) = self.metadata.pop_front() {
+            return Some(packet);
+        }
+        loop {
+            if self.queue.peek()?.0.due > now {
+                return None;
+            }
+            let Reverse(next) = self.queue.pop()?;
+            if next.is_video() {
+                // Only the newest of several due video frames is worth showing
+                if self.queue.iter().any(|Reverse(other)| other.due <= now && other.is_video()) {
+                    self.stats.dropped_video += 1;
+                    continue;
+                }
+                self.stats.released_video += 1;
+            } else {
+                self.stats.released_audio += 1;
+            }
+            return Some(next.packet);
+        }
+    }
+
+    // When the next scheduled frame is due. Metadata is always ready to pop.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse(next)| next.due)
+    }
+
+    // Drops everything held and forgets the schedule
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.metadata.clear();
+        self.anchor = None;
+    }
+
+    fn due(&mut self, timestamp: Duration, now: Instant) -> Instant {
+        if let Some((instant, base)) = self.anchor {
+            let slot = if timestamp >= base {
+                instant.checked_add(timestamp - base)
+            } else {
+                instant.checked_sub(base - timestamp)
+            };
+            if let Some(due) = slot.and_then(|slot| slot.checked_add(self.config.target_latency)) {
+                let late = now.saturating_duration_since(due);
+                let early = due.saturating_duration_since(now + self.config.target_latency);
+                if late <= self.config.resync_threshold && early <= self.config.resync_threshold {
+                    return due;
+                }
+            }
+            log::info!("Frame at {:?} is off schedule, re-anchoring playout", timestamp);
+            self.stats.resyncs += 1;
+        }
+        self.anchor = Some((now, timestamp));
+        now + self.config.target_latency
+    }
+}
+
+/// Plays out a receiver's stream: packets come out of `next` at their
+/// presentation time rather than as they arrive.
+///
+/// The receiver is read on its own task, so dropping a `next` future never
+/// loses a packet.
+pub struct Playout {
+    buffer: PlayoutBuffer,
+    packets: mpsc::Receiver<Result<Packet>>,
+    reader: JoinHandle<()>,
+    // Why the receiver stopped, reported once the buffer has played out
+    error: Option<AqueductError>,
+}
+
+impl Playout {
+    pub fn new(mut receiver: Receiver, config: PlayoutConfig) -> Self {
+        let (tx, packets) = mpsc::channel(READ_AHEAD);
+        let reader = tokio::spawn(async move {
+            loop {
+                let received = receiver.receive().await;
+                let failed = received.is_err();
+                if tx.send(received).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self::with_reader(packets, reader, config)
+    }
+
+    fn with_reader(packets: mpsc::Receiver<Result<Packet>>, reader: JoinHandle<()>, config: PlayoutConfig) -> Self {
+        Self {
+            buffer: PlayoutBuffer::new(config),
+            packets,
+            reader,
+            error: None,
+        }
+    }
+
+    pub fn stats(&self) -> PlayoutStats {
+        self.buffer.stats()
+    }
+
+    pub async fn next(&mut self) -> Result<Packet> {
+        loop {
+            if let Some(packet) = self.buffer.pop(now()) {
+                return Ok(packet);
+            }
+            let next_due = self.buffer.next_due();
+            if self.error.is_some() {
+                // Nothing more is coming, play out what's left
+                let Some(due) = next_due else {
+                    return Err(self.error.take().unwrap_or_else(|| {
+                        AqueductError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+                    }));
+                };
+                tokio::time::sleep_until(due.into()).await;
+                continue;
+            }
+
+            let deadline = next_due.unwrap_or_else(now);
+            tokio::select! {
+                received = self.packets.recv() => match received {
+                    Some(Ok(packet)) => self.buffer.push(packet, now()),
+                    Some(Err(e)) => self.error = Some(e),
+                    None => self.error = Some(AqueductError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))),
+                },
+                _ = tokio::time::sleep_until(deadline.into()), if next_due.is_some() => {}
+            }
+        }
+    }
+}
+
+// The runtime's clock, the one Playout sleeps on
+fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+impl Drop for Playout {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::protocol::{AudioFrame, MetadataFrame, PixelFormat, VideoFrame};
+
+    const LATENCY: Duration = Duration::from_millis(80);
+
+    // A Playout fed straight from the test instead of a receiver
+    fn playout(config: PlayoutConfig) -> (mpsc::Sender<Result<Packet>>, Playout) {
+        let (tx, packets) = mpsc::channel(READ_AHEAD);
+        (tx, Playout::with_reader(packets, tokio::spawn(std::future::pending()), config))
+    }
+
+    fn video(millis: u64) -> Result<Packet> {
+        let timestamp = Duration::from_millis(millis);
+        Ok(Packet::Video(VideoFrame::new(2, 2, PixelFormat::UYVY, timestamp, Bytes::from_static(&[0; 8]))))
+    }
+
+    fn audio(millis: u64) -> Result<Packet> {
+        let timestamp = Duration::from_millis(millis);
+        Ok(Packet::Audio(AudioFrame::new(48000, 1, timestamp, Bytes::from_static(&[0; 4]))))
+    }
+
+    fn timestamp(packet: &Packet) -> Duration {
+        match packet {
+            Packet::Video(frame) => frame.timestamp,
+            Packet::Audio(frame) => frame.timestamp,
+            Packet::Metadata(frame) => frame.timestamp,
+        }
+    }
+
+    // Waits for the next packet, returning its timestamp and how far into the test it came out
+    async fn next(playout: &mut Playout, start: tokio::time::Instant) -> (Duration, Duration) {
+        let packet = playout.next().await.unwrap();
+        (timestamp(&packet), start.elapsed())
+    }
+
+    fn assert_at(released: (Duration, Duration), timestamp: u64, millis: u64) {
+        assert_eq!(released.0, Duration::from_millis(timestamp), "{:?}", released);
+        // The timer wheel rounds sleeps up to the next millisecond
+        let at = Duration::from_millis(millis);
+        assert!(released.1 >= at && released.1 - at <= Duration::from_millis(1), "{:?}", released);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_after_target_latency() {
+        let (tx, mut playout) = playout(PlayoutConfig::with_target_latency(LATENCY));
+        let start = tokio::time::Instant::now();
+        for packet in [video(1000), audio(1000), audio(1020), video(1040)] {
+            tx.send(packet).await.unwrap();
+        }
+
+        // Everything is released on the first frame's schedule, however it arrived
+        assert_at(next(&mut playout, start).await, 1000, 80);
+        assert_at(next(&mut playout, start).await, 1000, 80);
+        assert_at(next(&mut playout, start).await, 1020, 100);
+        assert_at(next(&mut playout, start).await, 1040, 120);
+
+        // Frames that arrive early wait for their slot
+        tokio::time::advance(Duration::from_millis(20)).await;
+        tx.send(audio(1200)).await.unwrap();
+        assert_at(next(&mut playout, start).await, 1200, 280);
+        assert_eq!((playout.stats().released_video, playout.stats().released_audio), (2, 3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_late_video() {
+        let (tx, mut playout) = playout(PlayoutConfig::with_target_latency(LATENCY));
+        let start = tokio::time::Instant::now();
+        tx.send(video(0)).await.unwrap();
+        assert_at(next(&mut playout, start).await, 0, 80);
+
+        // Due at 120ms but only arriving at 200ms: the video goes, the audio still plays
+        tokio::time::advance(Duration::from_millis(120)).await;
+        for packet in [video(40), audio(40), video(160)] {
+            tx.send(packet).await.unwrap();
+        }
+        assert_at(next(&mut playout, start).await, 40, 200);
+        assert_at(next(&mut playout, start).await, 160, 240);
+
+        let stats = playout.stats();
+        assert_eq!((stats.late_video, stats.dropped_video, stats.late_audio), (1, 1, 1));
+        assert_eq!((stats.released_video, stats.released_audio, stats.resyncs), (2, 1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_late_video_when_asked() {
+        let config = PlayoutConfig { drop_late_video: false, ..PlayoutConfig::with_target_latency(LATENCY) };
+        let (tx, mut playout) = playout(config);
+        let start = tokio::time::Instant::now();
+        tx.send(video(0)).await.unwrap();
+        assert_at(next(&mut playout, start).await, 0, 80);
+
+        tokio::time::advance(Duration::from_millis(120)).await;
+        tx.send(video(40)).await.unwrap();
+        assert_at(next(&mut playout, start).await, 40, 200);
+        assert_eq!((playout.stats().late_video, playout.stats().dropped_video), (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reanchors_on_timestamp_jumps() {
+        let (tx, mut playout) = playout(PlayoutConfig::with_target_latency(LATENCY));
+        let start = tokio::time::Instant::now();
+        tx.send(video(0)).await.unwrap();
+        assert_at(next(&mut playout, start).await, 0, 80);
+
+        // The sender jumped 10s ahead, then restarted from zero
+        for (timestamp, released) in [(10_000, 160), (10_040, 200), (500, 280)] {
+            tx.send(video(timestamp)).await.unwrap();
+            assert_at(next(&mut playout, start).await, timestamp, released);
+        }
+        assert_eq!(playout.stats().resyncs, 2);
+        assert_eq!(playout.stats().dropped_video, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_out_before_the_error() {
+        let (tx, mut playout) = playout(PlayoutConfig::with_target_latency(LATENCY));
+        let start = tokio::time::Instant::now();
+        tx.send(audio(0)).await.unwrap();
+        tx.send(Ok(Packet::Metadata(MetadataFrame { timestamp: Duration::from_millis(50), content: "<tally />".into() })))
+            .await
+            .unwrap();
+        drop(tx);
+
+        // Metadata isn't held back, the audio is, and the end of the stream comes last
+        assert_at(next(&mut playout, start).await, 50, 0);
+        assert_at(next(&mut playout, start).await, 0, 80);
+        assert!(matches!(playout.next().await, Err(AqueductError::Io(_))));
+    }
+}
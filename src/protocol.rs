@@ -1,5 +1,7 @@
 use bytes::Bytes;
 use std::time::Duration;
+use crate::wire::fourcc;
+use crate::codec::CodecId;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -17,6 +19,10 @@ pub enum PixelFormat {
 }
 
 impl PixelFormat {
+    pub const ALL: [PixelFormat; 7] = [
+        Self::UYVY, Self::UYVA, Self::BGRA, Self::NV12, Self::YV12, Self::P216, Self::PA16,
+    ];
+
     pub fn from_u8(n: u8) -> Option<Self> {
         match n {
             0 => Some(Self::UYVY),
@@ -29,41 +35,402 @@ impl PixelFormat {
             _ => None,
         }
     }
+
+    // FourCC used for this format in the OMT video header
+    pub fn fourcc(self) -> u32 {
+        match self {
+            Self::UYVY => fourcc(b"UYVY"),
+            Self::UYVA => fourcc(b"UYVA"),
+            Self::BGRA => fourcc(b"BGRA"),
+            Self::NV12 => fourcc(b"NV12"),
+            Self::YV12 => fourcc(b"YV12"),
+            Self::P216 => fourcc(b"P216"),
+            Self::PA16 => fourcc(b"PA16"),
+        }
+    }
+
+    pub fn from_fourcc(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.fourcc() == code)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UYVY => "UYVY",
+            Self::UYVA => "UYVA",
+            Self::BGRA => "BGRA",
+            Self::NV12 => "NV12",
+            Self::YV12 => "YV12",
+            Self::P216 => "P216",
+            Self::PA16 => "PA16",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.name().eq_ignore_ascii_case(name))
+    }
+
+    // Bytes per line of the first plane when lines are tightly packed. Saturates
+    // for absurd widths, see `checked_stride`.
+    pub fn default_stride(self, width: u32) -> u32 {
+        self.checked_stride(width).unwrap_or(u32::MAX)
+    }
+
+    // None when the stride doesn't fit in a u32
+    pub fn checked_stride(self, width: u32) -> Option<u32> {
+        match self {
+            // 4:2:2 packs two pixels per macropixel, round odd widths up
+            Self::UYVY | Self::UYVA => width.div_ceil(2).checked_mul(4),
+            Self::BGRA => width.checked_mul(4),
+            // Planar formats keep chroma pairs whole, round odd widths up as well
+            Self::NV12 | Self::YV12 => width.div_ceil(2).checked_mul(2),
+            Self::P216 | Self::PA16 => width.div_ceil(2).checked_mul(4),
+        }
+    }
+
+    // Total bytes of a frame, `stride` being the stride of the first plane.
+    // Secondary planes (alpha, chroma) follow with strides derived from it.
+    // Saturates for absurd sizes, see `checked_frame_size`.
+    pub fn frame_size(self, height: u32, stride: u32) -> usize {
+        self.checked_frame_size(height, stride).unwrap_or(usize::MAX)
+    }
+
+    // None when the size doesn't fit in a usize
+    pub fn checked_frame_size(self, height: u32, stride: u32) -> Option<usize> {
+        let h = height as usize;
+        let stride = stride as usize;
+        let chroma_lines = height.div_ceil(2) as usize;
+        let plane = stride.checked_mul(h)?;
+        match self {
+            Self::UYVY | Self::BGRA => Some(plane),
+            Self::UYVA => plane.checked_add((stride / 2).checked_mul(h)?),
+            Self::NV12 => plane.checked_add(stride.checked_mul(chroma_lines)?),
+            Self::YV12 => plane.checked_add((stride / 2).checked_mul(chroma_lines)?.checked_mul(2)?),
+            Self::P216 => plane.checked_mul(2),
+            Self::PA16 => plane.checked_mul(3),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct FrameFlags {
-    pub alpha: bool,
-    pub premultiplied: bool,
-    pub high_bit_depth: bool,
+#[repr(u8)]
+pub enum ColorMatrix {
+    Bt601 = 0,
+    Bt709 = 1,
+    Bt2020 = 2,
 }
 
-impl Default for FrameFlags {
-    fn default() -> Self {
-        Self {
-            alpha: false,
-            premultiplied: false,
-            high_bit_depth: false,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ColorRange {
+    Limited = 0, // 16-235 luma, 16-240 chroma (8-bit)
+    Full = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TransferFunction {
+    Sdr = 0, // BT.709 / BT.1886
+    Pq = 1,  // SMPTE ST 2084
+    Hlg = 2, // ARIB STD-B67
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ColorPrimaries {
+    Bt601 = 0,
+    Bt709 = 1,
+    Bt2020 = 2,
+}
+
+impl ColorMatrix {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Bt601),
+            1 => Some(Self::Bt709),
+            2 => Some(Self::Bt2020),
+            _ => None,
+        }
+    }
+}
+
+impl ColorRange {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Limited),
+            1 => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+impl TransferFunction {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Sdr),
+            1 => Some(Self::Pq),
+            2 => Some(Self::Hlg),
+            _ => None,
+        }
+    }
+}
+
+impl ColorPrimaries {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Bt601),
+            1 => Some(Self::Bt709),
+            2 => Some(Self::Bt2020),
+            _ => None,
         }
     }
 }
 
+/// How the samples of a frame map to colours. The matrix and range describe
+/// YUV formats; BGRA is always full range RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colorimetry {
+    pub matrix: ColorMatrix,
+    pub range: ColorRange,
+    pub transfer: TransferFunction,
+    pub primaries: ColorPrimaries,
+}
+
+impl Colorimetry {
+    pub const BT601: Colorimetry = Colorimetry {
+        matrix: ColorMatrix::Bt601,
+        range: ColorRange::Limited,
+        transfer: TransferFunction::Sdr,
+        primaries: ColorPrimaries::Bt601,
+    };
+
+    pub const BT709: Colorimetry = Colorimetry {
+        matrix: ColorMatrix::Bt709,
+        range: ColorRange::Limited,
+        transfer: TransferFunction::Sdr,
+        primaries: ColorPrimaries::Bt709,
+    };
+
+    pub const BT2020: Colorimetry = Colorimetry {
+        matrix: ColorMatrix::Bt2020,
+        range: ColorRange::Limited,
+        transfer: TransferFunction::Sdr,
+        primaries: ColorPrimaries::Bt2020,
+    };
+
+    // Conventional default when a source doesn't say: BT.601 for SD, BT.709 otherwise
+    pub fn for_height(height: u32) -> Self {
+        if height < 720 {
+            Self::BT601
+        } else {
+            Self::BT709
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRate {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl FrameRate {
+    // 0/1 is used when the source does not have a fixed rate
+    pub const UNKNOWN: FrameRate = FrameRate::new(0, 1);
+    pub const FPS_23_976: FrameRate = FrameRate::new(24000, 1001);
+    pub const FPS_29_97: FrameRate = FrameRate::new(30000, 1001);
+    pub const FPS_59_94: FrameRate = FrameRate::new(60000, 1001);
+
+    pub const fn new(numerator: u32, denominator: u32) -> Self {
+        Self { numerator, denominator }
+    }
+
+    pub fn as_f64(self) -> f64 {
+        if self.denominator == 0 {
+            return 0.0;
+        }
+        self.numerator as f64 / self.denominator as f64
+    }
+
+    pub fn frame_duration(self) -> Option<Duration> {
+        if self.numerator == 0 || self.denominator == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos(self.denominator as u64 * 1_000_000_000 / self.numerator as u64))
+    }
+
+    pub fn is_known(self) -> bool {
+        self.numerator != 0 && self.denominator != 0
+    }
+
+    // Exact start of a frame counted from frame 0, without the rounding that
+    // adding up frame durations accumulates
+    pub fn frame_offset(self, frame: u64) -> Duration {
+        if !self.is_known() {
+            return Duration::ZERO;
+        }
+        let nanos = frame as u128 * self.denominator as u128 * 1_000_000_000 / self.numerator as u128;
+        Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+
+    // Number of whole frames started `elapsed` after frame 0
+    pub fn frames_in(self, elapsed: Duration) -> u64 {
+        if !self.is_known() {
+            return 0;
+        }
+        let frames = elapsed.as_nanos() * self.numerator as u128 / (self.denominator as u128 * 1_000_000_000);
+        frames.min(u64::MAX as u128) as u64
+    }
+
+    // Audio samples belonging to a frame. Rates that don't divide the sample rate
+    // alternate so the total never drifts, 29.97 at 48 kHz gives 1601 or 1602.
+    pub fn samples_in_frame(self, frame: u64, sample_rate: u32) -> usize {
+        if !self.is_known() {
+            return 0;
+        }
+        let samples_before = |frame: u64| {
+            frame as u128 * sample_rate as u128 * self.denominator as u128 / self.numerator as u128
+        };
+        (samples_before(frame + 1) - samples_before(frame)) as usize
+    }
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        Self::UNKNOWN
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameFlags {
+    pub alpha: bool,
+    pub premultiplied: bool,
+    pub high_bit_depth: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct VideoFrame {
     pub width: u32,
     pub height: u32,
-    pub format: PixelFormat,
+    pub format: PixelFormat, // Format of the decoded picture
+    pub codec: CodecId, // How `data` is encoded, UNCOMPRESSED for raw pixels
     pub flags: FrameFlags,
+    pub frame_rate: FrameRate,
+    pub pixel_aspect_ratio: f32,
+    pub stride: u32, // Bytes per line of the first plane
+    pub colorimetry: Colorimetry,
     pub timestamp: Duration,
     pub data: Bytes,
 }
 
+impl VideoFrame {
+    // Tightly packed frame with square pixels, no frame rate information and the
+    // default colorimetry for its size
+    pub fn new(width: u32, height: u32, format: PixelFormat, timestamp: Duration, data: Bytes) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            codec: CodecId::UNCOMPRESSED,
+            flags: FrameFlags::default(),
+            frame_rate: FrameRate::UNKNOWN,
+            pixel_aspect_ratio: 1.0,
+            stride: format.default_stride(width),
+            colorimetry: Colorimetry::for_height(height),
+            timestamp,
+            data,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.codec != CodecId::UNCOMPRESSED
+    }
+
+    // Display aspect ratio, as carried in the OMT video header
+    pub fn display_aspect_ratio(&self) -> f32 {
+        if self.height == 0 {
+            return 0.0;
+        }
+        self.width as f32 / self.height as f32 * self.pixel_aspect_ratio
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SampleFormat {
+    F32 = 0, // 32-bit float, -1.0 to 1.0
+    S16 = 1, // 16-bit signed integer
+    S24 = 2, // 24-bit signed integer, packed in 3 bytes
+}
+
+impl SampleFormat {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::F32),
+            1 => Some(Self::S16),
+            2 => Some(Self::S24),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::S16 => 2,
+            Self::S24 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChannelLayout {
+    Planar = 0, // One contiguous block per channel, as OMT sends it
+    Interleaved = 1, // One sample of every channel after the other
+}
+
+impl ChannelLayout {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Planar),
+            1 => Some(Self::Interleaved),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AudioFrame {
     pub sample_rate: u32,
     pub channels: u32,
+    pub format: SampleFormat, // Format of the decoded samples
+    pub channel_layout: ChannelLayout,
+    pub codec: CodecId, // How `data` is encoded, UNCOMPRESSED for plain samples
     pub timestamp: Duration,
-    pub data: Bytes, // 32-bit float samples
+    pub data: Bytes,
+}
+
+impl AudioFrame {
+    // Interleaved 32-bit float samples
+    pub fn new(sample_rate: u32, channels: u32, timestamp: Duration, data: Bytes) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            format: SampleFormat::F32,
+            channel_layout: ChannelLayout::Interleaved,
+            codec: CodecId::UNCOMPRESSED,
+            timestamp,
+            data,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.codec != CodecId::UNCOMPRESSED
+    }
+
+    // Samples per channel of an uncompressed frame
+    pub fn samples_per_channel(&self) -> usize {
+        self.data.len() / self.format.bytes_per_sample() / self.channels.max(1) as usize
+    }
 }
 
 #[derive(Debug, Clone)]
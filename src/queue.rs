@@ -0,0 +1,351 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use crate::wire::FrameType;
+
+// Each receiver connection has its own queue of packets waiting to be written,
+// so a slow receiver only ever affects itself. When a queue backs up, video is
+// dropped according to the policy. Audio and metadata are never dropped: losing
+// them is audible or breaks tally and control, and they are small next to video.
+// A receiver that can't even keep up with those falls further and further behind
+// and is disconnected once its oldest queued packet is `disconnect_after` old.
+//
+// With inter-frame codecs a dropped frame takes the frames that build on it along:
+// they are dropped up to the next keyframe, so the receiver freezes instead of
+// showing garbage, and the queue asks for a keyframe to resume.
+
+pub const DEFAULT_VIDEO_DEPTH: usize = 16;
+pub const DEFAULT_DISCONNECT_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VideoPolicy {
+    // Queue up to `video_depth` frames, dropping the oldest when full
+    #[default]
+    DropOldest,
+    // Queue a single frame, replaced by each new one. Lowest latency for
+    // multiviewers and previews, at the cost of frame rate when congested.
+    LatestOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueuePolicy {
+    pub video: VideoPolicy,
+    pub video_depth: usize,
+    // None keeps slow receivers connected however far behind they get
+    pub disconnect_after: Option<Duration>,
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        Self {
+            video: VideoPolicy::DropOldest,
+            video_depth: DEFAULT_VIDEO_DEPTH,
+            disconnect_after: Some(DEFAULT_DISCONNECT_AFTER),
+        }
+    }
+}
+
+impl QueuePolicy {
+    pub fn latest_video_only() -> Self {
+        Self {
+            video: VideoPolicy::LatestOnly,
+            ..Self::default()
+        }
+    }
+
+    fn video_limit(&self) -> usize {
+        match self.video {
+            VideoPolicy::DropOldest => self.video_depth.max(1),
+            VideoPolicy::LatestOnly => 1,
+        }
+    }
+}
+
+/// Counters of one receiver connection's queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub sent_video: u64,
+    pub sent_audio: u64,
+    pub sent_metadata: u64,
+    pub bytes_sent: u64,
+    pub dropped_video: u64,
+    // Packets waiting to be written
+    pub queued: usize,
+    // How long the oldest of them has been waiting
+    pub behind: Duration,
+}
+
+struct Queued<T> {
+    item: T,
+    frame_type: FrameType,
+    // Video that only decodes on top of the frames before it
+    dependent: bool,
+    queued_at: Instant,
+}
+
+pub(crate) struct SendQueue<T> {
+    policy: QueuePolicy,
+    items: VecDeque<Queued<T>>,
+    video: usize,
+    chain: Chain,
+    keyframe_wanted: bool,
+    stats: QueueStats,
+}
+
+// Whether queued video still decodes after drops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chain {
+    Intact,
+    // The last queued frame was dropped, a dependent frame pushed next can't be decoded
+    Broken,
+    // Dependent frames are being dropped, a keyframe was asked for
+    AwaitingKeyframe,
+}
+
+impl<T> SendQueue<T> {
+    pub(crate) fn new(policy: QueuePolicy) -> Self {
+        Self {
+            policy,
+            items: VecDeque::new(),
+            video: 0,
+            chain: Chain::Intact,
+            keyframe_wanted: false,
+            stats: QueueStats::default(),
+        }
+    }
+
+    // Takes effect with the next push
+    pub(crate) fn set_policy(&mut self, policy: QueuePolicy) {
+        self.policy = policy;
+    }
+
+    // False once the receiver is too far behind and should be disconnected
+    pub(crate) fn push(&mut self, item: T, frame_type: FrameType, dependent: bool, now: Instant) -> bool {
+        if frame_type == FrameType::Video {
+            while self.video >= self.policy.video_limit() {
+                let Some(oldest) = self.items.iter().position(|queued| queued.frame_type == FrameType::Video) else {
+                    break;
+                };
+                self.drop_video_from(oldest);
+            }
+            if dependent && self.chain != Chain::Intact {
+                self.stats.dropped_video += 1;
+                if self.chain == Chain::Broken {
+                    self.chain = Chain::AwaitingKeyframe;
+                    self.keyframe_wanted = true;
+                }
+                return self.policy.disconnect_after.is_none_or(|limit| self.behind(now) <= limit);
+            }
+            self.chain = Chain::Intact;
+            self.video += 1;
+        }
+        self.items.push_back(Queued { item, frame_type, dependent, queued_at: now });
+        self.policy.disconnect_after.is_none_or(|limit| self.behind(now) <= limit)
+    }
+
+    // Drops the video frame at `index` along with the queued frames that depend on it
+    fn drop_video_from(&mut self, index: usize) {
+        self.items.remove(index);
+        self.video -= 1;
+        self.stats.dropped_video += 1;
+        loop {
+            let next = self.items.iter().skip(index).position(|queued| queued.frame_type == FrameType::Video);
+            match next.map(|offset| index + offset) {
+                Some(next) if self.items[next].dependent => {
+                    self.items.remove(next);
+                    self.video -= 1;
+                    self.stats.dropped_video += 1;
+                }
+                // A keyframe, the chain continues from there
+                Some(_) => return,
+                None => break,
+            }
+        }
+        if self.chain == Chain::Intact {
+            self.chain = Chain::Broken;
+        }
+    }
+
+    // True once each time dependent video had to be dropped, the encoder should
+    // send a keyframe so the receiver can resume
+    pub(crate) fn take_keyframe_request(&mut self) -> bool {
+        std::mem::take(&mut self.keyframe_wanted)
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let queued = self.items.pop_front()?;
+        if queued.frame_type == FrameType::Video {
+            self.video -= 1;
+        }
+        Some(queued.item)
+    }
+
+    // The oldest queued audio or metadata, which may overtake video
+    pub(crate) fn pop_urgent(&mut self) -> Option<T> {
+        let index = self.items.iter().position(|queued| queued.frame_type != FrameType::Video)?;
+        self.items.remove(index).map(|queued| queued.item)
+    }
+
+    // Counts a packet once it was written to the receiver. Chunks only add their
+    // bytes, the frame is counted as video once all of it went out.
+    pub(crate) fn record_sent(&mut self, frame_type: FrameType, len: usize) {
+        match frame_type {
+            FrameType::Video => self.stats.sent_video += 1,
+            FrameType::Chunk => {}
+            FrameType::Audio => self.stats.sent_audio += 1,
+            FrameType::Metadata => self.stats.sent_metadata += 1,
+        }
+        self.stats.bytes_sent += len as u64;
+    }
+
+    pub(crate) fn behind(&self, now: Instant) -> Duration {
+        self.items.front().map_or(Duration::ZERO, |oldest| now.saturating_duration_since(oldest.queued_at))
+    }
+
+    pub(crate) fn stats(&self, now: Instant) -> QueueStats {
+        QueueStats {
+            queued: self.items.len(),
+            behind: self.behind(now),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: bool = false;
+    const DELTA: bool = true;
+
+    fn queue(video: VideoPolicy, video_depth: usize) -> SendQueue<&'static str> {
+        SendQueue::new(QueuePolicy { video, video_depth, disconnect_after: None })
+    }
+
+    fn push(queue: &mut SendQueue<&'static str>, items: &[(&'static str, FrameType, bool)]) {
+        let now = Instant::now();
+        for &(item, frame_type, dependent) in items {
+            assert!(queue.push(item, frame_type, dependent, now));
+        }
+    }
+
+    fn drain(queue: &mut SendQueue<&'static str>) -> Vec<&'static str> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn drops_oldest_video() {
+        let mut queue = queue(VideoPolicy::DropOldest, 3);
+        let frames = ["v1", "v2", "v3", "v4", "v5"].map(|item| (item, FrameType::Video, KEY));
+        push(&mut queue, &frames);
+        assert_eq!(drain(&mut queue), ["v3", "v4", "v5"]);
+        assert_eq!(queue.stats(Instant::now()).dropped_video, 2);
+    }
+
+    #[test]
+    fn keeps_latest_video_only() {
+        let mut queue = queue(VideoPolicy::LatestOnly, 16);
+        push(&mut queue, &[
+            ("v1", FrameType::Video, KEY),
+            ("a1", FrameType::Audio, false),
+            ("v2", FrameType::Video, KEY),
+            ("m1", FrameType::Metadata, false),
+            ("v3", FrameType::Video, KEY),
+        ]);
+        assert_eq!(drain(&mut queue), ["a1", "m1", "v3"]);
+        assert_eq!(queue.stats(Instant::now()).dropped_video, 2);
+    }
+
+    #[test]
+    fn never_drops_audio_or_metadata() {
+        let mut queue = queue(VideoPolicy::LatestOnly, 1);
+        for _ in 0..100 {
+            push(&mut queue, &[
+                ("video", FrameType::Video, KEY),
+                ("audio", FrameType::Audio, false),
+                ("metadata", FrameType::Metadata, false),
+            ]);
+        }
+        let stats = queue.stats(Instant::now());
+        assert_eq!((stats.queued, stats.dropped_video), (201, 99));
+
+        // Audio and metadata may overtake the queued video
+        assert_eq!(queue.pop_urgent(), Some("audio"));
+        assert_eq!(queue.pop_urgent(), Some("metadata"));
+        let drained = drain(&mut queue);
+        assert_eq!(drained.iter().filter(|&&item| item == "audio").count(), 99);
+        assert_eq!(drained.iter().filter(|&&item| item == "metadata").count(), 99);
+        assert_eq!(drained.iter().filter(|&&item| item == "video").count(), 1);
+    }
+
+    #[test]
+    fn drops_dependents_up_to_the_next_keyframe() {
+        let mut queue = queue(VideoPolicy::DropOldest, 4);
+        push(&mut queue, &[
+            ("k1", FrameType::Video, KEY),
+            ("d2", FrameType::Video, DELTA),
+            ("a1", FrameType::Audio, false),
+            ("d3", FrameType::Video, DELTA),
+            ("k4", FrameType::Video, KEY),
+            ("d5", FrameType::Video, DELTA),
+        ]);
+        // Dropping k1 takes d2 and d3 along, d5 still decodes on top of k4
+        assert_eq!(drain(&mut queue), ["a1", "k4", "d5"]);
+        assert_eq!(queue.stats(Instant::now()).dropped_video, 3);
+        assert!(!queue.take_keyframe_request());
+    }
+
+    #[test]
+    fn asks_for_a_keyframe_when_the_chain_breaks() {
+        let mut queue = queue(VideoPolicy::DropOldest, 2);
+        push(&mut queue, &[
+            ("k1", FrameType::Video, KEY),
+            ("d2", FrameType::Video, DELTA),
+            ("d3", FrameType::Video, DELTA),
+        ]);
+        // Nothing left to build d3 on: it's dropped too, and so is every delta until a keyframe
+        assert!(queue.take_keyframe_request());
+        assert!(!queue.take_keyframe_request());
+        push(&mut queue, &[
+            ("d4", FrameType::Video, DELTA),
+            ("a1", FrameType::Audio, false),
+            ("k5", FrameType::Video, KEY),
+            ("d6", FrameType::Video, DELTA),
+        ]);
+        assert!(!queue.take_keyframe_request());
+        assert_eq!(drain(&mut queue), ["a1", "k5", "d6"]);
+        assert_eq!(queue.stats(Instant::now()).dropped_video, 4);
+    }
+
+    #[test]
+    fn disconnects_past_the_limit() {
+        let policy = QueuePolicy { disconnect_after: Some(Duration::from_secs(1)), ..QueuePolicy::default() };
+        let mut queue = SendQueue::new(policy);
+        let start = Instant::now();
+        assert!(queue.push("a1", FrameType::Audio, false, start));
+        assert!(queue.push("a2", FrameType::Audio, false, start + Duration::from_millis(500)));
+        assert!(queue.push("a3", FrameType::Audio, false, start + Duration::from_secs(1)));
+        // a1 has now waited longer than allowed
+        let late = start + Duration::from_millis(1500);
+        assert!(!queue.push("a4", FrameType::Audio, false, late));
+        assert_eq!(queue.stats(late).behind, Duration::from_millis(1500));
+
+        // Once the old packets went out it's back within the limit
+        queue.pop();
+        queue.pop();
+        assert!(queue.push("a5", FrameType::Audio, false, late));
+        queue.set_policy(QueuePolicy { disconnect_after: None, ..policy });
+        assert!(queue.push("a6", FrameType::Audio, false, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn counts_sent_packets() {
+        let mut queue = queue(VideoPolicy::DropOldest, 4);
+        queue.record_sent(FrameType::Chunk, 1000);
+        queue.record_sent(FrameType::Video, 200);
+        queue.record_sent(FrameType::Audio, 100);
+        queue.record_sent(FrameType::Metadata, 10);
+        let stats = queue.stats(Instant::now());
+        assert_eq!((stats.sent_video, stats.sent_audio, stats.sent_metadata), (1, 1, 1));
+        assert_eq!((stats.bytes_sent, stats.queued, stats.behind), (1310, 0, Duration::ZERO));
+    }
+}
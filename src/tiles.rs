@@ -0,0 +1,271 @@
+use bytes::{BufMut, BytesMut};
+use crate::codec::{EncoderStats, Lz4Codec, VideoDecoder, VideoEncoder};
+use crate::error::{Result, AqueductError};
+use crate::protocol::{VideoFrame, PixelFormat};
+
+// Dirty-rectangle tile codec for screen capture sources.
+//
+// Frames are cut into square tiles and only the tiles that changed since the
+// previous frame are sent, with their position. The decoder keeps the last frame
+// as a canvas and paints the tiles onto it, so an idle desktop costs a few bytes
+// per frame. Works on the packed single plane formats (BGRA, UYVY).
+//
+// Payload:
+//
+//   [Kind: u8][Reserved: u8][Tile Size: u16][Sequence: u32][Tiles: u32]
+//   Tiles x [Column: u16][Row: u16]
+//   then an LZ4 payload with the pixels of those tiles, line by line, in order.
+//
+// Tiles on the right and bottom edges are clipped to the frame. A full frame
+// (Kind 0) carries every tile and resets the canvas, it is sent for the first
+// frame, on size or format changes and when a keyframe is requested.
+
+const KIND_FULL: u8 = 0;
+const KIND_PARTIAL: u8 = 1;
+const HEADER_LEN: usize = 12;
+
+pub const DEFAULT_TILE_SIZE: u16 = 64;
+
+// Frame geometry the canvas was built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Grid {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    stride: usize,
+    tile_size: usize,
+}
+
+impl Grid {
+    fn new(frame: &VideoFrame, tile_size: usize) -> Result<Self> {
+        if !matches!(frame.format, PixelFormat::BGRA | PixelFormat::UYVY) {
+            return Err(AqueductError::Codec(format!("Tile codec can't code {:?}", frame.format)));
+        }
+        if tile_size == 0 || !tile_size.is_multiple_of(2) {
+            return Err(AqueductError::Codec(format!("Invalid tile size: {}", tile_size)));
+        }
+        let grid = Self {
+            width: frame.width as usize,
+            height: frame.height as usize,
+            format: frame.format,
+            stride: frame.stride as usize,
+            tile_size,
+        };
+        if grid.stride < grid.line_bytes(grid.width) {
+            return Err(AqueductError::Codec(format!("Stride {} too small for {:?}", grid.stride, grid.format)));
+        }
+        Ok(grid)
+    }
+
+    fn frame_size(&self) -> usize {
+        self.stride * self.height
+    }
+
+    fn columns(&self) -> usize {
+        self.width.div_ceil(self.tile_size)
+    }
+
+    fn rows(&self) -> usize {
+        self.height.div_ceil(self.tile_size)
+    }
+
+    // Bytes covering `pixels` pixels of a line, UYVY packs pixels in pairs
+    fn line_bytes(&self, pixels: usize) -> usize {
+        match self.format {
+            PixelFormat::UYVY => pixels.div_ceil(2) * 4,
+            _ => pixels * 4,
+        }
+    }
+
+    // Byte range within each line and the lines a tile covers
+    fn tile(&self, column: usize, row: usize) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let x = column * self.tile_size;
+        let y = row * self.tile_size;
+        let start = self.line_bytes(x);
+        let end = self.line_bytes((x + self.tile_size).min(self.width));
+        (start..end, y..(y + self.tile_size).min(self.height))
+    }
+}
+
+pub struct TileCodec {
+    tile_size: u16,
+    lz4: Lz4Codec,
+    canvas: Vec<u8>,
+    grid: Option<Grid>,
+    sequence: u32,
+    keyframe_requested: bool,
+    // The last encoded frame carried every tile
+    full: bool,
+    tiles: BytesMut,
+}
+
+impl Default for TileCodec {
+    fn default() -> Self {
+        Self::with_tile_size(DEFAULT_TILE_SIZE)
+    }
+}
+
+impl TileCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Tile edge in pixels, must be even
+    pub fn with_tile_size(tile_size: u16) -> Self {
+        Self {
+            tile_size,
+            lz4: Lz4Codec::new(),
+            canvas: Vec::new(),
+            grid: None,
+            sequence: 0,
+            keyframe_requested: false,
+            full: false,
+            tiles: BytesMut::new(),
+        }
+    }
+
+    // The frame the decoder has reconstructed so far, laid out like the last frame it decoded
+    pub fn canvas(&self) -> &[u8] {
+        &self.canvas
+    }
+}
+
+impl VideoEncoder for TileCodec {
+    fn encode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        let grid = Grid::new(frame, self.tile_size as usize)?;
+        if frame.data.len() < grid.frame_size() {
+            return Err(AqueductError::Codec("Frame data too short".to_string()));
+        }
+        let data = &frame.data[..grid.frame_size()];
+        let full = self.keyframe_requested || self.grid != Some(grid);
+        self.full = full;
+        if full {
+            self.canvas.clear();
+            self.canvas.extend_from_slice(data);
+            self.grid = Some(grid);
+            self.keyframe_requested = false;
+        }
+
+        // Collect changed tiles, updating the reference as we go
+        let mut positions = Vec::new();
+        self.tiles.clear();
+        for row in 0..grid.rows() {
+            for column in 0..grid.columns() {
+                let (bytes, lines) = grid.tile(column, row);
+                let changed = full || lines.clone().any(|y| {
+                    let line = y * grid.stride;
+                    data[line + bytes.start..line + bytes.end] != self.canvas[line + bytes.start..line + bytes.end]
+                });
+                if !changed {
+                    continue;
+                }
+                positions.push((column as u16, row as u16));
+                for y in lines {
+                    let line = y * grid.stride;
+                    let src = &data[line + bytes.start..line + bytes.end];
+                    self.tiles.put_slice(src);
+                    self.canvas[line + bytes.start..line + bytes.end].copy_from_slice(src);
+                }
+            }
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        dst.reserve(HEADER_LEN + positions.len() * 4);
+        dst.put_u8(if full { KIND_FULL } else { KIND_PARTIAL });
+        dst.put_u8(0);
+        dst.put_u16_le(self.tile_size);
+        dst.put_u32_le(self.sequence);
+        dst.put_u32_le(positions.len() as u32);
+        for (column, row) in positions {
+            dst.put_u16_le(column);
+            dst.put_u16_le(row);
+        }
+
+        let tile_line = grid.line_bytes(grid.tile_size) as u32;
+        let tiles = VideoFrame {
+            stride: tile_line,
+            data: self.tiles.split().freeze(),
+            ..frame.clone()
+        };
+        self.lz4.encode_into(&tiles, dst)
+    }
+
+    fn stats(&self) -> Option<EncoderStats> {
+        self.lz4.stats()
+    }
+
+    fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    fn is_keyframe(&self) -> bool {
+        self.full
+    }
+}
+
+impl VideoDecoder for TileCodec {
+    fn decode_into(&mut self, frame: &VideoFrame, dst: &mut BytesMut) -> Result<()> {
+        let data = &frame.data;
+        if data.len() < HEADER_LEN {
+            return Err(AqueductError::Codec("Tile payload too short".to_string()));
+        }
+        let kind = data[0];
+        let tile_size = u16::from_le_bytes([data[2], data[3]]) as usize;
+        let sequence = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let count = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
+        let table_end = count.checked_mul(4)
+            .and_then(|len| len.checked_add(HEADER_LEN))
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| AqueductError::Codec("Tile table truncated".to_string()))?;
+
+        let grid = Grid::new(frame, tile_size)?;
+        match kind {
+            KIND_FULL => {
+                self.canvas.clear();
+                self.canvas.resize(grid.frame_size(), 0);
+                self.grid = Some(grid);
+            }
+            KIND_PARTIAL => {
+                if self.grid != Some(grid) || self.sequence.wrapping_add(1) != sequence {
+                    // Stay broken until a full frame, later updates don't apply either
+                    self.grid = None;
+                    return Err(AqueductError::KeyframeRequired);
+                }
+            }
+            other => return Err(AqueductError::Codec(format!("Unknown tile frame kind: {}", other))),
+        }
+
+        self.tiles.clear();
+        let pixels = VideoFrame {
+            data: data.slice(table_end..),
+            ..frame.clone()
+        };
+        self.lz4.decode_into(&pixels, &mut self.tiles)?;
+
+        // Paint the tiles onto the canvas
+        let mut offset = 0;
+        for position in data[HEADER_LEN..table_end].chunks_exact(4) {
+            let column = u16::from_le_bytes([position[0], position[1]]) as usize;
+            let row = u16::from_le_bytes([position[2], position[3]]) as usize;
+            if column >= grid.columns() || row >= grid.rows() {
+                self.grid = None;
+                return Err(AqueductError::Codec(format!("Tile {},{} outside the frame", column, row)));
+            }
+            let (bytes, lines) = grid.tile(column, row);
+            let tile_bytes = bytes.len() * lines.len();
+            let Some(src) = self.tiles.get(offset..offset + tile_bytes) else {
+                self.grid = None;
+                return Err(AqueductError::Codec("Tile pixels truncated".to_string()));
+            };
+            for (y, src_line) in lines.zip(src.chunks_exact(bytes.len())) {
+                let line = y * grid.stride;
+                self.canvas[line + bytes.start..line + bytes.end].copy_from_slice(src_line);
+            }
+            offset += tile_bytes;
+        }
+
+        self.sequence = sequence;
+        dst.extend_from_slice(&self.canvas);
+        Ok(())
+    }
+}
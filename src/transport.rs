@@ -1,67 +1,312 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::protocol::{Packet, VideoFrame, AudioFrame, MetadataFrame, PixelFormat, FrameFlags};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+use crate::protocol::{AudioFrame, Packet, SampleFormat};
+use crate::audio_convert::convert_audio_frame;
 use crate::error::{Result, AqueductError};
-use bytes::{BytesMut, Buf};
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use crate::framing::{encode_chunk_header, OmtCodec};
+use crate::handshake::{Capabilities, HandshakeMessage, Negotiated, negotiate, omt_subscriptions, HANDSHAKE_TIMEOUT};
+use crate::control::ControlMessage;
+use crate::playout::{Playout, PlayoutConfig};
+use crate::clock::{self, ClockEstimate, ClockSync};
+use crate::queue::{QueuePolicy, QueueStats, SendQueue};
+use crate::config::{bind_listener, SenderConfig, SocketOptions};
+use crate::wire::FrameType;
+use bytes::{Buf, Bytes, BytesMut};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use tokio::sync::{mpsc, Notify};
 use log::{info, error};
 
-// Simple header: [Type: u8] [Length: u32]
-// Types: 0x01 = Video, 0x02 = Audio, 0x03 = Metadata
-
-const TYPE_VIDEO: u8 = 0x01;
-const TYPE_AUDIO: u8 = 0x02;
-const TYPE_METADATA: u8 = 0x03;
-
-use crate::codec::{VideoEncoder, VideoDecoder, Lz4Codec};
+use std::collections::HashMap;
+use crate::codec::{CodecId, CodecRegistry, EncoderStats, VideoEncoder, VideoDecoder, AudioEncoder, AudioDecoder};
 
 #[derive(Clone)]
 pub struct Sender {
-    tx: broadcast::Sender<Arc<Packet>>,
-    compression_buffer: Arc<std::sync::Mutex<BytesMut>>,
+    connections: Arc<std::sync::Mutex<Connections>>,
+    registry: Arc<CodecRegistry>,
+    encoder: Arc<std::sync::Mutex<EncoderState>>,
+    audio_encoder: Arc<std::sync::Mutex<AudioEncoderState>>,
+    // Started by the first `enqueue` or `try_send`
+    workers: Arc<OnceLock<EncodeWorkers>>,
+    encode_queue_depth: usize,
+    local_addr: SocketAddr,
+}
+
+// A packet as it goes out, serialized once by `Sender::send` for every receiver.
+// The packet is kept to decide per receiver whether and how it can be sent.
+struct EncodedPacket {
+    packet: Packet,
+    frame_type: FrameType,
+    bytes: Bytes,
+}
+
+// Receivers a sender is writing to, and the policy their queues follow
+struct Connections {
+    policy: QueuePolicy,
+    max_receivers: Option<usize>,
+    list: Vec<Arc<Connection>>,
+}
+
+struct Connection {
+    name: String,
+    address: SocketAddr,
+    queue: std::sync::Mutex<SendQueue<Arc<EncodedPacket>>>,
+    // Packets were queued
+    ready: Notify,
+    // The receiver fell too far behind and must be dropped
+    kicked: Notify,
+}
+
+impl Connection {
+    fn pop(&self) -> Option<Arc<EncodedPacket>> {
+        self.queue.lock().ok()?.pop()
+    }
+
+    fn pop_urgent(&self) -> Option<Arc<EncodedPacket>> {
+        self.queue.lock().ok()?.pop_urgent()
+    }
+
+    fn record_sent(&self, frame_type: FrameType, len: usize) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.record_sent(frame_type, len);
+        }
+    }
+}
+
+/// A connected receiver and how its queue is doing.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub name: String,
+    pub address: SocketAddr,
+    pub queue: QueueStats,
+}
+
+// Codec used for the stream, shared by all clones of a Sender
+struct EncoderState {
+    codec: CodecId,
+    encoder: Option<Box<dyn VideoEncoder>>,
+    buffer: BytesMut,
+}
+
+// Audio has its own lock so it never waits behind a video frame being encoded
+struct AudioEncoderState {
+    codec: CodecId,
+    encoder: Option<Box<dyn AudioEncoder>>,
+    buffer: BytesMut,
 }
 
 impl Sender {
     pub async fn new(port: u16) -> Result<Self> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-        let (tx, _) = broadcast::channel(16); // Buffer size 16 frames
-        
-        let tx_clone = tx.clone();
+        Self::with_config(SenderConfig::with_port(port)).await
+    }
+
+    pub async fn with_capabilities(port: u16, capabilities: Capabilities) -> Result<Self> {
+        Self::with_config(SenderConfig { capabilities, ..SenderConfig::with_port(port) }).await
+    }
+
+    pub async fn with_registry(port: u16, capabilities: Capabilities, registry: Arc<CodecRegistry>) -> Result<Self> {
+        Self::with_config(SenderConfig { capabilities, registry, ..SenderConfig::with_port(port) }).await
+    }
+
+    // The advertised codecs are taken from the registry's encoders. Unless the
+    // config picks a codec, the stream starts out LZ4 compressed if the registry
+    // has it, uncompressed otherwise.
+    pub async fn with_config(config: SenderConfig) -> Result<Self> {
+        let SenderConfig { mut capabilities, registry, codec, audio_codec, .. } = config.clone();
+        capabilities.codecs = registry.encoder_ids();
+        let codec = codec.unwrap_or(if registry.has_encoder(CodecId::LZ4) { CodecId::LZ4 } else { CodecId::UNCOMPRESSED });
+        let encoder = EncoderState {
+            codec,
+            encoder: new_encoder(&registry, codec)?,
+            buffer: BytesMut::with_capacity(8192),
+        };
+
+        let audio_encoder = AudioEncoderState {
+            codec: audio_codec,
+            encoder: if audio_codec == CodecId::UNCOMPRESSED { None } else { Some(registry.audio_encoder(audio_codec)?) },
+            buffer: BytesMut::with_capacity(8192),
+        };
+
+        let listener = bind_listener(&config)?;
+        let local_addr = listener.local_addr()?;
+        let connections = Arc::new(std::sync::Mutex::new(Connections {
+            policy: config.queue_policy,
+            max_receivers: config.max_receivers,
+            list: Vec::new(),
+        }));
+
+        let connections_clone = connections.clone();
+        let capabilities = Arc::new(capabilities);
+        let encoder = Arc::new(std::sync::Mutex::new(encoder));
+        let encoder_clone = encoder.clone();
+        let registry_clone = registry.clone();
+        let socket_options = config.socket;
         tokio::spawn(async move {
-            if let Err(e) = run_accept_loop(listener, tx_clone).await {
+            if let Err(e) = run_accept_loop(listener, socket_options, connections_clone, capabilities, encoder_clone, registry_clone).await {
                 error!("Accept loop error: {}", e);
             }
         });
 
         Ok(Self { 
-            tx,
-            compression_buffer: Arc::new(std::sync::Mutex::new(BytesMut::with_capacity(8192))),
+            connections,
+            registry,
+            encoder,
+            audio_encoder: Arc::new(std::sync::Mutex::new(audio_encoder)),
+            workers: Arc::new(OnceLock::new()),
+            encode_queue_depth: config.encode_queue_depth.max(1),
+            local_addr,
         })
     }
 
+    // The address receivers connect to, with the port the system picked when
+    // the config asked for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Switch the codec used for subsequent video frames. Receivers that can't
+    // decode it stop getting video until the codec changes again.
+    pub fn set_codec(&self, codec: CodecId) -> Result<()> {
+        let encoder = new_encoder(&self.registry, codec)?;
+        let mut state = self.encoder.lock()
+            .map_err(|_| AqueductError::Config("Encoder state poisoned".to_string()))?;
+        state.codec = codec;
+        state.encoder = encoder;
+        Ok(())
+    }
+
+    pub fn codec(&self) -> CodecId {
+        self.encoder.lock().map(|state| state.codec).unwrap_or(CodecId::UNCOMPRESSED)
+    }
+
+    // Switch the codec used for subsequent audio frames. Audio is uncompressed
+    // until this is called. Receivers that can't decode it get uncompressed audio.
+    pub fn set_audio_codec(&self, codec: CodecId) -> Result<()> {
+        let encoder = if codec == CodecId::UNCOMPRESSED { None } else { Some(self.registry.audio_encoder(codec)?) };
+        let mut state = self.audio_encoder.lock()
+            .map_err(|_| AqueductError::Config("Audio encoder state poisoned".to_string()))?;
+        state.codec = codec;
+        state.encoder = encoder;
+        Ok(())
+    }
+
+    pub fn audio_codec(&self) -> CodecId {
+        self.audio_encoder.lock().map(|state| state.codec).unwrap_or(CodecId::UNCOMPRESSED)
+    }
+
+    // How receiver queues deal with receivers that can't keep up, applied to
+    // connected receivers as well as new ones
+    pub fn set_queue_policy(&self, policy: QueuePolicy) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.policy = policy;
+            for connection in &connections.list {
+                if let Ok(mut queue) = connection.queue.lock() {
+                    queue.set_policy(policy);
+                }
+            }
+        }
+    }
+
+    pub fn queue_policy(&self) -> QueuePolicy {
+        self.connections.lock().map(|connections| connections.policy).unwrap_or_default()
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        let now = Instant::now();
+        let Ok(connections) = self.connections.lock() else {
+            return Vec::new();
+        };
+        connections.list.iter()
+            .filter_map(|connection| Some(ConnectionStats {
+                name: connection.name.clone(),
+                address: connection.address,
+                queue: connection.queue.lock().ok()?.stats(now),
+            }))
+            .collect()
+    }
+
+    // Counters of the current encoder, reset when the codec changes
+    pub fn encoder_stats(&self) -> Option<EncoderStats> {
+        let state = self.encoder.lock().ok()?;
+        state.encoder.as_ref()?.stats()
+    }
+
+    // Queues the packet for the sender's encode workers, waiting while their queue
+    // is full, and resolves once it is queued. The capture loop can then move on
+    // while the frame is encoded. Encoding errors are logged, not returned.
+    pub async fn enqueue(&self, packet: Packet) -> Result<()> {
+        self.workers()?.lane(&packet).send(packet).await
+            .map_err(|_| AqueductError::Codec("Encode worker stopped".to_string()))
+    }
+
+    // Like `enqueue` but fails with `QueueFull` instead of waiting when the
+    // encoder is behind, e.g. to drop the frame and grab a fresher one
+    pub fn try_send(&self, packet: Packet) -> Result<()> {
+        match self.workers()?.lane(&packet).try_send(packet) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(AqueductError::QueueFull),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(AqueductError::Codec("Encode worker stopped".to_string())),
+        }
+    }
+
+    fn workers(&self) -> Result<&EncodeWorkers> {
+        if let Some(workers) = self.workers.get() {
+            return Ok(workers);
+        }
+        // The workers' handle has no workers of its own, so they don't keep this one alive
+        let detached = Self {
+            workers: Arc::new(OnceLock::new()),
+            ..self.clone()
+        };
+        let workers = EncodeWorkers::start(detached, self.encode_queue_depth)?;
+        // If another caller got there first, ours stop as soon as they are dropped
+        Ok(self.workers.get_or_init(|| workers))
+    }
+
+    // Encodes on the calling thread. Packets sent this way may overtake ones
+    // still queued by `enqueue` or `try_send`. The stream has a single video
+    // encoder, so concurrent callers take turns encoding video.
     pub fn send(&self, mut packet: Packet) -> Result<()> {
-        // Encode video frames before sending
+        if let Packet::Audio(ref mut frame) = packet {
+            if !frame.is_compressed() {
+                let mut state = self.audio_encoder.lock()
+                    .map_err(|_| AqueductError::Config("Audio encoder state poisoned".to_string()))?;
+                let AudioEncoderState { codec, encoder, buffer } = &mut *state;
+                if let Some(encoder) = encoder {
+                    buffer.clear();
+                    encoder.encode_into(frame, buffer)?;
+                    frame.data = buffer.split().freeze();
+                    frame.codec = *codec;
+                }
+            }
+        }
+
+        // Encode video frames before sending, unless the caller already did
         if let Packet::Video(ref mut frame) = packet {
+             if frame.is_compressed() {
+                 return self.broadcast(packet, false);
+             }
+
              let original_len = frame.data.len();
-             let mut codec = Lz4Codec::new();
-             
-             // Try to reuse the compression buffer
-             let compressed_data_bytes = if let Ok(mut buffer) = self.compression_buffer.lock() {
-                 buffer.clear();
-                 if let Err(e) = codec.encode_into(frame, &mut buffer) {
-                     // Fallback to allocation if buffer error (should not happen)
-                     log::warn!("Buffer encode failed, falling back: {}", e);
-                     codec.encode(frame)?
-                 } else {
-                     // Success, freeze the data out
-                     buffer.split().freeze()
-                 }
-             } else {
-                 // Lock failed, fallback
-                 codec.encode(frame)?
+             let mut state = self.encoder.lock()
+                 .map_err(|_| AqueductError::Config("Encoder state poisoned".to_string()))?;
+             let EncoderState { codec, encoder, buffer } = &mut *state;
+             let Some(encoder) = encoder else {
+                 // Uncompressed, the frame goes out as is
+                 drop(state);
+                 return self.broadcast(packet, false);
              };
-             
+
+             // Reuse the encode buffer, split() hands out the bytes and keeps the allocation
+             buffer.clear();
+             encoder.encode_into(frame, buffer)?;
+             let compressed_data_bytes = buffer.split().freeze();
+             frame.codec = *codec;
+             let dependent = !encoder.is_keyframe();
+             drop(state);
+
              let compressed_len = compressed_data_bytes.len();
              frame.data = compressed_data_bytes;
              
@@ -70,224 +315,730 @@ impl Sender {
                  log::debug!("Compressed frame: {} -> {} bytes ({:.2}%)", 
                     original_len, compressed_len, (compressed_len as f64 / original_len as f64) * 100.0);
              }
+             return self.broadcast(packet, dependent);
+        }
+
+        self.broadcast(packet, false)
+    }
+
+    // Serializes the packet once and queues the bytes for every receiver.
+    // `dependent` marks video that only decodes on top of the frames before it.
+    // The connection list is only locked to hand out the bytes, so receivers
+    // connecting or leaving never wait for a frame being serialized.
+    fn broadcast(&self, mut packet: Packet, dependent: bool) -> Result<()> {
+        let poisoned = |_| AqueductError::Config("Connection list poisoned".to_string());
+        if self.connections.lock().map_err(poisoned)?.list.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = BytesMut::new();
+        OmtCodec::new().encode(&packet, &mut buffer)?;
+        let bytes = buffer.freeze();
+        // The video payload is copied verbatim at the end, share it rather than keep both copies
+        if let Packet::Video(ref mut frame) = packet {
+            frame.data = bytes.slice(bytes.len() - frame.data.len()..);
         }
 
-        // Drop error if no receivers (Err(SendError) means no active subscribers, which is fine)
-        let _ = self.tx.send(Arc::new(packet));
+        let frame_type = match packet {
+            Packet::Video(_) => FrameType::Video,
+            Packet::Audio(_) => FrameType::Audio,
+            Packet::Metadata(_) => FrameType::Metadata,
+        };
+        let encoded = Arc::new(EncodedPacket { packet, frame_type, bytes });
+        let now = Instant::now();
+        let mut keyframe_wanted = false;
+        let mut connections = self.connections.lock().map_err(poisoned)?;
+        connections.list.retain(|connection| {
+            let Ok(mut queue) = connection.queue.lock() else {
+                return false;
+            };
+            if !queue.push(encoded.clone(), frame_type, dependent, now) {
+                log::warn!("Receiver '{}' is {:?} behind, disconnecting", connection.name, queue.behind(now));
+                connection.kicked.notify_one();
+                return false;
+            }
+            if queue.take_keyframe_request() {
+                log::debug!("Dropped video broke the reference of receiver '{}', requesting a keyframe", connection.name);
+                keyframe_wanted = true;
+            }
+            connection.ready.notify_one();
+            true
+        });
+        drop(connections);
+        if keyframe_wanted {
+            request_keyframe(&self.encoder);
+        }
         Ok(())
     }
 }
 
-async fn run_accept_loop(listener: TcpListener, tx: broadcast::Sender<Arc<Packet>>) -> Result<()> {
+// Encoding off the caller's thread. This is not a pool: video and audio have one
+// worker thread each, so audio never waits behind a video frame being encoded and
+// each keeps its packets in order. Encoders carry state from frame to frame (delta
+// references, rate control), so a stream's video can't be spread over threads;
+// LZ4 spreads a large frame's slices over cores instead. Metadata goes with the
+// audio. Workers stop once the last handle to the sender is dropped and their
+// queue is drained.
+struct EncodeWorkers {
+    video: mpsc::Sender<Packet>,
+    audio: mpsc::Sender<Packet>,
+}
+
+impl EncodeWorkers {
+    fn start(sender: Sender, depth: usize) -> Result<Self> {
+        Ok(Self {
+            video: spawn_encode_worker("aqueduct-video-encoder", sender.clone(), depth)?,
+            audio: spawn_encode_worker("aqueduct-audio-encoder", sender, depth)?,
+        })
+    }
+
+    fn lane(&self, packet: &Packet) -> &mpsc::Sender<Packet> {
+        match packet {
+            Packet::Video(_) => &self.video,
+            Packet::Audio(_) | Packet::Metadata(_) => &self.audio,
+        }
+    }
+}
+
+fn spawn_encode_worker(name: &str, sender: Sender, depth: usize) -> Result<mpsc::Sender<Packet>> {
+    let (tx, mut rx) = mpsc::channel(depth);
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            while let Some(packet) = rx.blocking_recv() {
+                if let Err(e) = sender.send(packet) {
+                    log::warn!("Dropping packet that failed to encode: {}", e);
+                }
+            }
+        })?;
+    Ok(tx)
+}
+
+fn new_encoder(registry: &CodecRegistry, codec: CodecId) -> Result<Option<Box<dyn VideoEncoder>>> {
+    if codec == CodecId::UNCOMPRESSED {
+        Ok(None)
+    } else {
+        registry.encoder(codec).map(Some)
+    }
+}
+
+async fn run_accept_loop(
+    listener: TcpListener,
+    socket_options: SocketOptions,
+    connections: Arc<std::sync::Mutex<Connections>>,
+    capabilities: Arc<Capabilities>,
+    encoder: Arc<std::sync::Mutex<EncoderState>>,
+    registry: Arc<CodecRegistry>,
+) -> Result<()> {
     info!("Sender listening on {}", listener.local_addr()?);
     loop {
         let (socket, addr) = listener.accept().await?;
         info!("New receiver connected: {}", addr);
-        let rx = tx.subscribe();
-        tokio::spawn(handle_receiver(socket, rx));
+        if let Err(e) = socket_options.apply(&socket, addr) {
+            log::warn!("Can't set socket options for receiver {}: {}", addr, e);
+        }
+        let connections = connections.clone();
+        let capabilities = capabilities.clone();
+        let encoder = encoder.clone();
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_receiver(socket, addr, connections, capabilities, encoder, registry).await {
+                error!("Receiver {} disconnected: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_receiver(
+    mut socket: TcpStream,
+    address: SocketAddr,
+    connections: Arc<std::sync::Mutex<Connections>>,
+    capabilities: Arc<Capabilities>,
+    encoder: Arc<std::sync::Mutex<EncoderState>>,
+    registry: Arc<CodecRegistry>,
+) -> Result<()> {
+    let mut codec = OmtCodec::new();
+    let mut buffer = BytesMut::with_capacity(8192);
+
+    let remote = match read_hello(&mut socket, &mut codec, &mut buffer).await? {
+        PeerHello::Hello(remote) => remote,
+        PeerHello::Reject(reason) => return Err(AqueductError::Handshake(reason)),
+        PeerHello::Plain(first) => {
+            info!("Receiver {} sent no hello, treating it as a plain OMT receiver", address);
+            if let Some(Packet::Metadata(frame)) = first {
+                log::debug!("Ignoring metadata from receiver {}: {}", address, frame.content);
+            }
+            Capabilities::plain_omt()
+        }
+    };
+
+    let negotiated = match negotiate(&capabilities, &remote) {
+        Ok(negotiated) => negotiated,
+        Err(e) => {
+            // Tell the receiver why before hanging up, ignoring failures since we are closing anyway
+            let reason = match &e {
+                AqueductError::Handshake(reason) => reason.clone(),
+                other => other.to_string(),
+            };
+            let reject = HandshakeMessage::Reject(reason).to_metadata()?;
+            let _ = write_packet(&mut socket, &mut codec, &mut buffer, &Packet::Metadata(reject)).await;
+            return Err(e);
+        }
+    };
+    // Register only once the handshake is done so we don't queue frames for peers we reject
+    let registered = connections.lock()
+        .map_err(|_| AqueductError::Config("Connection list poisoned".to_string()))
+        .map(|mut connections| {
+            if connections.max_receivers.is_some_and(|max| connections.list.len() >= max) {
+                return None;
+            }
+            let connection = Arc::new(Connection {
+                name: remote.name.clone(),
+                address,
+                queue: std::sync::Mutex::new(SendQueue::new(connections.policy)),
+                ready: Notify::new(),
+                kicked: Notify::new(),
+            });
+            connections.list.push(connection.clone());
+            Some(connection)
+        })?;
+    let Some(connection) = registered else {
+        let reason = "Sender has reached its maximum number of receivers".to_string();
+        let reject = HandshakeMessage::Reject(reason.clone()).to_metadata()?;
+        let _ = write_packet(&mut socket, &mut codec, &mut buffer, &Packet::Metadata(reject)).await;
+        return Err(AqueductError::Handshake(reason));
+    };
+    if negotiated.extensions {
+        let welcome = HandshakeMessage::Hello(capabilities.as_ref().clone()).to_metadata()?;
+        if let Err(e) = write_packet(&mut socket, &mut codec, &mut buffer, &Packet::Metadata(welcome)).await {
+            remove_connection(&connections, &connection);
+            return Err(e);
+        }
+    }
+    info!("Receiver '{}' accepted (codecs {:?}, formats {:?})", remote.name, negotiated.codecs, negotiated.pixel_formats);
+
+    // A new receiver has no reference for inter-frame codecs
+    request_keyframe(&encoder);
+
+    // Control messages from the receiver are handled on their own task
+    let (mut reader, socket) = socket.into_split();
+    let name = remote.name.clone();
+    // Clock requests are answered by the writer, which stamps the reply as it goes out
+    let (clock_tx, mut clock_requests) = mpsc::channel(CLOCK_REQUEST_QUEUE);
+    let control = tokio::spawn(async move {
+        let mut codec = OmtCodec::new();
+        let mut buffer = BytesMut::with_capacity(4096);
+        while let Ok(packet) = read_packet(&mut reader, &mut codec, &mut buffer).await {
+            match packet {
+                Packet::Metadata(ref frame) => match ControlMessage::from_metadata(frame) {
+                    Some(ControlMessage::RequestKeyframe) => {
+                        log::debug!("Receiver '{}' requested a keyframe", name);
+                        request_keyframe(&encoder);
+                    }
+                    Some(ControlMessage::ClockRequest { origin }) => {
+                        // A full queue means replies are already pending, the receiver retries later
+                        let _ = clock_tx.try_send((origin, clock::now()));
+                    }
+                    Some(ControlMessage::ClockReply { .. }) => {}
+                    None => log::debug!("Ignoring metadata from receiver '{}': {}", name, frame.content),
+                },
+                _ => log::debug!("Ignoring media from receiver '{}'", name),
+            }
+        }
+    });
+    let codec = if negotiated.extensions { codec } else { OmtCodec::plain() };
+    let mut writer = PacketWriter { socket, codec, buffer };
+    let result = tokio::select! {
+        result = forward_packets(&mut writer, &connection, &mut clock_requests, &negotiated, &registry) => result,
+        _ = connection.kicked.notified() => Err(AqueductError::Io(std::io::Error::new(
+            std::io::ErrorKind::TimedOut, "Receiver fell too far behind",
+        ))),
+    };
+    control.abort();
+    remove_connection(&connections, &connection);
+    result
+}
+
+fn remove_connection(connections: &std::sync::Mutex<Connections>, connection: &Arc<Connection>) {
+    if let Ok(mut connections) = connections.lock() {
+        connections.list.retain(|other| !Arc::ptr_eq(other, connection));
+    }
+}
+
+fn request_keyframe(encoder: &std::sync::Mutex<EncoderState>) {
+    if let Ok(mut state) = encoder.lock() {
+        if let Some(encoder) = state.encoder.as_mut() {
+            encoder.request_keyframe();
+        }
+    }
+}
+
+// Writing half of a receiver connection
+struct PacketWriter<W> {
+    socket: W,
+    codec: OmtCodec,
+    buffer: BytesMut,
+}
+
+impl<W: AsyncWrite + Unpin> PacketWriter<W> {
+    async fn write(&mut self, packet: &Packet) -> Result<()> {
+        write_packet(&mut self.socket, &mut self.codec, &mut self.buffer, packet).await
+    }
+
+    async fn write_encoded(&mut self, bytes: &[u8]) -> Result<()> {
+        self.socket.write_all(bytes).await?;
+        Ok(())
+    }
+
+    async fn write_chunk(&mut self, timestamp: Duration, sequence: u32, offset: usize, piece: &[u8], total_len: usize) -> Result<()> {
+        self.buffer.clear();
+        encode_chunk_header(timestamp, sequence, offset, piece.len(), total_len, &mut self.buffer);
+        // Headers and piece in a single vectored write
+        self.socket.write_all_buf(&mut Buf::chain(&self.buffer[..], piece)).await?;
+        Ok(())
+    }
+
+    async fn write_clock_reply(&mut self, origin: Duration, receive: Duration) -> Result<()> {
+        let reply = ControlMessage::ClockReply { origin, receive, transmit: clock::now() };
+        self.write(&Packet::Metadata(reply.to_metadata())).await
     }
 }
 
-async fn handle_receiver(mut socket: TcpStream, mut rx: broadcast::Receiver<Arc<Packet>>) {
+async fn forward_packets<W: AsyncWrite + Unpin>(
+    writer: &mut PacketWriter<W>,
+    connection: &Connection,
+    clock_requests: &mut mpsc::Receiver<(Duration, Duration)>,
+    negotiated: &Negotiated,
+    registry: &CodecRegistry,
+) -> Result<()> {
+    let name = &connection.name;
+    let mut skipped_codec = None;
+    // Decodes audio for receivers that don't support the stream's audio codec
+    let mut audio_decoder: Option<(CodecId, Box<dyn AudioDecoder>)> = None;
+    let mut chunk_sequence = 0u32;
     loop {
-        match rx.recv().await {
-            Ok(packet) => {
-                if let Err(e) = write_packet(&mut socket, &packet).await {
-                    error!("Failed to send packet: {}", e);
-                    break;
+        // Clock replies go ahead of queued media so a backlog doesn't skew the measurement
+        while let Ok((origin, receive)) = clock_requests.try_recv() {
+            writer.write_clock_reply(origin, receive).await?;
+        }
+        let Some(encoded) = connection.pop() else {
+            tokio::select! {
+                _ = connection.ready.notified() => {}
+                Some((origin, receive)) = clock_requests.recv() => writer.write_clock_reply(origin, receive).await?,
+            }
+            continue;
+        };
+
+        let Packet::Video(ref frame) = encoded.packet else {
+            forward_other(writer, connection, &encoded, negotiated, registry, &mut audio_decoder).await?;
+            continue;
+        };
+        if !negotiated.pixel_formats.contains(&frame.format) {
+            log::debug!("Skipping {:?} frame unsupported by '{}'", frame.format, name);
+            continue;
+        }
+        if !negotiated.codecs.contains(&frame.codec) {
+            // Warn once per codec rather than on every frame
+            if skipped_codec != Some(frame.codec) {
+                log::warn!("Receiver '{}' can't decode {}, skipping video", name, frame.codec);
+                skipped_codec = Some(frame.codec);
+            }
+            continue;
+        }
+
+        if !negotiated.extensions {
+            // Re-encoded without Aqueduct's extension header, plain OMT has no way to describe padded rows
+            if frame.stride != frame.format.default_stride(frame.width) {
+                log::debug!("Skipping frame with stride {} unsupported by '{}'", frame.stride, name);
+                continue;
+            }
+            writer.write(&encoded.packet).await?;
+            connection.record_sent(FrameType::Video, writer.buffer.len());
+            continue;
+        }
+
+        let bytes = &encoded.bytes;
+        if negotiated.chunked_video && bytes.len() > VIDEO_CHUNK_LEN {
+            chunk_sequence = chunk_sequence.wrapping_add(1);
+            for offset in (0..bytes.len()).step_by(VIDEO_CHUNK_LEN) {
+                let piece = &bytes[offset..bytes.len().min(offset + VIDEO_CHUNK_LEN)];
+                writer.write_chunk(frame.timestamp, chunk_sequence, offset, piece, bytes.len()).await?;
+                // Whatever audio and metadata came in meanwhile goes out before the next chunk
+                while let Ok((origin, receive)) = clock_requests.try_recv() {
+                    writer.write_clock_reply(origin, receive).await?;
+                }
+                while let Some(urgent) = connection.pop_urgent() {
+                    forward_other(writer, connection, &urgent, negotiated, registry, &mut audio_decoder).await?;
                 }
             }
-            Err(broadcast::error::RecvError::Lagged(n)) => {
-                info!("Receiver lagged by {} packets", n);
+        } else {
+            writer.write_encoded(bytes).await?;
+        }
+        connection.record_sent(FrameType::Video, bytes.len());
+    }
+}
+
+// Audio and metadata
+async fn forward_other<W: AsyncWrite + Unpin>(
+    writer: &mut PacketWriter<W>,
+    connection: &Connection,
+    encoded: &EncodedPacket,
+    negotiated: &Negotiated,
+    registry: &CodecRegistry,
+    audio_decoder: &mut Option<(CodecId, Box<dyn AudioDecoder>)>,
+) -> Result<()> {
+    if let Packet::Audio(ref frame) = encoded.packet {
+        let decode = frame.is_compressed() && !negotiated.codecs.contains(&frame.codec);
+        // Re-encoded for plain OMT peers, which only read float audio
+        if decode || !negotiated.extensions {
+            let mut frame = frame.clone();
+            if decode {
+                if audio_decoder.as_ref().is_none_or(|(codec, _)| *codec != frame.codec) {
+                    *audio_decoder = Some((frame.codec, registry.audio_decoder(frame.codec)?));
+                }
+                let (_, decoder) = audio_decoder.as_mut().expect("decoder was just created");
+                frame = AudioFrame {
+                    codec: CodecId::UNCOMPRESSED,
+                    data: decoder.decode(&frame)?,
+                    ..frame
+                };
             }
-            Err(broadcast::error::RecvError::Closed) => {
-                break;
+            if !negotiated.extensions && frame.format != SampleFormat::F32 {
+                frame = convert_audio_frame(&frame, SampleFormat::F32, frame.channel_layout)?;
             }
+            writer.write(&Packet::Audio(frame)).await?;
+            connection.record_sent(FrameType::Audio, writer.buffer.len());
+            return Ok(());
         }
     }
+    writer.write_encoded(&encoded.bytes).await?;
+    connection.record_sent(encoded.frame_type, encoded.bytes.len());
+    Ok(())
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(socket: &mut W, codec: &mut OmtCodec, buffer: &mut BytesMut, packet: &Packet) -> Result<()> {
+    buffer.clear();
+    codec.encode(packet, buffer)?;
+    socket.write_all(buffer).await?;
+    Ok(())
+}
+
+// The first thing a peer says
+enum PeerHello {
+    Hello(Capabilities),
+    Reject(String),
+    // No hello within the timeout, along with the packet it sent instead if any
+    Plain(Option<Packet>),
 }
 
-async fn write_packet(socket: &mut TcpStream, packet: &Packet) -> Result<()> {
-    match packet {
-        Packet::Video(frame) => {
-            socket.write_u8(TYPE_VIDEO).await?;
-            // We need to serialize the frame metadata + data. 
-            // Simplified: [Width: u32][Height: u32][Format: u8][Timestamp: u64 (micros)][DataLen: u32][Data]
-            // buf was unused in previous draft, removed.
-            
-            // This is a placeholder serialization. Real OMT might differ.
-            // TODO: Implement proper serialization based on OMT spec
-            let len = 4 + 4 + 1 + 8 + frame.data.len() as u32; 
-            socket.write_u32(len).await?;
-            
-            socket.write_u32(frame.width).await?;
-            socket.write_u32(frame.height).await?;
-            socket.write_u8(frame.format as u8).await?; // Assuming enum matches u8 representation
-            socket.write_u64(frame.timestamp.as_micros() as u64).await?;
-            socket.write_all(&frame.data).await?;
+async fn read_hello<R: AsyncRead + Unpin>(socket: &mut R, codec: &mut OmtCodec, buffer: &mut BytesMut) -> Result<PeerHello> {
+    // The decoder only consumes whole frames, so a read cut short by the timeout loses nothing
+    let packet = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_packet(socket, codec, buffer)).await {
+        Ok(packet) => packet?,
+        Err(_) => return Ok(PeerHello::Plain(None)),
+    };
+    if let Packet::Metadata(ref frame) = packet {
+        if HandshakeMessage::is_handshake(frame) {
+            return Ok(match HandshakeMessage::from_metadata(frame)? {
+                HandshakeMessage::Hello(remote) => PeerHello::Hello(remote),
+                HandshakeMessage::Reject(reason) => PeerHello::Reject(reason),
+            });
         }
-        Packet::Audio(frame) => {
-            socket.write_u8(TYPE_AUDIO).await?;
-            let len = 4 + 4 + 8 + frame.data.len() as u32;
-            socket.write_u32(len).await?;
-            
-            socket.write_u32(frame.sample_rate).await?;
-            socket.write_u32(frame.channels).await?;
-            socket.write_u64(frame.timestamp.as_micros() as u64).await?;
-            socket.write_all(&frame.data).await?;
+    }
+    Ok(PeerHello::Plain(Some(packet)))
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(socket: &mut R, codec: &mut OmtCodec, buffer: &mut BytesMut) -> Result<Packet> {
+    loop {
+        if let Some(packet) = codec.decode(buffer)? {
+            return Ok(packet);
         }
-        Packet::Metadata(frame) => {
-            socket.write_u8(TYPE_METADATA).await?;
-            let bytes = frame.content.as_bytes();
-            let len = 8 + bytes.len() as u32;
-            socket.write_u32(len).await?;
-            
-            socket.write_u64(frame.timestamp.as_micros() as u64).await?;
-            socket.write_all(bytes).await?;
+        if socket.read_buf(buffer).await? == 0 {
+             if buffer.is_empty() {
+                 return Err(AqueductError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+             } else {
+                 return Err(AqueductError::Protocol("Connection closed incomplete".to_string()));
+             }
         }
     }
-    Ok(())
 }
 
 pub struct Receiver {
     stream: TcpStream,
+    codec: OmtCodec,
     buffer: BytesMut,
     decompress_buffer: BytesMut,
+    sender_capabilities: Capabilities,
+    // Media a plain OMT sender sent in place of a hello, returned by the first `receive`
+    pending: Option<Packet>,
+    registry: Arc<CodecRegistry>,
+    decoders: HashMap<CodecId, Box<dyn VideoDecoder>>,
+    audio_decoders: HashMap<CodecId, Box<dyn AudioDecoder>>,
+    // Video frames skipped while waiting for a keyframe
+    frames_without_reference: u32,
+    clock: ClockSync,
+    last_clock_request: Instant,
 }
 
+// Skipped frames after which a keyframe is requested again, in case it got lost
+const KEYFRAME_RETRY_FRAMES: u32 = 30;
+
+// How often a receiver measures the sender's clock
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+// Clock requests a sender holds for a receiver while media is being written
+const CLOCK_REQUEST_QUEUE: usize = 4;
+
+// Video frames larger than this are sent in chunks to receivers that support it,
+// so audio and metadata don't wait for a whole frame to be written
+const VIDEO_CHUNK_LEN: usize = 64 * 1024;
+
 impl Receiver {
     pub async fn connect(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        Ok(Self { 
-            stream,
-            buffer: BytesMut::with_capacity(4096),
-            decompress_buffer: BytesMut::with_capacity(4096),
-        })
+        Self::connect_with_capabilities(addr, Capabilities::new("Aqueduct Receiver")).await
     }
 
-    pub async fn receive(&mut self) -> Result<Packet> {
-        // Ensure we have the header (Type + Length = 1 + 4 = 5 bytes)
-        loop {
-            if self.buffer.len() >= 5 {
-                break;
+    pub async fn connect_with_capabilities(addr: &str, capabilities: Capabilities) -> Result<Self> {
+        Self::connect_with_registry(addr, capabilities, Arc::new(CodecRegistry::default())).await
+    }
+
+    // The advertised codecs are taken from the registry's decoders
+    pub async fn connect_with_registry(addr: &str, mut capabilities: Capabilities, registry: Arc<CodecRegistry>) -> Result<Self> {
+        capabilities.codecs = registry.decoder_ids();
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut codec = OmtCodec::new();
+        let mut buffer = BytesMut::with_capacity(4096);
+
+        let hello = HandshakeMessage::Hello(capabilities.clone()).to_metadata()?;
+        write_packet(&mut stream, &mut codec, &mut buffer, &Packet::Metadata(hello)).await?;
+        buffer.clear();
+
+        let (sender_capabilities, pending) = match read_hello(&mut stream, &mut codec, &mut buffer).await? {
+            PeerHello::Hello(remote) => (remote, None),
+            PeerHello::Reject(reason) => {
+                return Err(AqueductError::Handshake(format!("Rejected by sender: {}", reason)));
             }
-            if self.stream.read_buf(&mut self.buffer).await? == 0 {
-                 if self.buffer.is_empty() {
-                     return Err(AqueductError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
-                 } else {
-                     return Err(AqueductError::Protocol("Connection closed incomplete".to_string()));
-                 }
+            PeerHello::Plain(first) => {
+                info!("Sender at {} sent no hello, treating it as a plain OMT sender", addr);
+                // Its own buffer, `buffer` may hold the start of a frame already
+                let mut out = BytesMut::new();
+                for subscription in omt_subscriptions() {
+                    write_packet(&mut stream, &mut codec, &mut out, &Packet::Metadata(subscription)).await?;
+                }
+                (Capabilities::plain_omt(), first)
             }
+        };
+        negotiate(&capabilities, &sender_capabilities)?;
+
+        let mut receiver = Self {
+            stream,
+            codec,
+            buffer,
+            decompress_buffer: BytesMut::with_capacity(4096),
+            sender_capabilities,
+            pending,
+            registry,
+            decoders: HashMap::new(),
+            audio_decoders: HashMap::new(),
+            frames_without_reference: 0,
+            clock: ClockSync::new(),
+            last_clock_request: Instant::now(),
+        };
+        receiver.sync_clock().await?;
+        Ok(receiver)
+    }
+
+    pub fn sender_capabilities(&self) -> &Capabilities {
+        &self.sender_capabilities
+    }
+
+    // The sender's clock as measured so far, None until the first reply comes in.
+    // Use it to turn frame timestamps into local times, or to see how old a frame is.
+    pub fn clock_estimate(&self) -> Option<ClockEstimate> {
+        self.clock.estimate()
+    }
+
+    // Starts a clock measurement, done every few seconds automatically. The reply
+    // is timed when `receive` reads it, so a receiver that isn't read promptly
+    // sees long round trips; the estimate favours the shortest ones.
+    pub async fn sync_clock(&mut self) -> Result<()> {
+        if self.sender_capabilities.is_plain_omt() {
+            return Ok(());
         }
+        let mut buffer = BytesMut::new();
+        let request = ControlMessage::ClockRequest { origin: clock::now() };
+        self.last_clock_request = Instant::now();
+        write_packet(&mut self.stream, &mut self.codec, &mut buffer, &Packet::Metadata(request.to_metadata())).await
+    }
+
+    // Hands the stream to a jitter buffer that releases frames at their presentation time
+    pub fn into_playout(self, config: PlayoutConfig) -> Playout {
+        Playout::new(self, config)
+    }
 
-        // Peek header
-        let type_id = self.buffer[0];
-        let mut len_bytes = [0u8; 4];
-        len_bytes.copy_from_slice(&self.buffer[1..5]);
-        let len = u32::from_be_bytes(len_bytes) as usize; // read_u32 is big endian? 
-        // Wait, tokio read_u32 is Big Endian. My write_u32 was...
-        // AsyncWriteExt::write_u32 is Big Endian.
-        // So from_be_bytes is correct.
-
-        // Safety check
-        if len > 100_000_000 {
-             return Err(AqueductError::Protocol("Packet too large".to_string()));
+    // Asks the sender to make its next video frame a keyframe. Plain OMT senders
+    // only send intra-frame video and aren't asked.
+    pub async fn request_keyframe(&mut self) -> Result<()> {
+        if self.sender_capabilities.is_plain_omt() {
+            return Ok(());
         }
+        let mut buffer = BytesMut::new();
+        let request = Packet::Metadata(ControlMessage::RequestKeyframe.to_metadata());
+        write_packet(&mut self.stream, &mut self.codec, &mut buffer, &request).await
+    }
 
-        // Ensure we have the full packet
-        let total_len = 5 + len;
+    // Video frames that can't be decoded until the next keyframe are skipped, the
+    // keyframe is requested from the sender automatically.
+    pub async fn receive(&mut self) -> Result<Packet> {
         loop {
-            if self.buffer.len() >= total_len {
-                break;
+            if self.last_clock_request.elapsed() >= CLOCK_SYNC_INTERVAL {
+                self.sync_clock().await?;
             }
-            // Reserve space if needed
-            if self.buffer.capacity() < total_len {
-                self.buffer.reserve(total_len - self.buffer.len());
+            let packet = match self.pending.take() {
+                Some(packet) => packet,
+                None => read_packet(&mut self.stream, &mut self.codec, &mut self.buffer).await?,
+            };
+            if let Packet::Metadata(ref frame) = packet {
+                if let Some(ControlMessage::ClockReply { origin, receive, transmit }) = ControlMessage::from_metadata(frame) {
+                    if let Some(estimate) = self.clock.update(origin, receive, transmit, clock::now()) {
+                        log::debug!("Sender clock offset {} ticks, round trip {:?}", estimate.offset, estimate.round_trip);
+                    }
+                    continue;
+                }
             }
-            if self.stream.read_buf(&mut self.buffer).await? == 0 {
-                 return Err(AqueductError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+            match self.decode(packet) {
+                Err(AqueductError::KeyframeRequired) => {
+                    if self.frames_without_reference.is_multiple_of(KEYFRAME_RETRY_FRAMES) {
+                        info!("Lost the video reference, requesting a keyframe");
+                        self.request_keyframe().await?;
+                    }
+                    self.frames_without_reference += 1;
+                }
+                Ok(packet) => {
+                    if matches!(packet, Packet::Video(_)) {
+                        self.frames_without_reference = 0;
+                    }
+                    return Ok(packet);
+                }
+                Err(e) => return Err(e),
             }
         }
+    }
+
+    fn decode(&mut self, packet: Packet) -> Result<Packet> {
+        match packet {
+            Packet::Video(mut frame) if frame.is_compressed() => {
+                // Decoders are created on first use and kept, they may hold state across frames
+                let decoder = match self.decoders.entry(frame.codec) {
+                    std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(entry) => entry.insert(self.registry.decoder(frame.codec)?),
+                };
 
-        // Consume header
-        self.buffer.advance(5);
-        
-        // Split payload
-        let payload = self.buffer.split_to(len);
-        // buffer now contains the *next* packet data (if any)
-        
-        let mut cursor = std::io::Cursor::new(payload.freeze());
-
-        match type_id {
-            TYPE_VIDEO => {
-                // [Width: u32][Height: u32][Format: u8][Timestamp: u64][Data...]
-                if len < 21 { return Err(AqueductError::Protocol("Video packet too short".to_string())); }
-                
-                let width = cursor.get_u32();
-                let height = cursor.get_u32();
-                let format_byte = cursor.get_u8();
-                let timestamp_micros = cursor.get_u64();
-                
-                // Rest is data
-                let data_pos = cursor.position() as usize;
-                // cursor.into_inner() gives Bytes.
-                let data_bytes = cursor.into_inner();
-                let compressed_data = data_bytes.slice(data_pos..);
-
-                // Map format_byte to enum
-                let format = PixelFormat::from_u8(format_byte)
-                    .ok_or_else(|| AqueductError::Protocol(format!("Invalid pixel format: {}", format_byte)))?;
-
-                let mut codec = Lz4Codec::new();
-                
-                // Read uncompressed size from header to reserve space?
-                // decode_into handles reading the size from the first 4 bytes of compressed_data
-                // We use our persistent buffer.
-                // We need to ensure it's empty of previous data but keeps capacity?
-                // split() removes the data. So it is empty.
-                
-                // codec.decode_into appends to the buffer.
-                codec.decode_into(&compressed_data, &mut self.decompress_buffer)?;
-                
-                // The data is now in self.decompress_buffer.
-                // We split it out to get a Bytes object.
-                let data = self.decompress_buffer.split().freeze();
-
-                Ok(Packet::Video(VideoFrame {
-                    width,
-                    height,
-                    format,
-                    flags: FrameFlags::default(),
-                    timestamp: std::time::Duration::from_micros(timestamp_micros),
-                    data,
-                }))
+                // decode_into appends to the buffer. split() keeps its capacity, so
+                // the allocation is reused across frames. A failed decode can leave
+                // partial output behind, clear it so it doesn't end up in the next frame.
+                self.decompress_buffer.clear();
+                decoder.decode_into(&frame, &mut self.decompress_buffer)?;
+                frame.data = self.decompress_buffer.split().freeze();
+                frame.codec = CodecId::UNCOMPRESSED;
+                Ok(Packet::Video(frame))
             }
-            TYPE_AUDIO => {
-                let sample_rate = cursor.get_u32();
-                let channels = cursor.get_u32();
-                let timestamp_micros = cursor.get_u64();
-                
-                let data_pos = cursor.position() as usize;
-                let data = cursor.into_inner().slice(data_pos..);
-
-                Ok(Packet::Audio(AudioFrame {
-                    sample_rate,
-                    channels,
-                    timestamp: std::time::Duration::from_micros(timestamp_micros),
-                    data,
-                }))
+            Packet::Audio(mut frame) if frame.is_compressed() => {
+                let decoder = match self.audio_decoders.entry(frame.codec) {
+                    std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(entry) => entry.insert(self.registry.audio_decoder(frame.codec)?),
+                };
+                self.decompress_buffer.clear();
+                decoder.decode_into(&frame, &mut self.decompress_buffer)?;
+                frame.data = self.decompress_buffer.split().freeze();
+                frame.codec = CodecId::UNCOMPRESSED;
+                Ok(Packet::Audio(frame))
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+    use crate::codec::Lz4Codec;
+    use crate::protocol::{PixelFormat, VideoFrame};
+
+    // Reads the receiver's hello and answers it like an Aqueduct sender would
+    async fn accept_receiver(listener: &TcpListener, codec: &mut OmtCodec, buffer: &mut BytesMut) -> TcpStream {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let hello = read_packet(&mut socket, codec, buffer).await.unwrap();
+        assert!(matches!(hello, Packet::Metadata(ref frame) if HandshakeMessage::is_handshake(frame)));
+        let reply = HandshakeMessage::Hello(Capabilities::new("Test Sender")).to_metadata().unwrap();
+        write_packet(&mut socket, codec, buffer, &Packet::Metadata(reply)).await.unwrap();
+        socket
+    }
+
+    #[tokio::test]
+    async fn corrupt_frame_leaves_nothing_behind() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let data: Vec<u8> = (0..64 * 64 * 4).map(|i| (i / 64) as u8).collect();
+        let frame = VideoFrame::new(64, 64, PixelFormat::BGRA, Duration::ZERO, Bytes::from(data));
+        let good = VideoFrame { codec: CodecId::LZ4, data: Lz4Codec::new().encode(&frame).unwrap(), ..frame.clone() };
+
+        // A sliced frame whose empty slice table doesn't cover the picture. The
+        // decoder has sized its output by the time it notices.
+        let mut payload = BytesMut::new();
+        payload.put_u32_le(frame.data.len() as u32 | 1 << 30);
+        payload.put_u32_le(0);
+        let corrupt = VideoFrame { codec: CodecId::LZ4, data: payload.freeze(), ..frame.clone() };
+
+        let sender = tokio::spawn(async move {
+            let (mut codec, mut buffer) = (OmtCodec::new(), BytesMut::new());
+            let mut socket = accept_receiver(&listener, &mut codec, &mut buffer).await;
+            for frame in [corrupt, good] {
+                write_packet(&mut socket, &mut codec, &mut buffer, &Packet::Video(frame)).await.unwrap();
             }
-            TYPE_METADATA => {
-                let timestamp_micros = cursor.get_u64();
-                let data_pos = cursor.position() as usize;
-                let content = String::from_utf8_lossy(&cursor.into_inner()[data_pos..]).to_string();
-
-                Ok(Packet::Metadata(MetadataFrame {
-                    timestamp: std::time::Duration::from_micros(timestamp_micros),
-                    content,
-                }))
+            socket
+        });
+
+        let mut receiver = Receiver::connect(&addr).await.unwrap();
+        let result = receiver.receive().await;
+        assert!(matches!(result, Err(AqueductError::Protocol(_))), "{:?}", result.map(|_| ()));
+        match receiver.receive().await.unwrap() {
+            Packet::Video(decoded) => {
+                assert_eq!(decoded.data.len(), frame.data.len());
+                assert!(decoded.data == frame.data);
             }
-            _ => Err(AqueductError::Protocol(format!("Unknown packet type: {}", type_id))),
+            other => panic!("expected video, got {:?}", other),
         }
+        drop(sender.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn subscribes_to_plain_senders() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // A libomt sender: never answers the hello, waits for subscriptions
+        let sender = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (mut codec, mut buffer) = (OmtCodec::new(), BytesMut::new());
+            let mut received = Vec::new();
+            for _ in 0..4 {
+                match read_packet(&mut socket, &mut codec, &mut buffer).await.unwrap() {
+                    Packet::Metadata(frame) => received.push(frame.content),
+                    other => panic!("expected metadata, got {:?}", other),
+                }
+            }
+            received
+        });
+
+        let receiver = Receiver::connect(&addr).await.unwrap();
+        assert!(receiver.sender_capabilities().is_plain_omt());
+        let received = sender.await.unwrap();
+        assert!(received[0].contains("AqueductHello"));
+        assert_eq!(received[1..], [
+            r#"<OMTSubscribe Video="true" />"#,
+            r#"<OMTSubscribe Audio="true" />"#,
+            r#"<OMTSubscribe Metadata="true" />"#,
+        ]);
     }
 }
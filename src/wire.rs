@@ -0,0 +1,611 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::time::Duration;
+use crate::error::{Result, AqueductError};
+use crate::protocol::{
+    PixelFormat, FrameFlags, Colorimetry, ColorMatrix, ColorRange, TransferFunction, ColorPrimaries,
+    SampleFormat, ChannelLayout,
+};
+
+// OMT frame layout (all fields little-endian):
+//
+//   [FrameHeader: 16 bytes][Extended header][Payload][Per-frame metadata]
+//
+// The extended header depends on the frame type (32 bytes for video, 24 bytes
+// for audio, none for metadata). `data_len` in the frame header counts every
+// byte after the frame header, including the trailing per-frame metadata.
+
+pub const OMT_VERSION: u8 = 1;
+
+pub const FRAME_HEADER_LEN: usize = 16;
+pub const VIDEO_HEADER_LEN: usize = 32;
+pub const AUDIO_HEADER_LEN: usize = 24;
+pub const CHUNK_HEADER_LEN: usize = 12;
+
+// Largest frame we are willing to buffer before giving up on the stream
+pub const MAX_FRAME_LEN: usize = 100_000_000;
+
+// Largest width or height accepted from the wire, twice 8K UHD
+pub const MAX_VIDEO_DIMENSION: u32 = 16384;
+// Largest decoded video frame accepted from the wire, 8K in the 16-bit formats fits
+pub const MAX_VIDEO_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+// OMT timestamps are expressed in 100ns ticks
+const TICKS_PER_SECOND: u64 = 10_000_000;
+
+pub const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+// Floating point planar audio, the only audio codec defined by OMT
+pub const FOURCC_FPA1: u32 = fourcc(b"FPA1");
+// Aqueduct specific, integer samples as described by the audio format field
+pub const FOURCC_PCM: u32 = fourcc(b"PCM ");
+
+// Video flag bits
+pub const VIDEO_FLAG_INTERLACED: u32 = 1;
+pub const VIDEO_FLAG_ALPHA: u32 = 2;
+pub const VIDEO_FLAG_PREMULTIPLIED: u32 = 4;
+pub const VIDEO_FLAG_PREVIEW: u32 = 8;
+pub const VIDEO_FLAG_HIGH_BIT_DEPTH: u32 = 16;
+// Aqueduct specific, see VideoExtension
+pub const VIDEO_FLAG_EXTENSION: u32 = 1 << 31;
+
+// Color space values
+pub const COLOR_SPACE_UNDEFINED: u32 = 0;
+pub const COLOR_SPACE_BT601: u32 = 601;
+pub const COLOR_SPACE_BT709: u32 = 709;
+// Not defined by OMT, OMT peers treat it as undefined
+pub const COLOR_SPACE_BT2020: u32 = 2020;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameType {
+    Metadata = 1,
+    Video = 2,
+    Audio = 4,
+    // Aqueduct specific, see ChunkHeader
+    Chunk = 128,
+}
+
+impl FrameType {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            1 => Some(Self::Metadata),
+            2 => Some(Self::Video),
+            4 => Some(Self::Audio),
+            128 => Some(Self::Chunk),
+            _ => None,
+        }
+    }
+}
+
+pub fn duration_to_ticks(d: Duration) -> i64 {
+    (d.as_nanos() / 100) as i64
+}
+
+pub fn ticks_to_duration(ticks: i64) -> Duration {
+    // Negative timestamps are not representable by Duration, clamp them to zero
+    let ticks = ticks.max(0) as u64;
+    Duration::new(ticks / TICKS_PER_SECOND, ((ticks % TICKS_PER_SECOND) * 100) as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub frame_type: u8,
+    pub timestamp: i64,
+    pub metadata_len: u16,
+    pub data_len: u32,
+}
+
+impl FrameHeader {
+    pub fn new(frame_type: FrameType, timestamp: Duration, data_len: usize) -> Self {
+        Self {
+            version: OMT_VERSION,
+            frame_type: frame_type as u8,
+            timestamp: duration_to_ticks(timestamp),
+            metadata_len: 0,
+            data_len: data_len as u32,
+        }
+    }
+
+    pub fn write(&self, dst: &mut BytesMut) {
+        dst.reserve(FRAME_HEADER_LEN);
+        dst.put_u8(self.version);
+        dst.put_u8(self.frame_type);
+        dst.put_i64_le(self.timestamp);
+        dst.put_u16_le(self.metadata_len);
+        dst.put_u32_le(self.data_len);
+    }
+
+    // Parses and validates a header. `src` must hold at least FRAME_HEADER_LEN bytes.
+    pub fn read(mut src: &[u8]) -> Result<Self> {
+        if src.len() < FRAME_HEADER_LEN {
+            return Err(AqueductError::Protocol("Frame header too short".to_string()));
+        }
+        let header = Self {
+            version: src.get_u8(),
+            frame_type: src.get_u8(),
+            timestamp: src.get_i64_le(),
+            metadata_len: src.get_u16_le(),
+            data_len: src.get_u32_le(),
+        };
+
+        if header.version != OMT_VERSION {
+            return Err(AqueductError::Protocol(format!("Unsupported OMT version: {}", header.version)));
+        }
+        if header.data_len as usize > MAX_FRAME_LEN {
+            return Err(AqueductError::Protocol("Packet too large".to_string()));
+        }
+        if (header.metadata_len as usize) > header.data_len as usize {
+            return Err(AqueductError::Protocol("Metadata length exceeds frame length".to_string()));
+        }
+        Ok(header)
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        ticks_to_duration(self.timestamp)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoHeader {
+    pub codec: u32,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate_n: u32,
+    pub frame_rate_d: u32,
+    pub aspect_ratio: f32,
+    pub flags: u32,
+    pub color_space: u32,
+}
+
+impl VideoHeader {
+    pub fn write(&self, dst: &mut BytesMut) {
+        dst.reserve(VIDEO_HEADER_LEN);
+        dst.put_u32_le(self.codec);
+        dst.put_u32_le(self.width);
+        dst.put_u32_le(self.height);
+        dst.put_u32_le(self.frame_rate_n);
+        dst.put_u32_le(self.frame_rate_d);
+        dst.put_f32_le(self.aspect_ratio);
+        dst.put_u32_le(self.flags);
+        dst.put_u32_le(self.color_space);
+    }
+
+    pub fn read(mut src: &[u8]) -> Result<Self> {
+        if src.len() < VIDEO_HEADER_LEN {
+            return Err(AqueductError::Protocol("Video packet too short".to_string()));
+        }
+        Ok(Self {
+            codec: src.get_u32_le(),
+            width: src.get_u32_le(),
+            height: src.get_u32_le(),
+            frame_rate_n: src.get_u32_le(),
+            frame_rate_d: src.get_u32_le(),
+            aspect_ratio: src.get_f32_le(),
+            flags: src.get_u32_le(),
+            color_space: src.get_u32_le(),
+        })
+    }
+
+    // Stride and size in bytes of the decoded frame, checked against the limits
+    // above so hostile dimensions can't overflow or cause huge allocations.
+    // `stride` is the one from the extension, 0 for tightly packed lines.
+    pub fn frame_layout(&self, format: PixelFormat, stride: u32) -> Result<(u32, usize)> {
+        if self.width > MAX_VIDEO_DIMENSION || self.height > MAX_VIDEO_DIMENSION {
+            return Err(AqueductError::Protocol(format!("Video frame too large: {}x{}", self.width, self.height)));
+        }
+        let min_stride = format.checked_stride(self.width)
+            .ok_or_else(|| AqueductError::Protocol(format!("Invalid width: {}", self.width)))?;
+        let stride = if stride == 0 { min_stride } else { stride };
+        if stride < min_stride {
+            return Err(AqueductError::Protocol(format!(
+                "Stride {} too short for {} {:?} pixels", stride, self.width, format
+            )));
+        }
+        match format.checked_frame_size(self.height, stride) {
+            Some(size) if size <= MAX_VIDEO_FRAME_SIZE => Ok((stride, size)),
+            _ => Err(AqueductError::Protocol(format!(
+                "Video frame too large: {}x{} with stride {}", self.width, self.height, stride
+            ))),
+        }
+    }
+
+    pub fn pixel_format(&self) -> Result<PixelFormat> {
+        PixelFormat::from_fourcc(self.codec)
+            .ok_or_else(|| AqueductError::Protocol(format!("Invalid pixel format: {:#010x}", self.codec)))
+    }
+}
+
+// Aqueduct extension to the OMT video header, present when VIDEO_FLAG_EXTENSION is set.
+// It carries what the OMT header cannot express and is only written when a frame
+// needs it, so plain frames stay readable by any OMT peer.
+//
+//   [Length: u16][Stride: u32][Pixel Aspect Ratio: f32]
+//   [Matrix: u8][Range: u8][Transfer: u8][Primaries: u8][Pixel Format: u32]
+//
+// Compressed frames put the codec FourCC in the OMT header, the pixel format the
+// payload decodes to then travels here (0 when the codec implies it).
+//
+// Fields are append-only: readers take the fields they know that fit in Length,
+// keep defaults for missing ones and skip the rest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoExtension {
+    pub stride: u32,
+    pub pixel_aspect_ratio: f32,
+    pub colorimetry: Colorimetry,
+    pub pixel_format: u32,
+}
+
+const VIDEO_EXTENSION_FIELDS_LEN: usize = 16;
+
+impl VideoExtension {
+    pub fn encoded_len(&self) -> usize {
+        2 + VIDEO_EXTENSION_FIELDS_LEN
+    }
+
+    pub fn write(&self, dst: &mut BytesMut) {
+        dst.reserve(self.encoded_len());
+        dst.put_u16_le(VIDEO_EXTENSION_FIELDS_LEN as u16);
+        dst.put_u32_le(self.stride);
+        dst.put_f32_le(self.pixel_aspect_ratio);
+        dst.put_u8(self.colorimetry.matrix as u8);
+        dst.put_u8(self.colorimetry.range as u8);
+        dst.put_u8(self.colorimetry.transfer as u8);
+        dst.put_u8(self.colorimetry.primaries as u8);
+        dst.put_u32_le(self.pixel_format);
+    }
+
+    // Returns the extension along with the number of bytes it occupied
+    pub fn read(src: &[u8], defaults: Self) -> Result<(Self, usize)> {
+        if src.len() < 2 {
+            return Err(AqueductError::Protocol("Video extension too short".to_string()));
+        }
+        let len = u16::from_le_bytes([src[0], src[1]]) as usize;
+        if src.len() < 2 + len {
+            return Err(AqueductError::Protocol("Video extension too short".to_string()));
+        }
+
+        let mut fields = &src[2..2 + len];
+        let mut extension = defaults;
+        if fields.remaining() >= 4 {
+            extension.stride = fields.get_u32_le();
+        }
+        if fields.remaining() >= 4 {
+            extension.pixel_aspect_ratio = fields.get_f32_le();
+        }
+        if fields.remaining() >= 4 {
+            let invalid = || AqueductError::Protocol("Invalid colorimetry".to_string());
+            extension.colorimetry = Colorimetry {
+                matrix: ColorMatrix::from_u8(fields.get_u8()).ok_or_else(invalid)?,
+                range: ColorRange::from_u8(fields.get_u8()).ok_or_else(invalid)?,
+                transfer: TransferFunction::from_u8(fields.get_u8()).ok_or_else(invalid)?,
+                primaries: ColorPrimaries::from_u8(fields.get_u8()).ok_or_else(invalid)?,
+            };
+        }
+        if fields.remaining() >= 4 {
+            extension.pixel_format = fields.get_u32_le();
+        }
+        Ok((extension, 2 + len))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioHeader {
+    pub codec: u32,
+    pub sample_rate: u32,
+    pub samples_per_channel: u32,
+    pub channels: u32,
+    pub active_channels: u32,
+    // Reserved in OMT. Aqueduct puts the sample format and channel layout here as
+    // [Sample Format: u8][Channel Layout: u8][Reserved: u16], OMT peers send zero,
+    // which reads as planar float.
+    pub format: u32,
+}
+
+impl AudioHeader {
+    pub fn write(&self, dst: &mut BytesMut) {
+        dst.reserve(AUDIO_HEADER_LEN);
+        dst.put_u32_le(self.codec);
+        dst.put_u32_le(self.sample_rate);
+        dst.put_u32_le(self.samples_per_channel);
+        dst.put_u32_le(self.channels);
+        dst.put_u32_le(self.active_channels);
+        dst.put_u32_le(self.format);
+    }
+
+    pub fn read(mut src: &[u8]) -> Result<Self> {
+        if src.len() < AUDIO_HEADER_LEN {
+            return Err(AqueductError::Protocol("Audio packet too short".to_string()));
+        }
+        Ok(Self {
+            codec: src.get_u32_le(),
+            sample_rate: src.get_u32_le(),
+            samples_per_channel: src.get_u32_le(),
+            channels: src.get_u32_le(),
+            active_channels: src.get_u32_le(),
+            format: src.get_u32_le(),
+        })
+    }
+}
+
+pub fn audio_format(format: SampleFormat, channel_layout: ChannelLayout) -> u32 {
+    format as u32 | (channel_layout as u32) << 8
+}
+
+pub fn audio_format_from_field(field: u32) -> Result<(SampleFormat, ChannelLayout)> {
+    let format = SampleFormat::from_u8(field as u8)
+        .ok_or_else(|| AqueductError::Protocol(format!("Unknown sample format: {}", field as u8)))?;
+    let channel_layout = ChannelLayout::from_u8((field >> 8) as u8)
+        .ok_or_else(|| AqueductError::Protocol(format!("Unknown channel layout: {}", (field >> 8) as u8)))?;
+    Ok((format, channel_layout))
+}
+
+pub fn color_space(matrix: ColorMatrix) -> u32 {
+    match matrix {
+        ColorMatrix::Bt601 => COLOR_SPACE_BT601,
+        ColorMatrix::Bt709 => COLOR_SPACE_BT709,
+        ColorMatrix::Bt2020 => COLOR_SPACE_BT2020,
+    }
+}
+
+// What an OMT color space value implies when no extension refines it
+pub fn colorimetry_from_color_space(color_space: u32, height: u32) -> Colorimetry {
+    match color_space {
+        COLOR_SPACE_BT601 => Colorimetry::BT601,
+        COLOR_SPACE_BT709 => Colorimetry::BT709,
+        COLOR_SPACE_BT2020 => Colorimetry::BT2020,
+        _ => Colorimetry::for_height(height),
+    }
+}
+
+// Bitmask with one bit per channel, saturating at 32 channels
+pub fn active_channel_mask(channels: u32) -> u32 {
+    if channels >= 32 {
+        u32::MAX
+    } else {
+        (1u32 << channels) - 1
+    }
+}
+
+impl FrameFlags {
+    pub fn to_bits(self) -> u32 {
+        let mut bits = 0;
+        if self.alpha {
+            bits |= VIDEO_FLAG_ALPHA;
+        }
+        if self.premultiplied {
+            bits |= VIDEO_FLAG_PREMULTIPLIED;
+        }
+        if self.high_bit_depth {
+            bits |= VIDEO_FLAG_HIGH_BIT_DEPTH;
+        }
+        bits
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            alpha: bits & VIDEO_FLAG_ALPHA != 0,
+            premultiplied: bits & VIDEO_FLAG_PREMULTIPLIED != 0,
+            high_bit_depth: bits & VIDEO_FLAG_HIGH_BIT_DEPTH != 0,
+        }
+    }
+}
+
+// OMT carries audio as planar float, one contiguous block per channel.
+// AudioFrame data is interleaved, so we transpose on the way in and out.
+pub fn interleaved_to_planar(src: &[u8], channels: usize, dst: &mut BytesMut) {
+    let channels = channels.max(1);
+    let samples = src.len() / 4 / channels;
+    dst.reserve(samples * channels * 4);
+    for ch in 0..channels {
+        for i in 0..samples {
+            let offset = (i * channels + ch) * 4;
+            dst.put_slice(&src[offset..offset + 4]);
+        }
+    }
+}
+
+pub fn planar_to_interleaved(src: &[u8], channels: usize, dst: &mut BytesMut) {
+    let channels = channels.max(1);
+    let samples = src.len() / 4 / channels;
+    dst.reserve(samples * channels * 4);
+    for i in 0..samples {
+        for ch in 0..channels {
+            let offset = (ch * samples + i) * 4;
+            dst.put_slice(&src[offset..offset + 4]);
+        }
+    }
+}
+
+// Aqueduct specific. A large frame can be split into chunks so other frames can be
+// sent between them, each chunk goes out as its own frame of type Chunk:
+//
+//   [Sequence: u32][Offset: u32][Total Length: u32][Piece of the frame]
+//
+// The pieces, in order, make up the original frame including its frame header.
+// Chunks of one frame are never interleaved with chunks of another, Sequence
+// tells frames apart. Only sent to peers that advertise support in their hello.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub sequence: u32,
+    pub offset: u32,
+    pub total_len: u32,
+}
+
+impl ChunkHeader {
+    pub fn write(&self, dst: &mut BytesMut) {
+        dst.reserve(CHUNK_HEADER_LEN);
+        dst.put_u32_le(self.sequence);
+        dst.put_u32_le(self.offset);
+        dst.put_u32_le(self.total_len);
+    }
+
+    pub fn read(mut src: &[u8]) -> Result<Self> {
+        if src.len() < CHUNK_HEADER_LEN {
+            return Err(AqueductError::Protocol("Chunk too short".to_string()));
+        }
+        Ok(Self {
+            sequence: src.get_u32_le(),
+            offset: src.get_u32_le(),
+            total_len: src.get_u32_le(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Headers as libomt lays them out in OMTFrameHeader, OMTVideoHeader and OMTAudioHeader
+
+    // Video, one second in, 8 bytes of per-frame metadata in 0x40 bytes of data
+    const FRAME_HEADER: [u8; FRAME_HEADER_LEN] = [
+        0x01, 0x02, 0x80, 0x96, 0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x40, 0x00, 0x00, 0x00,
+    ];
+
+    // 1920x1080 interlaced UYVY at 59.94 fields, 16:9, BT.709
+    const VIDEO_HEADER: [u8; VIDEO_HEADER_LEN] = [
+        b'U', b'Y', b'V', b'Y', 0x80, 0x07, 0x00, 0x00, 0x38, 0x04, 0x00, 0x00, 0x60, 0xea, 0x00, 0x00,
+        0xe9, 0x03, 0x00, 0x00, 0x39, 0x8e, 0xe3, 0x3f, 0x01, 0x00, 0x00, 0x00, 0xc5, 0x02, 0x00, 0x00,
+    ];
+
+    // 1024 samples of stereo FPA1 at 48kHz, reserved field zero
+    const AUDIO_HEADER: [u8; AUDIO_HEADER_LEN] = [
+        b'F', b'P', b'A', b'1', 0x80, 0xbb, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn frame_header_vector() {
+        let header = FrameHeader::read(&FRAME_HEADER).unwrap();
+        assert_eq!(header, FrameHeader {
+            version: OMT_VERSION,
+            frame_type: FrameType::Video as u8,
+            timestamp: 10_000_000,
+            metadata_len: 8,
+            data_len: 0x40,
+        });
+        assert_eq!(header.timestamp(), Duration::from_secs(1));
+
+        let mut dst = BytesMut::new();
+        header.write(&mut dst);
+        assert_eq!(&dst[..], &FRAME_HEADER[..]);
+    }
+
+    #[test]
+    fn frame_header_validation() {
+        // Unknown version
+        let mut bytes = FRAME_HEADER;
+        bytes[0] = 2;
+        assert!(matches!(FrameHeader::read(&bytes), Err(AqueductError::Protocol(_))));
+
+        // More metadata than data
+        let mut bytes = FRAME_HEADER;
+        bytes[10] = 0x41;
+        assert!(matches!(FrameHeader::read(&bytes), Err(AqueductError::Protocol(_))));
+
+        // Larger than we buffer
+        let mut bytes = FRAME_HEADER;
+        bytes[12..16].copy_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_le_bytes());
+        assert!(matches!(FrameHeader::read(&bytes), Err(AqueductError::Protocol(_))));
+
+        assert!(FrameHeader::read(&FRAME_HEADER[..FRAME_HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn negative_timestamp_clamps_to_zero() {
+        let mut bytes = FRAME_HEADER;
+        bytes[2..10].copy_from_slice(&(-5i64).to_le_bytes());
+        assert_eq!(FrameHeader::read(&bytes).unwrap().timestamp(), Duration::ZERO);
+    }
+
+    #[test]
+    fn video_header_vector() {
+        let header = VideoHeader::read(&VIDEO_HEADER).unwrap();
+        assert_eq!(header, VideoHeader {
+            codec: fourcc(b"UYVY"),
+            width: 1920,
+            height: 1080,
+            frame_rate_n: 60000,
+            frame_rate_d: 1001,
+            aspect_ratio: 1920.0 / 1080.0,
+            flags: VIDEO_FLAG_INTERLACED,
+            color_space: COLOR_SPACE_BT709,
+        });
+        assert_eq!(header.pixel_format().unwrap(), PixelFormat::UYVY);
+        assert_eq!(header.frame_layout(PixelFormat::UYVY, 0).unwrap(), (3840, 3840 * 1080));
+        assert_eq!(colorimetry_from_color_space(header.color_space, header.height), Colorimetry::BT709);
+
+        let mut dst = BytesMut::new();
+        header.write(&mut dst);
+        assert_eq!(&dst[..], &VIDEO_HEADER[..]);
+    }
+
+    #[test]
+    fn audio_header_vector() {
+        let header = AudioHeader::read(&AUDIO_HEADER).unwrap();
+        assert_eq!(header, AudioHeader {
+            codec: FOURCC_FPA1,
+            sample_rate: 48000,
+            samples_per_channel: 1024,
+            channels: 2,
+            active_channels: 3,
+            format: 0,
+        });
+        // What libomt leaves in the reserved field reads as planar float
+        assert_eq!(audio_format_from_field(header.format).unwrap(), (SampleFormat::F32, ChannelLayout::Planar));
+
+        let mut dst = BytesMut::new();
+        header.write(&mut dst);
+        assert_eq!(&dst[..], &AUDIO_HEADER[..]);
+    }
+
+    #[test]
+    fn hostile_video_dimensions() {
+        let header = |width, height| VideoHeader { width, height, ..VideoHeader::read(&VIDEO_HEADER).unwrap() };
+
+        // Would overflow the stride in u32
+        assert!(header(0x4000_0000, 1).frame_layout(PixelFormat::BGRA, 0).is_err());
+        assert!(header(MAX_VIDEO_DIMENSION + 1, 1).frame_layout(PixelFormat::BGRA, 0).is_err());
+        assert!(header(1, MAX_VIDEO_DIMENSION + 1).frame_layout(PixelFormat::BGRA, 0).is_err());
+        // Within the dimension limit but beyond the size limit
+        assert!(header(MAX_VIDEO_DIMENSION, MAX_VIDEO_DIMENSION).frame_layout(PixelFormat::PA16, 0).is_err());
+        // Extension strides that are too short or too long
+        assert!(header(1920, 1080).frame_layout(PixelFormat::UYVY, 3839).is_err());
+        assert!(header(1920, 1080).frame_layout(PixelFormat::UYVY, u32::MAX).is_err());
+        assert_eq!(header(1920, 1080).frame_layout(PixelFormat::UYVY, 4096).unwrap(), (4096, 4096 * 1080));
+    }
+
+    #[test]
+    fn video_extension_skips_unknown_fields() {
+        let defaults = VideoExtension {
+            stride: 0,
+            pixel_aspect_ratio: 1.0,
+            colorimetry: Colorimetry::BT709,
+            pixel_format: 0,
+        };
+        // A newer writer's extension with four more bytes than we know about
+        let mut dst = BytesMut::new();
+        dst.put_u16_le(VIDEO_EXTENSION_FIELDS_LEN as u16 + 4);
+        dst.put_u32_le(4096);
+        dst.put_f32_le(1.0);
+        dst.put_slice(&[0, 1, 0, 0]);
+        dst.put_u32_le(fourcc(b"NV12"));
+        dst.put_u32_le(0xdead_beef);
+        let (extension, len) = VideoExtension::read(&dst, defaults).unwrap();
+        assert_eq!(len, dst.len());
+        assert_eq!(extension.stride, 4096);
+        assert_eq!(extension.colorimetry.matrix, ColorMatrix::Bt601);
+        assert_eq!(extension.colorimetry.range, ColorRange::Full);
+        assert_eq!(extension.pixel_format, fourcc(b"NV12"));
+
+        // An older writer's extension with only the stride
+        let (extension, len) = VideoExtension::read(&[4, 0, 0, 16, 0, 0], defaults).unwrap();
+        assert_eq!(len, 6);
+        assert_eq!(extension, VideoExtension { stride: 4096, ..defaults });
+
+        assert!(VideoExtension::read(&[16, 0, 0, 0], defaults).is_err());
+    }
+}